@@ -0,0 +1,584 @@
+use crate::envoy::Metadata;
+use crate::headers::HeaderMap;
+use crate::{PendingValue, Phase, ReqRespCtx};
+use protobuf::Message;
+use protobuf::well_known_types::{Struct, Value, Value_oneof_kind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The typed value of a request attribute.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Timestamp(SystemTime),
+    Duration(Duration),
+    Map(BTreeMap<String, AttributeValue>),
+    List(Vec<AttributeValue>),
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeValue::String(value) => write!(f, "{value}"),
+            AttributeValue::Int(value) => write!(f, "{value}"),
+            AttributeValue::Double(value) => write!(f, "{value}"),
+            AttributeValue::Bool(value) => write!(f, "{value}"),
+            AttributeValue::Bytes(value) => write!(f, "{}", String::from_utf8_lossy(value)),
+            AttributeValue::Timestamp(value) => write_rfc3339(f, *value),
+            AttributeValue::Duration(value) => write!(f, "{}s", value.as_secs_f64()),
+            AttributeValue::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{key:?}:{value}")?;
+                }
+                write!(f, "}}")
+            }
+            AttributeValue::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+fn write_rfc3339(f: &mut fmt::Formatter<'_>, time: SystemTime) -> fmt::Result {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    write!(
+        f,
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )?;
+    match since_epoch.subsec_nanos() {
+        0 => write!(f, "Z"),
+        nanos => write!(f, ".{nanos:09}Z"),
+    }
+}
+
+/// Converts a `google.protobuf.Value`, `null` being no value at all.
+fn from_value(value: &Value) -> Option<AttributeValue> {
+    match value.kind.as_ref()? {
+        Value_oneof_kind::null_value(_) => None,
+        Value_oneof_kind::number_value(value) => Some(AttributeValue::Double(*value)),
+        Value_oneof_kind::string_value(value) => Some(AttributeValue::String(value.clone())),
+        Value_oneof_kind::bool_value(value) => Some(AttributeValue::Bool(*value)),
+        Value_oneof_kind::struct_value(value) => Some(value.into()),
+        Value_oneof_kind::list_value(list) => Some(AttributeValue::List(
+            list.values.iter().filter_map(from_value).collect(),
+        )),
+    }
+}
+
+impl From<&Struct> for AttributeValue {
+    fn from(value: &Struct) -> Self {
+        AttributeValue::Map(
+            value
+                .fields
+                .iter()
+                .filter_map(|(key, value)| from_value(value).map(|value| (key.clone(), value)))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    Int,
+    Bool,
+    Timestamp,
+    Duration,
+}
+
+/// Where a top level attribute's value comes from.
+#[derive(Clone, Copy)]
+enum Source {
+    RequestHeader(&'static str),
+    RequestHeaders,
+    RequestBody,
+    UrlPath,
+    Query,
+    /// The first value of each query parameter, decoded.
+    QueryParams,
+    /// Every value of each query parameter, decoded, in order.
+    QueryParamValues,
+    ContentLength,
+    ResponseStatus,
+    ResponseHeaders,
+    ResponseBody,
+    Property(Kind),
+}
+
+/// The attributes Envoy exposes, see
+/// https://www.envoyproxy.io/docs/envoy/latest/intro/arch_overview/advanced/attributes
+const ATTRIBUTES: &[(&str, Source)] = &[
+    ("request.path", Source::RequestHeader(":path")),
+    ("request.url_path", Source::UrlPath),
+    ("request.host", Source::RequestHeader(":authority")),
+    ("request.scheme", Source::RequestHeader(":scheme")),
+    ("request.method", Source::RequestHeader(":method")),
+    ("request.headers", Source::RequestHeaders),
+    ("request.referer", Source::RequestHeader("referer")),
+    ("request.useragent", Source::RequestHeader("user-agent")),
+    ("request.id", Source::RequestHeader("x-request-id")),
+    ("request.query", Source::Query),
+    ("request.query_params", Source::QueryParams),
+    ("request.query_param_values", Source::QueryParamValues),
+    ("request.size", Source::ContentLength),
+    ("request.body", Source::RequestBody),
+    ("request.time", Source::Property(Kind::Timestamp)),
+    ("request.protocol", Source::Property(Kind::String)),
+    ("request.duration", Source::Property(Kind::Duration)),
+    ("request.total_size", Source::Property(Kind::Int)),
+    ("response.code", Source::ResponseStatus),
+    ("response.headers", Source::ResponseHeaders),
+    ("response.body", Source::ResponseBody),
+    ("response.code_details", Source::Property(Kind::String)),
+    ("response.flags", Source::Property(Kind::Int)),
+    ("response.grpc_status", Source::Property(Kind::Int)),
+    ("response.size", Source::Property(Kind::Int)),
+    ("response.total_size", Source::Property(Kind::Int)),
+    ("source.address", Source::Property(Kind::String)),
+    ("source.port", Source::Property(Kind::Int)),
+    ("destination.address", Source::Property(Kind::String)),
+    ("destination.port", Source::Property(Kind::Int)),
+    ("xds.cluster_name", Source::Property(Kind::String)),
+    ("connection.id", Source::Property(Kind::Int)),
+    ("connection.mtls", Source::Property(Kind::Bool)),
+    (
+        "connection.requested_server_name",
+        Source::Property(Kind::String),
+    ),
+    ("connection.tls_version", Source::Property(Kind::String)),
+    (
+        "connection.subject_local_certificate",
+        Source::Property(Kind::String),
+    ),
+    (
+        "connection.subject_peer_certificate",
+        Source::Property(Kind::String),
+    ),
+    (
+        "connection.dns_san_local_certificate",
+        Source::Property(Kind::String),
+    ),
+    (
+        "connection.dns_san_peer_certificate",
+        Source::Property(Kind::String),
+    ),
+    (
+        "connection.uri_san_local_certificate",
+        Source::Property(Kind::String),
+    ),
+    (
+        "connection.uri_san_peer_certificate",
+        Source::Property(Kind::String),
+    ),
+    (
+        "connection.sha256_peer_certificate_digest",
+        Source::Property(Kind::String),
+    ),
+    (
+        "connection.termination_details",
+        Source::Property(Kind::String),
+    ),
+];
+
+impl Source {
+    /// The phase from which the attribute can be resolved, anything earlier is `Pending`.
+    fn available_from(&self, namespace: &str) -> Phase {
+        match (self, namespace) {
+            (Source::RequestBody, _) => Phase::RequestBody,
+            (Source::ResponseBody, _) => Phase::ResponseBody,
+            (_, "response") => Phase::ResponseHeaders,
+            _ => Phase::RequestHeaders,
+        }
+    }
+}
+
+/// Resolves an attribute `path`, e.g. `["request", "headers", "x-api-key"]`, against the
+/// host and what the pipeline learned so far.
+pub fn resolve(ctx: &ReqRespCtx, path: &[&str]) -> PendingValue<Option<AttributeValue>> {
+    match path {
+        ["metadata", rest @ ..] => PendingValue::Resolved(resolve_metadata(ctx, rest)),
+        // Keys are usually dotted themselves, e.g. `envoy.tcp_proxy.cluster`
+        ["filter_state", key @ ..] if !key.is_empty() => PendingValue::Resolved(
+            ctx.host
+                .get_property(&["filter_state", &key.join(".")])
+                .map(AttributeValue::Bytes),
+        ),
+        [namespace, name, rest @ ..] => {
+            let Some((_, source)) = ATTRIBUTES
+                .iter()
+                .find(|(attribute, _)| *attribute == format!("{namespace}.{name}"))
+            else {
+                return PendingValue::Resolved(None);
+            };
+            let available_from = source.available_from(namespace);
+            let is_body = matches!(source, Source::RequestBody | Source::ResponseBody);
+            // Headers that end the stream leave no body to wait for
+            let headers_phase = match source {
+                Source::RequestBody => Some(Phase::RequestHeaders),
+                Source::ResponseBody => Some(Phase::ResponseHeaders),
+                _ => None,
+            };
+            if headers_phase.is_some() && ctx.phase() == headers_phase && ctx.host.end_of_stream() {
+                return PendingValue::Resolved(Some(AttributeValue::Bytes(Vec::new())));
+            }
+            if ctx.phase().is_none_or(|phase| phase < available_from) {
+                return PendingValue::Pending;
+            }
+            // A body is only known once complete, not as far as it got
+            if is_body && ctx.phase() == Some(available_from) && !ctx.host.end_of_stream() {
+                return PendingValue::Pending;
+            }
+            let value = resolve_source(ctx, namespace, name, *source);
+            PendingValue::Resolved(match (source, rest) {
+                (_, []) => value,
+                // header names are case-insensitive
+                (Source::RequestHeaders | Source::ResponseHeaders, [header]) => {
+                    select(value, &[&header.to_ascii_lowercase()])
+                }
+                (_, rest) => select(value, rest),
+            })
+        }
+        _ => PendingValue::Resolved(None),
+    }
+}
+
+fn resolve_source(
+    ctx: &ReqRespCtx,
+    namespace: &str,
+    name: &str,
+    source: Source,
+) -> Option<AttributeValue> {
+    let request_header = |name: &str| ctx.host.request_headers().get(name).map(str::to_string);
+    let path = || request_header(":path").unwrap_or_default();
+    match source {
+        Source::RequestHeader(header) => request_header(header).map(AttributeValue::String),
+        Source::RequestHeaders => Some(headers(&ctx.host.request_headers())),
+        Source::ResponseHeaders => Some(headers(&ctx.host.response_headers())),
+        Source::RequestBody => ctx.host.request_body().map(AttributeValue::Bytes),
+        Source::ResponseBody => ctx.host.response_body().map(AttributeValue::Bytes),
+        Source::UrlPath => {
+            let path = path();
+            let url_path = path.split_once('?').map(|(p, _)| p).unwrap_or(&path);
+            Some(AttributeValue::String(url_path.to_string()))
+        }
+        Source::Query => Some(AttributeValue::String(
+            path()
+                .split_once('?')
+                .map(|(_, query)| query.to_string())
+                .unwrap_or_default(),
+        )),
+        Source::QueryParams => {
+            let mut map = BTreeMap::new();
+            for (name, value) in ctx.query_params().iter() {
+                map.entry(name.to_string())
+                    .or_insert_with(|| AttributeValue::String(value.to_string()));
+            }
+            Some(AttributeValue::Map(map))
+        }
+        Source::QueryParamValues => {
+            let params = ctx.query_params();
+            let mut map = BTreeMap::new();
+            for (name, _) in params.iter() {
+                map.entry(name.to_string()).or_insert_with(|| {
+                    AttributeValue::List(
+                        params
+                            .get_all(name)
+                            .map(|value| AttributeValue::String(value.to_string()))
+                            .collect(),
+                    )
+                });
+            }
+            Some(AttributeValue::Map(map))
+        }
+        Source::ContentLength => request_header("content-length")
+            .and_then(|length| length.parse().ok())
+            .map(AttributeValue::Int),
+        Source::ResponseStatus => ctx
+            .host
+            .response_headers()
+            .get(":status")
+            .and_then(|status| status.parse().ok())
+            .map(AttributeValue::Int),
+        Source::Property(kind) => decode(kind, ctx.host.get_property(&[namespace, name])?),
+    }
+}
+
+/// Multiple values of a header are joined with a `,`, as Envoy does.
+fn headers(headers: &HeaderMap) -> AttributeValue {
+    let mut map: BTreeMap<String, AttributeValue> = BTreeMap::new();
+    for (key, value) in headers.iter() {
+        map.entry(key.to_ascii_lowercase())
+            .and_modify(|existing| {
+                if let AttributeValue::String(existing) = existing {
+                    existing.push(',');
+                    existing.push_str(value);
+                }
+            })
+            .or_insert_with(|| AttributeValue::String(value.to_string()));
+    }
+    AttributeValue::Map(map)
+}
+
+fn decode(kind: Kind, bytes: Vec<u8>) -> Option<AttributeValue> {
+    let int = |bytes: &[u8]| bytes.try_into().ok().map(i64::from_le_bytes);
+    match kind {
+        Kind::String => String::from_utf8(bytes).ok().map(AttributeValue::String),
+        Kind::Int => int(&bytes).map(AttributeValue::Int),
+        Kind::Bool => bytes.first().map(|b| AttributeValue::Bool(*b != 0)),
+        Kind::Timestamp => int(&bytes).map(|nanos| {
+            AttributeValue::Timestamp(UNIX_EPOCH + Duration::from_nanos(nanos.max(0) as u64))
+        }),
+        Kind::Duration => int(&bytes)
+            .map(|nanos| AttributeValue::Duration(Duration::from_nanos(nanos.max(0) as u64))),
+    }
+}
+
+fn select(value: Option<AttributeValue>, path: &[&str]) -> Option<AttributeValue> {
+    path.iter().try_fold(value?, |value, key| match value {
+        AttributeValue::Map(mut entries) => entries.remove(*key),
+        _ => None,
+    })
+}
+
+/// Looks the path up in the dynamic metadata, the one written by the pipeline first, then the
+/// one known to the host. As namespaces usually contain dots themselves, e.g.
+/// `envoy.filters.http.ext_authz`, the longest one matching the start of the path wins.
+fn resolve_metadata(ctx: &ReqRespCtx, path: &[&str]) -> Option<AttributeValue> {
+    let lookup = |metadata: &Metadata| {
+        (1..=path.len()).rev().find_map(|i| {
+            let namespace = metadata.filter_metadata.get(&path[..i].join("."))?;
+            select(Some(namespace.into()), &path[i..])
+        })
+    };
+    lookup(&ctx.dynamic_metadata).or_else(|| {
+        let bytes = ctx.host.get_property(&["metadata"])?;
+        lookup(&Metadata::parse_from_bytes(&bytes).ok()?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::MockHost;
+
+    fn ctx(host: &MockHost) -> ReqRespCtx {
+        ReqRespCtx::new(Box::new(host.clone()))
+    }
+
+    fn resolved(ctx: &ReqRespCtx, path: &str) -> Option<AttributeValue> {
+        match ctx.get_attribute(path) {
+            PendingValue::Resolved(value) => value,
+            PendingValue::Pending => panic!("{path} should be resolved"),
+        }
+    }
+
+    fn string(value: &str) -> Option<AttributeValue> {
+        Some(AttributeValue::String(value.to_string()))
+    }
+
+    #[test]
+    fn it_resolves_request_attributes() {
+        let host = MockHost::default()
+            .with_request_headers(vec![
+                (":method", "GET"),
+                (":path", "/api/users?page=2&q=a%20b&page=3&debug"),
+                (":authority", "example.com"),
+                ("Content-Length", "42"),
+                ("accept", "text/html"),
+                ("Accept", "application/json"),
+            ])
+            .with_property(&["request", "time"], 1_500_000_000i64.to_le_bytes())
+            .with_property(&["request", "protocol"], "HTTP/1.1")
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ctx(&host);
+
+        assert_eq!(resolved(&ctx, "request.method"), string("GET"));
+        assert_eq!(
+            resolved(&ctx, "request.path"),
+            string("/api/users?page=2&q=a%20b&page=3&debug")
+        );
+        assert_eq!(resolved(&ctx, "request.url_path"), string("/api/users"));
+        assert_eq!(
+            resolved(&ctx, "request.query"),
+            string("page=2&q=a%20b&page=3&debug")
+        );
+        assert_eq!(resolved(&ctx, "request.query_params.page"), string("2"));
+        assert_eq!(resolved(&ctx, "request.query_params.q"), string("a b"));
+        assert_eq!(resolved(&ctx, "request.query_params.debug"), string(""));
+        assert_eq!(resolved(&ctx, "request.query_params.nope"), None);
+        assert_eq!(
+            resolved(&ctx, "request.query_param_values.page"),
+            Some(AttributeValue::List(vec![
+                AttributeValue::String("2".to_string()),
+                AttributeValue::String("3".to_string()),
+            ]))
+        );
+        assert_eq!(
+            resolved(&ctx, "request.query_param_values.debug"),
+            Some(AttributeValue::List(vec![AttributeValue::String(
+                String::new()
+            )]))
+        );
+        assert_eq!(resolved(&ctx, "request.query_param_values.nope"), None);
+        assert_eq!(resolved(&ctx, "request.host"), string("example.com"));
+        assert_eq!(
+            resolved(&ctx, "request.size"),
+            Some(AttributeValue::Int(42))
+        );
+        assert_eq!(
+            resolved(&ctx, "request.headers.ACCEPT"),
+            string("text/html,application/json")
+        );
+        assert_eq!(resolved(&ctx, "request.protocol"), string("HTTP/1.1"));
+        assert_eq!(
+            resolved(&ctx, "request.time").map(|time| time.to_string()),
+            Some("1970-01-01T00:00:01.500000000Z".to_string())
+        );
+        assert_eq!(resolved(&ctx, "request.scheme"), None);
+        assert_eq!(resolved(&ctx, "request.nope"), None);
+        assert_eq!(resolved(&ctx, "nope.nope"), None);
+    }
+
+    #[test]
+    fn it_resolves_connection_attributes() {
+        let host = MockHost::default()
+            .with_property(&["source", "address"], "10.0.0.1:5000")
+            .with_property(&["source", "port"], 5000i64.to_le_bytes())
+            .with_property(&["connection", "mtls"], [1])
+            .with_property(&["filter_state", "key"], "raw")
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ctx(&host);
+
+        assert_eq!(resolved(&ctx, "source.address"), string("10.0.0.1:5000"));
+        assert_eq!(
+            resolved(&ctx, "source.port"),
+            Some(AttributeValue::Int(5000))
+        );
+        assert_eq!(
+            resolved(&ctx, "connection.mtls"),
+            Some(AttributeValue::Bool(true))
+        );
+        assert_eq!(
+            resolved(&ctx, "filter_state.key"),
+            Some(AttributeValue::Bytes(b"raw".to_vec()))
+        );
+    }
+
+    #[test]
+    fn it_is_pending_until_the_attribute_phase() {
+        let host = MockHost::default();
+        let ctx = ctx(&host);
+        assert_eq!(ctx.get_attribute("source.address"), PendingValue::Pending);
+        for (phase, path) in [
+            (Phase::RequestHeaders, "request.method"),
+            (Phase::RequestBody, "request.body"),
+            (Phase::ResponseHeaders, "response.code"),
+            (Phase::ResponseBody, "response.body"),
+        ] {
+            assert_eq!(ctx.get_attribute(path), PendingValue::Pending, "{path}");
+            host.set_phase(phase);
+            host.set_end_of_stream(false);
+            if path.ends_with(".body") {
+                assert_eq!(ctx.get_attribute(path), PendingValue::Pending, "{path}");
+                host.set_end_of_stream(true);
+            }
+            assert_ne!(ctx.get_attribute(path), PendingValue::Pending, "{path}");
+        }
+        host.append_response_body(b"{}");
+        assert_eq!(
+            resolved(&ctx, "response.body"),
+            Some(AttributeValue::Bytes(b"{}".to_vec()))
+        );
+    }
+
+    #[test]
+    fn it_resolves_no_body_when_the_headers_end_the_stream() {
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ctx(&host);
+        assert_eq!(ctx.get_attribute("request.body"), PendingValue::Pending);
+
+        host.set_end_of_stream(true);
+        assert_eq!(
+            resolved(&ctx, "request.body"),
+            Some(AttributeValue::Bytes(Vec::new()))
+        );
+        assert_eq!(ctx.get_attribute("response.body"), PendingValue::Pending);
+
+        host.set_phase(Phase::ResponseHeaders);
+        assert_eq!(
+            resolved(&ctx, "response.body"),
+            Some(AttributeValue::Bytes(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn it_resolves_filter_state() {
+        let host = MockHost::default()
+            .with_property(&["filter_state", "envoy.tcp_proxy.cluster"], "backend")
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ctx(&host);
+
+        assert_eq!(
+            resolved(&ctx, "filter_state.envoy.tcp_proxy.cluster"),
+            Some(AttributeValue::Bytes(b"backend".to_vec()))
+        );
+        assert_eq!(resolved(&ctx, "filter_state.nope"), None);
+    }
+
+    #[test]
+    fn it_resolves_metadata() {
+        let host = MockHost::default();
+        let mut ctx = ctx(&host);
+        let mut user = Value::new();
+        user.set_string_value("alice".to_string());
+        let mut metadata = Struct::new();
+        metadata.fields.insert("user".to_string(), user);
+        ctx.dynamic_metadata
+            .filter_metadata
+            .insert("envoy.filters.http.ext_authz".to_string(), metadata);
+
+        assert_eq!(
+            resolved(&ctx, "metadata.envoy.filters.http.ext_authz.user"),
+            string("alice")
+        );
+        assert_eq!(
+            ctx.get_attribute_path(&["metadata", "envoy.filters.http.ext_authz", "user"]),
+            PendingValue::Resolved(string("alice"))
+        );
+        assert_eq!(
+            resolved(&ctx, "metadata.envoy.filters.http.ext_authz.nope"),
+            None
+        );
+    }
+}
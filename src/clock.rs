@@ -0,0 +1,36 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tells the time, as elapsed since any fixed point, to local rate limits and call deadlines.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// The wall clock, which is all a proxy-wasm module has.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to, starting at zero.
+#[cfg(test)]
+#[derive(Default)]
+pub struct ManualClock(std::cell::Cell<Duration>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn advance(&self, by: Duration) {
+        self.0.set(self.0.get() + by);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.0.get()
+    }
+}
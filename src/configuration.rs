@@ -0,0 +1,2689 @@
+use crate::clock::{Clock, SystemClock};
+use crate::descriptors::DescriptorBuilder;
+use crate::envoy::{
+    self, GrpcService_EnvoyGrpc, GrpcService_oneof_target_specifier,
+    HeaderMatcher_oneof_header_match_specifier, HeaderValue, HttpGenericBodyMatch,
+    HttpGenericBodyMatch_GenericTextMatch, HttpGenericBodyMatch_GenericTextMatch_oneof_rule,
+    Int64Range, LocalRateLimitDescriptor, MatchPredicate_oneof_rule, Matcher_MatcherList,
+    Matcher_MatcherList_FieldMatcher, Matcher_MatcherList_Predicate,
+    Matcher_MatcherList_Predicate_PredicateList, Matcher_MatcherList_Predicate_SinglePredicate,
+    Matcher_MatcherList_Predicate_SinglePredicate_oneof_matcher,
+    Matcher_MatcherList_Predicate_oneof_match_type, Matcher_MatcherTree,
+    Matcher_MatcherTree_MatchMap, Matcher_MatcherTree_oneof_tree_type, Matcher_OnMatch,
+    Matcher_OnMatch_oneof_on_match, Matcher_oneof_matcher_type, MetadataKey_PathSegment,
+    MetadataKey_PathSegment_oneof_segment,
+    QueryParameterMatcher_oneof_query_parameter_match_specifier, RateLimit_Action,
+    RateLimit_Action_DynamicMetaData, RateLimit_Action_GenericKey,
+    RateLimit_Action_HeaderValueMatch, RateLimit_Action_MetaData, RateLimit_Action_MetaData_Source,
+    RateLimit_Action_RequestHeaders, RateLimit_Action_oneof_action_specifier, RateLimit_Override,
+    RateLimit_Override_DynamicMetadata, RateLimit_Override_oneof_override_specifier,
+    RateLimitDescriptor_Entry, RegexMatchAndSubstitute, RegexMatcher,
+    StringMatcher_oneof_match_pattern, TokenBucket, TypedExtensionConfig,
+};
+use crate::host::Host;
+use crate::local_ratelimit::{LocalRateLimitError, LocalRateLimitTask, LocalRateLimiter};
+use crate::matchers::{
+    DataInputRegistry, HeaderMatcher, ListStringMatcher, MatchPredicate, MatchTree, MatcherError,
+    QueryParameterMatcher, RegexRewrite,
+};
+use crate::predicate::{Predicate, PredicateError};
+use crate::services::{AuthService, DescriptorValue, GrpcService, HitsAddend, RateLimitService};
+use crate::{
+    ErrorReply, FailureMode, LocalReplyTask, Outcome, PendingValue, Pipeline, RLTask, ReportTask,
+    ReqRespCtx, RewritePathTask, Service, Task, TooManyRequestsTask,
+};
+use protobuf::well_known_types::{Any, BoolValue};
+use protobuf::{CodedOutputStream, Message};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+
+/// The plugin configuration, as sent by the control plane, e.g.
+///
+/// ```yaml
+/// services:
+///   limitador:
+///     type: ratelimit
+///     endpoint: limitador-cluster
+///     failureMode: allow
+///     timeout: 0.5s
+///     initialMetadata: { x-tenant: acme }
+///   shed:
+///     type: localratelimit
+///     descriptors:
+///       - entries: [ { key: user } ]
+///         tokenBucket: { maxTokens: 100, tokensPerFill: 10, fillInterval: 1s }
+/// errorReply:
+///   statusCode: 503
+///   headers: { retry-after: "5" }
+/// actionSets:
+///   - name: api
+///     routeRuleConditions:
+///       hostnames: ["*.example.com"]
+///       predicates: ['request.url_path.startsWith("/api")']
+///     actions:
+///       - service: shed
+///         scope: api
+///         data: [ attribute: { key: user, path: request.headers.x-user } ]
+///       - service: limitador
+///         scope: api
+///         predicates: ['request.method == "POST"']
+///         data:
+///           - static: { key: tier, value: gold }
+///           - attribute: { key: user, path: request.headers.x-user }
+///           - attribute:
+///               key: path
+///               path: request.url_path
+///               rewrite: { pattern: { regex: "/[0-9]+" }, substitution: "/{id}" }
+///         rateLimits:
+///           - actions: [ requestHeaders: { headerName: x-org, descriptorKey: org } ]
+///       - service: limitador
+///         scope: tokens
+///         predicates: ['response.code == 200']
+///         hitsAddend: { responseBody: usage.total_tokens }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PluginConfig {
+    pub services: BTreeMap<String, ServiceConfig>,
+    #[serde(default)]
+    pub action_sets: Vec<ActionSet>,
+    /// Sent when a service fails and its failure mode denies the request.
+    #[serde(default)]
+    pub error_reply: ErrorReply,
+    /// Selects the action set of a request rather than their route rule conditions.
+    pub action_set_matcher: Option<MatcherConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ServiceConfig {
+    #[serde(rename = "type")]
+    pub kind: ServiceKind,
+    /// The cluster the service is reached through, none for a local rate limit.
+    #[serde(default)]
+    pub endpoint: String,
+    #[serde(default)]
+    pub failure_mode: FailureMode,
+    /// The buckets of a local rate limit, for the requests whose descriptor matches theirs.
+    #[serde(default)]
+    pub descriptors: Vec<LocalDescriptorConfig>,
+    /// The bucket of a local rate limit for the requests matching none of its descriptors,
+    /// which aren't limited otherwise.
+    pub token_bucket: Option<TokenBucketConfig>,
+    /// How long to wait for the service, e.g. `0.5s`, after which the call fails. Unset or
+    /// zero, the call waits as long as the service takes.
+    pub timeout: Option<String>,
+    /// Sent along with every call to the service.
+    #[serde(default)]
+    pub initial_metadata: BTreeMap<String, String>,
+    /// The request headers an auth service gets, as with ext_authz's `allowedHeaders`, e.g.
+    /// `[{ exact: authorization }, { prefix: x- }]`, all of them when not set.
+    pub allowed_headers: Option<Vec<StringCondition>>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceKind {
+    Auth,
+    RateLimit,
+    /// Limits requests in process, with token buckets shared by all of them.
+    LocalRateLimit,
+}
+
+/// An Envoy `LocalRateLimitDescriptor`, in its JSON form. An entry without a value matches
+/// any value of its key.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LocalDescriptorConfig {
+    pub entries: Vec<EntryConfig>,
+    pub token_bucket: TokenBucketConfig,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EntryConfig {
+    pub key: String,
+    #[serde(default)]
+    pub value: String,
+}
+
+/// An Envoy `TokenBucket`, in its JSON form, e.g. `{ maxTokens: 10, fillInterval: 0.5s }`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TokenBucketConfig {
+    pub max_tokens: u32,
+    /// One by default.
+    pub tokens_per_fill: Option<u32>,
+    pub fill_interval: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ActionSet {
+    pub name: String,
+    pub route_rule_conditions: RouteRuleConditions,
+    /// Called all at once, except for those reading what an earlier action's service may write,
+    /// e.g. the metadata of an authorization, which wait for its answer.
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    /// Rewrites the path of the requests once the actions are done with it, e.g.
+    /// `{ pattern: { regex: "^/v1/" }, substitution: "/" }`, as Envoy's route `regex_rewrite`.
+    pub path_rewrite: Option<RewriteCondition>,
+}
+
+/// Selects the action set for a request: the most specific hostname matching the request's,
+/// `*.example.com` being less specific than `api.example.com`, and then the first action set
+/// whose headers and query parameters match, and predicates hold.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RouteRuleConditions {
+    pub hostnames: Vec<String>,
+    #[serde(default)]
+    pub headers: Vec<HeaderCondition>,
+    #[serde(default)]
+    pub query_params: Vec<QueryParamCondition>,
+    #[serde(default)]
+    pub predicates: Vec<String>,
+}
+
+/// An Envoy `HeaderMatcher`, in its JSON form, e.g. `{ name: x-tier, prefixMatch: gold }`. At
+/// most one of the `*Match` fields can be set, the header only has to be present otherwise.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HeaderCondition {
+    pub name: String,
+    pub exact_match: Option<String>,
+    pub safe_regex_match: Option<RegexCondition>,
+    pub range_match: Option<RangeCondition>,
+    pub present_match: Option<bool>,
+    pub prefix_match: Option<String>,
+    pub suffix_match: Option<String>,
+    pub contains_match: Option<String>,
+    #[serde(default)]
+    pub invert_match: bool,
+    #[serde(default)]
+    pub treat_missing_header_as_empty: bool,
+}
+
+/// An Envoy `QueryParameterMatcher`, in its JSON form, e.g.
+/// `{ name: api_key, stringMatch: { prefix: live_ } }`, matched against the first value of the
+/// parameter. Without a `stringMatch`, the parameter only has to be present.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct QueryParamCondition {
+    pub name: String,
+    pub string_match: Option<StringCondition>,
+    pub present_match: Option<bool>,
+}
+
+/// An Envoy `StringMatcher`, in its JSON form, e.g. `{ exact: gold, ignoreCase: true }`, with
+/// exactly one of its patterns set.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StringCondition {
+    pub exact: Option<String>,
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+    pub contains: Option<String>,
+    pub safe_regex: Option<RegexCondition>,
+    #[serde(default)]
+    pub ignore_case: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RegexCondition {
+    pub regex: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RangeCondition {
+    pub start: i64,
+    pub end: i64,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Action {
+    pub service: String,
+    /// The rate limit domain, or the `host` context extension of the authorization request.
+    pub scope: String,
+    #[serde(default)]
+    pub predicates: Vec<String>,
+    /// The descriptor entries sent to a rate limit service.
+    #[serde(default)]
+    pub data: Vec<DataItem>,
+    /// Envoy rate limits, whose descriptors are sent to a rate limit service along with the one
+    /// of the `data`.
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimitConfig>,
+    /// Only applies the action to requests whose body matches, in addition to its predicates.
+    pub request_body_match: Option<BodyCondition>,
+    /// Makes a rate limit action a report of the hits the request turned out to be worth, sent
+    /// once they are known rather than checked before the request goes on.
+    pub hits_addend: Option<HitsAddendConfig>,
+}
+
+/// Where the hits of a report are read from.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum HitsAddendConfig {
+    /// The dot-separated path to a number in the JSON response body, e.g.
+    /// `usage.total_tokens`.
+    ResponseBody(String),
+}
+
+/// An Envoy `RateLimit`, in its JSON form, e.g.
+/// `{ actions: [ { requestHeaders: { headerName: x-user, descriptorKey: user } } ] }`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub actions: Vec<RateLimitActionConfig>,
+    pub limit: Option<RateLimitOverrideConfig>,
+}
+
+/// An Envoy `RateLimit.Action`, named after its action specifier, e.g. `remoteAddress: {}`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum RateLimitActionConfig {
+    SourceCluster {},
+    DestinationCluster {},
+    RequestHeaders {
+        header_name: String,
+        descriptor_key: String,
+        #[serde(default)]
+        skip_if_absent: bool,
+    },
+    RemoteAddress {},
+    GenericKey {
+        descriptor_value: String,
+        #[serde(default)]
+        descriptor_key: String,
+    },
+    HeaderValueMatch {
+        descriptor_value: String,
+        expect_match: Option<bool>,
+        headers: Vec<HeaderCondition>,
+    },
+    DynamicMetadata {
+        descriptor_key: String,
+        metadata_key: MetadataKeyConfig,
+        #[serde(default)]
+        default_value: String,
+    },
+    Metadata {
+        descriptor_key: String,
+        metadata_key: MetadataKeyConfig,
+        #[serde(default)]
+        default_value: String,
+        #[serde(default)]
+        source: MetadataSourceConfig,
+    },
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MetadataSourceConfig {
+    #[default]
+    Dynamic,
+    RouteEntry,
+}
+
+/// An Envoy `RateLimit.Override`, e.g. `dynamicMetadata: { metadataKey: { key: acme } }`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum RateLimitOverrideConfig {
+    DynamicMetadata { metadata_key: MetadataKeyConfig },
+}
+
+/// An Envoy `MetadataKey`, in its JSON form, e.g. `{ key: acme, path: [ { key: limit } ] }`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MetadataKeyConfig {
+    pub key: String,
+    #[serde(default)]
+    pub path: Vec<PathSegmentConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PathSegmentConfig {
+    pub key: String,
+}
+
+/// An Envoy unified `Matcher`, in its JSON form, its actions naming the action set to use, e.g.
+///
+/// ```yaml
+/// matcherTree:
+///   input:
+///     name: method
+///     typedConfig:
+///       "@type": type.googleapis.com/envoy.type.matcher.v3.HttpRequestHeaderMatchInput
+///       headerName: ":method"
+///   exactMatchMap: { map: { GET: { action: { name: reads } } } }
+/// onNoMatch: { action: { name: default } }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MatcherConfig {
+    pub matcher_list: Option<MatcherListConfig>,
+    pub matcher_tree: Option<MatcherTreeConfig>,
+    pub on_no_match: Option<OnMatchConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct OnMatchConfig {
+    pub matcher: Option<Box<MatcherConfig>>,
+    pub action: Option<MatcherActionConfig>,
+}
+
+/// The `TypedExtensionConfig` of an action, of which only the name, that of an action set,
+/// matters.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MatcherActionConfig {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MatcherListConfig {
+    pub matchers: Vec<FieldMatcherConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FieldMatcherConfig {
+    pub predicate: PredicateConfig,
+    pub on_match: OnMatchConfig,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PredicateConfig {
+    pub single_predicate: Option<SinglePredicateConfig>,
+    pub or_matcher: Option<PredicateListConfig>,
+    pub and_matcher: Option<PredicateListConfig>,
+    pub not_matcher: Option<Box<PredicateConfig>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SinglePredicateConfig {
+    pub input: DataInputConfig,
+    pub value_match: StringCondition,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PredicateListConfig {
+    pub predicate: Vec<PredicateConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MatcherTreeConfig {
+    pub input: DataInputConfig,
+    pub exact_match_map: Option<MatchMapConfig>,
+    pub prefix_match_map: Option<MatchMapConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MatchMapConfig {
+    pub map: BTreeMap<String, OnMatchConfig>,
+}
+
+/// The `TypedExtensionConfig` of a data input.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DataInputConfig {
+    #[serde(default)]
+    pub name: String,
+    pub typed_config: TypedDataInput,
+}
+
+/// The data inputs a [`DataInputRegistry`] knows about by default, as an `Any` in its JSON form.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "@type", deny_unknown_fields)]
+pub enum TypedDataInput {
+    #[serde(
+        rename = "type.googleapis.com/envoy.type.matcher.v3.HttpRequestHeaderMatchInput",
+        rename_all = "camelCase"
+    )]
+    RequestHeader { header_name: String },
+    #[serde(
+        rename = "type.googleapis.com/envoy.type.matcher.v3.HttpRequestQueryParamMatchInput",
+        rename_all = "camelCase"
+    )]
+    QueryParam { query_param: String },
+    #[serde(
+        rename = "type.googleapis.com/envoy.extensions.matching.common_inputs.network.v3.DynamicMetadataInput"
+    )]
+    DynamicMetadata {
+        filter: String,
+        #[serde(default)]
+        path: Vec<PathSegmentConfig>,
+    },
+}
+
+/// An Envoy `HttpGenericBodyMatch`, all of the string `patterns` have to be found in the first
+/// `bytesLimit` bytes of the body, or anywhere in it when `0`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BodyCondition {
+    #[serde(default)]
+    pub bytes_limit: u32,
+    pub patterns: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum DataItem {
+    Static {
+        key: String,
+        value: String,
+    },
+    Attribute {
+        key: String,
+        path: String,
+        #[serde(default)]
+        rewrite: Option<RewriteCondition>,
+    },
+}
+
+/// An Envoy `RegexMatchAndSubstitute`, in its JSON form, e.g.
+/// `{ pattern: { regex: "/[0-9]+" }, substitution: "/{id}" }`, normalising a value by replacing
+/// every match of the pattern.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RewriteCondition {
+    pub pattern: RegexCondition,
+    pub substitution: String,
+}
+
+impl PluginConfig {
+    pub fn from_json(source: &str) -> Result<Self, ConfigError> {
+        serde_json::from_str(source).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    pub fn from_yaml(source: &str) -> Result<Self, ConfigError> {
+        // Enums are written as `static: {..}`, as in JSON, rather than with YAML's `!static` tags
+        serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(
+            source,
+        ))
+        .map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Parse(String),
+    UnknownService {
+        action_set: String,
+        service: String,
+    },
+    MissingEndpoint(String),
+    InvalidGrpcService {
+        service: String,
+        error: String,
+    },
+    InvalidLocalRateLimit {
+        service: String,
+        error: String,
+    },
+    InvalidAllowedHeaders {
+        service: String,
+        error: String,
+    },
+    InvalidPredicate {
+        action_set: String,
+        predicate: String,
+        error: PredicateError,
+    },
+    UnexpectedData {
+        action_set: String,
+        service: String,
+    },
+    UnexpectedHitsAddend {
+        action_set: String,
+        service: String,
+    },
+    UnexpectedRateLimits {
+        action_set: String,
+        service: String,
+    },
+    InvalidRateLimit {
+        action_set: String,
+        error: String,
+    },
+    InvalidHeaderMatcher {
+        action_set: String,
+        header: String,
+        error: String,
+    },
+    InvalidQueryParamMatcher {
+        action_set: String,
+        param: String,
+        error: String,
+    },
+    InvalidRewrite {
+        action_set: String,
+        key: String,
+        error: MatcherError,
+    },
+    InvalidActionSetMatcher(MatcherError),
+    InvalidBodyMatch {
+        action_set: String,
+        error: MatcherError,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse(message) => write!(f, "invalid configuration: {message}"),
+            ConfigError::UnknownService {
+                action_set,
+                service,
+            } => write!(
+                f,
+                "action set `{action_set}` uses unknown service `{service}`"
+            ),
+            ConfigError::MissingEndpoint(service) => {
+                write!(f, "service `{service}` has no endpoint")
+            }
+            ConfigError::InvalidGrpcService { service, error } => {
+                write!(f, "service `{service}` is invalid: {error}")
+            }
+            ConfigError::InvalidLocalRateLimit { service, error } => {
+                write!(f, "local rate limit `{service}` is invalid: {error}")
+            }
+            ConfigError::InvalidAllowedHeaders { service, error } => {
+                write!(
+                    f,
+                    "service `{service}` has invalid allowed headers: {error}"
+                )
+            }
+            ConfigError::InvalidPredicate {
+                action_set,
+                predicate,
+                error,
+            } => write!(
+                f,
+                "action set `{action_set}` has invalid predicate `{predicate}`: {error}"
+            ),
+            ConfigError::UnexpectedData {
+                action_set,
+                service,
+            } => write!(
+                f,
+                "action set `{action_set}` sends data to `{service}`, which doesn't take any"
+            ),
+            ConfigError::UnexpectedHitsAddend {
+                action_set,
+                service,
+            } => write!(
+                f,
+                "action set `{action_set}` reports hits to `{service}`, which isn't a rate limit \
+                 service"
+            ),
+            ConfigError::UnexpectedRateLimits {
+                action_set,
+                service,
+            } => write!(
+                f,
+                "action set `{action_set}` sends rate limits to `{service}`, which isn't a rate \
+                 limit service"
+            ),
+            ConfigError::InvalidRateLimit { action_set, error } => {
+                write!(
+                    f,
+                    "action set `{action_set}` has invalid rate limit: {error}"
+                )
+            }
+            ConfigError::InvalidHeaderMatcher {
+                action_set,
+                header,
+                error,
+            } => write!(
+                f,
+                "action set `{action_set}` has invalid matcher for header `{header}`: {error}"
+            ),
+            ConfigError::InvalidQueryParamMatcher {
+                action_set,
+                param,
+                error,
+            } => write!(
+                f,
+                "action set `{action_set}` has invalid matcher for query parameter `{param}`: \
+                 {error}"
+            ),
+            ConfigError::InvalidRewrite {
+                action_set,
+                key,
+                error,
+            } => write!(
+                f,
+                "action set `{action_set}` has invalid rewrite for `{key}`: {error}"
+            ),
+            ConfigError::InvalidActionSetMatcher(error) => {
+                write!(f, "invalid action set matcher: {error}")
+            }
+            ConfigError::InvalidBodyMatch { action_set, error } => {
+                write!(
+                    f,
+                    "action set `{action_set}` has invalid body match: {error}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+struct CompiledAction {
+    predicate: Predicate,
+    kind: ServiceKind,
+    failure_mode: FailureMode,
+    backend: Backend,
+}
+
+/// What an action is checked against.
+enum Backend {
+    Remote(Rc<dyn Service<Response = Outcome>>),
+    /// With the descriptor entries of the requests.
+    Local(Rc<LocalRateLimiter>, Vec<(String, DescriptorValue)>),
+    /// Not checked at all, only reported to once its hits are known.
+    Report(Rc<RateLimitService>, HitsAddend),
+}
+
+impl CompiledAction {
+    fn task(&self, clock: &Rc<dyn Clock>) -> Box<dyn Task> {
+        let service = match &self.backend {
+            Backend::Remote(service) => service.clone(),
+            Backend::Local(limiter, descriptor_entries) => {
+                return Box::new(LocalRateLimitTask {
+                    predicate: self.predicate.clone(),
+                    limiter: limiter.clone(),
+                    clock: clock.clone(),
+                    descriptor_entries: descriptor_entries.clone(),
+                });
+            }
+            Backend::Report(service, hits_addend) => {
+                return Box::new(ReportTask {
+                    predicate: self.predicate.clone(),
+                    service: service.clone(),
+                    hits_addend: hits_addend.clone(),
+                });
+            }
+        };
+        let deny_task: Box<dyn Task> = match self.kind {
+            ServiceKind::RateLimit | ServiceKind::LocalRateLimit => {
+                Box::new(TooManyRequestsTask {})
+            }
+            // The service usually tells what to reply with, falling back to a plain 403
+            ServiceKind::Auth => Box::new(LocalReplyTask {
+                status_code: Some(403),
+                headers: Vec::new(),
+                body: None,
+            }),
+        };
+        Box::new(RLTask {
+            predicate: self.predicate.clone(),
+            service,
+            allow_task: None,
+            deny_task,
+            failure_mode: self.failure_mode,
+        })
+    }
+}
+
+struct CompiledActionSet {
+    name: String,
+    hostnames: Vec<String>,
+    headers: Vec<HeaderMatcher>,
+    query_params: Vec<QueryParameterMatcher>,
+    predicate: Predicate,
+    actions: Vec<CompiledAction>,
+    path_rewrite: Option<RegexRewrite>,
+}
+
+impl CompiledActionSet {
+    /// How specifically the action set targets `host`, if at all.
+    fn specificity(&self, host: &str) -> Option<(bool, usize)> {
+        self.hostnames
+            .iter()
+            .filter_map(|hostname| match hostname.strip_prefix('*') {
+                None => hostname
+                    .eq_ignore_ascii_case(host)
+                    .then_some((true, hostname.len())),
+                Some(suffix) => {
+                    let matches = host.len() > suffix.len()
+                        && host
+                            .get(host.len() - suffix.len()..)
+                            .is_some_and(|end| end.eq_ignore_ascii_case(suffix));
+                    matches.then_some((false, suffix.len()))
+                }
+            })
+            .max()
+    }
+}
+
+/// The validated configuration, building a fresh [`Pipeline`] for every request.
+pub struct PipelineFactory {
+    action_sets: Vec<CompiledActionSet>,
+    /// Selects the action set by its index instead of the route rule conditions.
+    action_set_matcher: Option<MatchTree<usize>>,
+    error_reply: ErrorReply,
+    /// The time of the local rate limits, and of the call deadlines.
+    clock: Rc<dyn Clock>,
+}
+
+impl TryFrom<PluginConfig> for PipelineFactory {
+    type Error = ConfigError;
+
+    fn try_from(config: PluginConfig) -> Result<Self, Self::Error> {
+        // Shared by all the actions using them, for their buckets to be
+        let mut limiters = HashMap::new();
+        let mut grpc_services = HashMap::new();
+        let mut allowed_headers = HashMap::new();
+        for (name, service) in &config.services {
+            let matcher = allowed_headers_matcher(service).map_err(|error| {
+                ConfigError::InvalidAllowedHeaders {
+                    service: name.clone(),
+                    error,
+                }
+            })?;
+            if let Some(matcher) = matcher {
+                allowed_headers.insert(name.clone(), matcher);
+            }
+            match service.kind {
+                ServiceKind::LocalRateLimit => {
+                    let limiter = local_rate_limiter(service).map_err(|error| {
+                        ConfigError::InvalidLocalRateLimit {
+                            service: name.clone(),
+                            error,
+                        }
+                    })?;
+                    limiters.insert(name.clone(), Rc::new(limiter));
+                }
+                _ if service.endpoint.is_empty() => {
+                    return Err(ConfigError::MissingEndpoint(name.clone()));
+                }
+                _ => {
+                    let grpc_service =
+                        grpc_service(service).map_err(|error| ConfigError::InvalidGrpcService {
+                            service: name.clone(),
+                            error,
+                        })?;
+                    grpc_services.insert(name.clone(), grpc_service);
+                }
+            }
+        }
+        let mut action_sets = Vec::with_capacity(config.action_sets.len());
+        for action_set in config.action_sets {
+            let name = &action_set.name;
+            let predicate = compile(name, &action_set.route_rule_conditions.predicates)?;
+            let headers = action_set
+                .route_rule_conditions
+                .headers
+                .iter()
+                .map(|condition| header_matcher(name, condition))
+                .collect::<Result<_, _>>()?;
+            let query_params = action_set
+                .route_rule_conditions
+                .query_params
+                .iter()
+                .map(|condition| query_param_matcher(name, condition))
+                .collect::<Result<_, _>>()?;
+            let path_rewrite = action_set
+                .path_rewrite
+                .as_ref()
+                .map(regex_rewrite)
+                .transpose()
+                .map_err(|error| ConfigError::InvalidRewrite {
+                    action_set: name.clone(),
+                    key: ":path".to_string(),
+                    error,
+                })?;
+            let mut actions = Vec::with_capacity(action_set.actions.len());
+            for action in action_set.actions {
+                let Some(service) = config.services.get(&action.service) else {
+                    return Err(ConfigError::UnknownService {
+                        action_set: name.clone(),
+                        service: action.service,
+                    });
+                };
+                if action.hits_addend.is_some() && service.kind != ServiceKind::RateLimit {
+                    return Err(ConfigError::UnexpectedHitsAddend {
+                        action_set: name.clone(),
+                        service: action.service,
+                    });
+                }
+                if !action.rate_limits.is_empty() && service.kind != ServiceKind::RateLimit {
+                    return Err(ConfigError::UnexpectedRateLimits {
+                        action_set: name.clone(),
+                        service: action.service,
+                    });
+                }
+                let backend = match service.kind {
+                    ServiceKind::RateLimit => {
+                        let rate_limits = action
+                            .rate_limits
+                            .iter()
+                            .map(|rate_limit| descriptor_builder(name, rate_limit))
+                            .collect::<Result<_, _>>()?;
+                        let service = Rc::new(
+                            RateLimitService::new(
+                                grpc_services[&action.service].clone(),
+                                &action.scope,
+                                descriptor_entries(name, action.data)?,
+                            )
+                            .with_rate_limits(rate_limits),
+                        );
+                        match action.hits_addend {
+                            Some(HitsAddendConfig::ResponseBody(path)) => Backend::Report(
+                                service,
+                                HitsAddend::ResponseBody(
+                                    path.split('.').map(str::to_string).collect(),
+                                ),
+                            ),
+                            None => Backend::Remote(service),
+                        }
+                    }
+                    ServiceKind::LocalRateLimit => Backend::Local(
+                        limiters[&action.service].clone(),
+                        descriptor_entries(name, action.data)?,
+                    ),
+                    ServiceKind::Auth if !action.data.is_empty() => {
+                        return Err(ConfigError::UnexpectedData {
+                            action_set: name.clone(),
+                            service: action.service,
+                        });
+                    }
+                    ServiceKind::Auth => {
+                        let mut service = AuthService::new(
+                            grpc_services[&action.service].clone(),
+                            HashMap::from([("host".to_string(), action.scope.clone())]),
+                        );
+                        if let Some(matcher) = allowed_headers.get(&action.service) {
+                            service = service.with_allowed_headers(matcher.clone());
+                        }
+                        Backend::Remote(Rc::new(service))
+                    }
+                };
+                let mut predicate = compile(name, &action.predicates)?;
+                if let Some(body) = &action.request_body_match {
+                    predicate = Predicate::all(vec![predicate, body_match(name, body)?]);
+                }
+                actions.push(CompiledAction {
+                    predicate,
+                    kind: service.kind,
+                    failure_mode: service.failure_mode,
+                    backend,
+                });
+            }
+            action_sets.push(CompiledActionSet {
+                name: name.clone(),
+                hostnames: action_set.route_rule_conditions.hostnames,
+                headers,
+                query_params,
+                predicate,
+                actions,
+                path_rewrite,
+            });
+        }
+        let factory = Self {
+            action_sets,
+            action_set_matcher: None,
+            error_reply: config.error_reply,
+            clock: Rc::new(SystemClock),
+        };
+        match config.action_set_matcher {
+            Some(matcher) => factory.with_action_set_matcher(
+                &envoy_matcher(&matcher).map_err(ConfigError::InvalidActionSetMatcher)?,
+                &DataInputRegistry::default(),
+            ),
+            None => Ok(factory),
+        }
+    }
+}
+
+fn descriptor_entries(
+    action_set: &str,
+    data: Vec<DataItem>,
+) -> Result<Vec<(String, DescriptorValue)>, ConfigError> {
+    data.into_iter()
+        .map(|item| match item {
+            DataItem::Static { key, value } => Ok((key, DescriptorValue::Static(value))),
+            DataItem::Attribute {
+                key,
+                path,
+                rewrite: None,
+            } => Ok((key, DescriptorValue::Attribute(path))),
+            DataItem::Attribute {
+                key,
+                path,
+                rewrite: Some(rewrite),
+            } => match regex_rewrite(&rewrite) {
+                Ok(rewrite) => Ok((key, DescriptorValue::Rewritten(path, rewrite))),
+                Err(error) => Err(ConfigError::InvalidRewrite {
+                    action_set: action_set.to_string(),
+                    key,
+                    error,
+                }),
+            },
+        })
+        .collect()
+}
+
+fn descriptor_builder(
+    action_set: &str,
+    rate_limit: &RateLimitConfig,
+) -> Result<DescriptorBuilder, ConfigError> {
+    use RateLimit_Action_oneof_action_specifier as Specifier;
+    let invalid = |error: String| ConfigError::InvalidRateLimit {
+        action_set: action_set.to_string(),
+        error,
+    };
+    let actions = rate_limit
+        .actions
+        .iter()
+        .map(|action| {
+            let action_specifier = match action {
+                RateLimitActionConfig::SourceCluster {} => {
+                    Specifier::source_cluster(Default::default())
+                }
+                RateLimitActionConfig::DestinationCluster {} => {
+                    Specifier::destination_cluster(Default::default())
+                }
+                RateLimitActionConfig::RequestHeaders {
+                    header_name,
+                    descriptor_key,
+                    skip_if_absent,
+                } => Specifier::request_headers(RateLimit_Action_RequestHeaders {
+                    header_name: header_name.clone(),
+                    descriptor_key: descriptor_key.clone(),
+                    skip_if_absent: *skip_if_absent,
+                    ..Default::default()
+                }),
+                RateLimitActionConfig::RemoteAddress {} => {
+                    Specifier::remote_address(Default::default())
+                }
+                RateLimitActionConfig::GenericKey {
+                    descriptor_value,
+                    descriptor_key,
+                } => Specifier::generic_key(RateLimit_Action_GenericKey {
+                    descriptor_value: descriptor_value.clone(),
+                    descriptor_key: descriptor_key.clone(),
+                    ..Default::default()
+                }),
+                RateLimitActionConfig::HeaderValueMatch {
+                    descriptor_value,
+                    expect_match,
+                    headers,
+                } => Specifier::header_value_match(RateLimit_Action_HeaderValueMatch {
+                    descriptor_value: descriptor_value.clone(),
+                    expect_match: expect_match
+                        .map(|value| BoolValue {
+                            value,
+                            ..Default::default()
+                        })
+                        .into(),
+                    headers: headers
+                        .iter()
+                        .map(envoy_header_matcher)
+                        .collect::<Result<_, _>>()
+                        .map_err(invalid)?,
+                    ..Default::default()
+                }),
+                RateLimitActionConfig::DynamicMetadata {
+                    descriptor_key,
+                    metadata_key,
+                    default_value,
+                } => Specifier::dynamic_metadata(RateLimit_Action_DynamicMetaData {
+                    descriptor_key: descriptor_key.clone(),
+                    metadata_key: Some(envoy_metadata_key(&metadata_key.key, &metadata_key.path))
+                        .into(),
+                    default_value: default_value.clone(),
+                    ..Default::default()
+                }),
+                RateLimitActionConfig::Metadata {
+                    descriptor_key,
+                    metadata_key,
+                    default_value,
+                    source,
+                } => Specifier::metadata(RateLimit_Action_MetaData {
+                    descriptor_key: descriptor_key.clone(),
+                    metadata_key: Some(envoy_metadata_key(&metadata_key.key, &metadata_key.path))
+                        .into(),
+                    default_value: default_value.clone(),
+                    source: match source {
+                        MetadataSourceConfig::Dynamic => RateLimit_Action_MetaData_Source::DYNAMIC,
+                        MetadataSourceConfig::RouteEntry => {
+                            RateLimit_Action_MetaData_Source::ROUTE_ENTRY
+                        }
+                    },
+                    ..Default::default()
+                }),
+            };
+            Ok(RateLimit_Action {
+                action_specifier: Some(action_specifier),
+                ..Default::default()
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    let limit = rate_limit.limit.as_ref().map(|limit| match limit {
+        RateLimitOverrideConfig::DynamicMetadata { metadata_key } => RateLimit_Override {
+            override_specifier: Some(
+                RateLimit_Override_oneof_override_specifier::dynamic_metadata(
+                    RateLimit_Override_DynamicMetadata {
+                        metadata_key: Some(envoy_metadata_key(
+                            &metadata_key.key,
+                            &metadata_key.path,
+                        ))
+                        .into(),
+                        ..Default::default()
+                    },
+                ),
+            ),
+            ..Default::default()
+        },
+    });
+    let envoy_rate_limit = envoy::RateLimit {
+        actions,
+        limit: limit.into(),
+        ..Default::default()
+    };
+    let builder = DescriptorBuilder::try_from(&envoy_rate_limit)
+        .map_err(|error| invalid(error.to_string()))?;
+    // The protos predate `treat_missing_header_as_empty`, the compiled matchers know about it
+    let missing_as_empty = rate_limit_config_headers(rate_limit)
+        .filter(|(_, _, condition)| condition.treat_missing_header_as_empty);
+    Ok(
+        missing_as_empty.fold(builder, |builder, (action, header, _)| {
+            builder.treat_missing_as_empty(action, header)
+        }),
+    )
+}
+
+/// The header conditions of the header value match actions of `rate_limit`, along with the
+/// index of their action and their own.
+fn rate_limit_config_headers(
+    rate_limit: &RateLimitConfig,
+) -> impl Iterator<Item = (usize, usize, &HeaderCondition)> {
+    rate_limit
+        .actions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, action)| match action {
+            RateLimitActionConfig::HeaderValueMatch { headers, .. } => Some((index, headers)),
+            _ => None,
+        })
+        .flat_map(|(action, headers)| {
+            headers
+                .iter()
+                .enumerate()
+                .map(move |(header, condition)| (action, header, condition))
+        })
+}
+
+fn envoy_metadata_key(key: &str, path: &[PathSegmentConfig]) -> envoy::MetadataKey {
+    envoy::MetadataKey {
+        key: key.to_string(),
+        path: path
+            .iter()
+            .map(|segment| MetadataKey_PathSegment {
+                segment: Some(MetadataKey_PathSegment_oneof_segment::key(
+                    segment.key.clone(),
+                )),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+fn envoy_matcher(matcher: &MatcherConfig) -> Result<envoy::Matcher, MatcherError> {
+    use Matcher_MatcherTree_oneof_tree_type as TreeType;
+    let matcher_list = matcher
+        .matcher_list
+        .as_ref()
+        .map(|list| {
+            let matchers = list
+                .matchers
+                .iter()
+                .map(|field| {
+                    Ok(Matcher_MatcherList_FieldMatcher {
+                        predicate: Some(envoy_predicate(&field.predicate)?).into(),
+                        on_match: Some(envoy_on_match(&field.on_match)?).into(),
+                        ..Default::default()
+                    })
+                })
+                .collect::<Result<_, MatcherError>>()?;
+            Ok(Matcher_oneof_matcher_type::matcher_list(
+                Matcher_MatcherList {
+                    matchers,
+                    ..Default::default()
+                },
+            ))
+        })
+        .transpose()?;
+    let matcher_tree = matcher
+        .matcher_tree
+        .as_ref()
+        .map(|tree| {
+            let exact_match_map = tree
+                .exact_match_map
+                .as_ref()
+                .map(|map| envoy_match_map(map).map(TreeType::exact_match_map))
+                .transpose()?;
+            let prefix_match_map = tree
+                .prefix_match_map
+                .as_ref()
+                .map(|map| envoy_match_map(map).map(TreeType::prefix_match_map))
+                .transpose()?;
+            Ok(Matcher_oneof_matcher_type::matcher_tree(
+                Matcher_MatcherTree {
+                    input: Some(envoy_data_input(&tree.input)).into(),
+                    tree_type: one_of("tree_type", [exact_match_map, prefix_match_map])?,
+                    ..Default::default()
+                },
+            ))
+        })
+        .transpose()?;
+    Ok(envoy::Matcher {
+        matcher_type: one_of("matcher_type", [matcher_list, matcher_tree])?,
+        on_no_match: matcher
+            .on_no_match
+            .as_ref()
+            .map(envoy_on_match)
+            .transpose()?
+            .into(),
+        ..Default::default()
+    })
+}
+
+fn envoy_on_match(on_match: &OnMatchConfig) -> Result<Matcher_OnMatch, MatcherError> {
+    use Matcher_OnMatch_oneof_on_match as OnMatch;
+    let matcher = on_match
+        .matcher
+        .as_deref()
+        .map(|matcher| envoy_matcher(matcher).map(OnMatch::matcher))
+        .transpose()?;
+    let action = on_match.action.as_ref().map(|action| {
+        OnMatch::action(TypedExtensionConfig {
+            name: action.name.clone(),
+            ..Default::default()
+        })
+    });
+    Ok(Matcher_OnMatch {
+        on_match: one_of("on_match", [matcher, action])?,
+        ..Default::default()
+    })
+}
+
+fn envoy_match_map(map: &MatchMapConfig) -> Result<Matcher_MatcherTree_MatchMap, MatcherError> {
+    Ok(Matcher_MatcherTree_MatchMap {
+        map: map
+            .map
+            .iter()
+            .map(|(key, on_match)| Ok((key.clone(), envoy_on_match(on_match)?)))
+            .collect::<Result<_, MatcherError>>()?,
+        ..Default::default()
+    })
+}
+
+fn envoy_predicate(
+    predicate: &PredicateConfig,
+) -> Result<Matcher_MatcherList_Predicate, MatcherError> {
+    use Matcher_MatcherList_Predicate_oneof_match_type as MatchType;
+    let list = |list: &PredicateListConfig| {
+        list.predicate
+            .iter()
+            .map(envoy_predicate)
+            .collect::<Result<_, _>>()
+            .map(|predicate| Matcher_MatcherList_Predicate_PredicateList {
+                predicate,
+                ..Default::default()
+            })
+    };
+    let single_predicate = predicate
+        .single_predicate
+        .as_ref()
+        .map(|single| {
+            let value_match = string_matcher(&single.value_match)
+                .map_err(|_| MatcherError::MoreThanOne("match_pattern"))?;
+            Ok(MatchType::single_predicate(
+                Matcher_MatcherList_Predicate_SinglePredicate {
+                    input: Some(envoy_data_input(&single.input)).into(),
+                    matcher: Some(
+                        Matcher_MatcherList_Predicate_SinglePredicate_oneof_matcher::value_match(
+                            value_match,
+                        ),
+                    ),
+                    ..Default::default()
+                },
+            ))
+        })
+        .transpose()?;
+    let or_matcher = predicate
+        .or_matcher
+        .as_ref()
+        .map(|predicates| list(predicates).map(MatchType::or_matcher))
+        .transpose()?;
+    let and_matcher = predicate
+        .and_matcher
+        .as_ref()
+        .map(|predicates| list(predicates).map(MatchType::and_matcher))
+        .transpose()?;
+    let not_matcher = predicate
+        .not_matcher
+        .as_deref()
+        .map(|predicate| {
+            envoy_predicate(predicate).map(|predicate| MatchType::not_matcher(Box::new(predicate)))
+        })
+        .transpose()?;
+    Ok(Matcher_MatcherList_Predicate {
+        match_type: one_of(
+            "match_type",
+            [single_predicate, or_matcher, and_matcher, not_matcher],
+        )?,
+        ..Default::default()
+    })
+}
+
+/// The input as the registry gets it: its `typed_config` encoded, behind its type URL.
+fn envoy_data_input(input: &DataInputConfig) -> TypedExtensionConfig {
+    let (type_url, value) = match &input.typed_config {
+        TypedDataInput::RequestHeader { header_name } => (
+            "type.googleapis.com/envoy.type.matcher.v3.HttpRequestHeaderMatchInput",
+            string_message(header_name),
+        ),
+        TypedDataInput::QueryParam { query_param } => (
+            "type.googleapis.com/envoy.type.matcher.v3.HttpRequestQueryParamMatchInput",
+            string_message(query_param),
+        ),
+        // A `MetadataKey` has the same fields as a `DynamicMetadataInput`
+        TypedDataInput::DynamicMetadata { filter, path } => (
+            "type.googleapis.com/envoy.extensions.matching.common_inputs.network.v3.DynamicMetadataInput",
+            envoy_metadata_key(filter, path)
+                .write_to_bytes()
+                .expect("MetadataKey is always serializable"),
+        ),
+    };
+    TypedExtensionConfig {
+        name: input.name.clone(),
+        typed_config: Some(Any {
+            type_url: type_url.to_string(),
+            value,
+            ..Default::default()
+        })
+        .into(),
+        ..Default::default()
+    }
+}
+
+/// A message of a single string field, numbered 1.
+fn string_message(value: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    let mut output = CodedOutputStream::vec(&mut message);
+    output
+        .write_string(1, value)
+        .and_then(|()| output.flush())
+        .expect("writing to a Vec doesn't fail");
+    drop(output);
+    message
+}
+
+/// The field of a `oneof` that's set, if any.
+fn one_of<T, const N: usize>(
+    oneof: &'static str,
+    fields: [Option<T>; N],
+) -> Result<Option<T>, MatcherError> {
+    let mut fields = fields.into_iter().flatten();
+    let field = fields.next();
+    if fields.next().is_some() {
+        return Err(MatcherError::MoreThanOne(oneof));
+    }
+    Ok(field)
+}
+
+fn local_rate_limiter(service: &ServiceConfig) -> Result<LocalRateLimiter, String> {
+    let descriptors = service
+        .descriptors
+        .iter()
+        .map(|descriptor| {
+            Ok(LocalRateLimitDescriptor {
+                entries: descriptor
+                    .entries
+                    .iter()
+                    .map(|entry| RateLimitDescriptor_Entry {
+                        key: entry.key.clone(),
+                        value: entry.value.clone(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                token_bucket: Some(token_bucket(&descriptor.token_bucket)?).into(),
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let default_bucket = service
+        .token_bucket
+        .as_ref()
+        .map(token_bucket)
+        .transpose()?;
+    LocalRateLimiter::new(&descriptors, default_bucket.as_ref())
+        .map_err(|error: LocalRateLimitError| error.to_string())
+}
+
+/// An Envoy `GrpcService` calling the service's endpoint.
+fn allowed_headers_matcher(service: &ServiceConfig) -> Result<Option<ListStringMatcher>, String> {
+    let Some(conditions) = &service.allowed_headers else {
+        return Ok(None);
+    };
+    if service.kind != ServiceKind::Auth {
+        return Err("only an auth service takes them".to_string());
+    }
+    let patterns = conditions
+        .iter()
+        .map(string_matcher)
+        .collect::<Result<Vec<_>, _>>()?;
+    ListStringMatcher::try_from(&envoy::ListStringMatcher {
+        patterns: patterns.into(),
+        ..Default::default()
+    })
+    .map(Some)
+    .map_err(|error| error.to_string())
+}
+
+fn grpc_service(service: &ServiceConfig) -> Result<GrpcService, String> {
+    let timeout = service
+        .timeout
+        .as_ref()
+        .map(|timeout| {
+            duration(timeout)
+                .map(|timeout| protobuf::well_known_types::Duration {
+                    seconds: timeout.as_secs() as i64,
+                    nanos: timeout.subsec_nanos() as i32,
+                    ..Default::default()
+                })
+                .ok_or_else(|| format!("invalid timeout `{timeout}`"))
+        })
+        .transpose()?;
+    let grpc_service = envoy::GrpcService {
+        target_specifier: Some(GrpcService_oneof_target_specifier::envoy_grpc(
+            GrpcService_EnvoyGrpc {
+                cluster_name: service.endpoint.clone(),
+                ..Default::default()
+            },
+        )),
+        timeout: timeout.into(),
+        initial_metadata: service
+            .initial_metadata
+            .iter()
+            .map(|(key, value)| HeaderValue {
+                key: key.clone(),
+                value: value.clone(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    GrpcService::try_from(&grpc_service).map_err(|error| error.to_string())
+}
+
+/// A JSON `Duration`, in seconds with an `s` suffix, e.g. `1.5s`.
+fn duration(value: &str) -> Option<std::time::Duration> {
+    value
+        .strip_suffix('s')
+        .and_then(|seconds| seconds.parse::<f64>().ok())
+        .and_then(|seconds| std::time::Duration::try_from_secs_f64(seconds).ok())
+}
+
+fn token_bucket(bucket: &TokenBucketConfig) -> Result<TokenBucket, String> {
+    let fill_interval = duration(&bucket.fill_interval)
+        .ok_or_else(|| format!("invalid fill interval `{}`", bucket.fill_interval))?;
+    Ok(TokenBucket {
+        max_tokens: bucket.max_tokens,
+        tokens_per_fill: bucket
+            .tokens_per_fill
+            .map(|tokens| protobuf::well_known_types::UInt32Value {
+                value: tokens,
+                ..Default::default()
+            })
+            .into(),
+        fill_interval: Some(protobuf::well_known_types::Duration {
+            seconds: fill_interval.as_secs() as i64,
+            nanos: fill_interval.subsec_nanos() as i32,
+            ..Default::default()
+        })
+        .into(),
+        ..Default::default()
+    })
+}
+
+fn compile(action_set: &str, predicates: &[String]) -> Result<Predicate, ConfigError> {
+    let predicates = predicates
+        .iter()
+        .map(|source| {
+            Predicate::new(source).map_err(|error| ConfigError::InvalidPredicate {
+                action_set: action_set.to_string(),
+                predicate: source.clone(),
+                error,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Predicate::all(predicates))
+}
+
+fn body_match(action_set: &str, body: &BodyCondition) -> Result<Predicate, ConfigError> {
+    let predicate = envoy::MatchPredicate {
+        rule: Some(MatchPredicate_oneof_rule::http_request_generic_body_match(
+            HttpGenericBodyMatch {
+                bytes_limit: body.bytes_limit,
+                patterns: body
+                    .patterns
+                    .iter()
+                    .map(|pattern| HttpGenericBodyMatch_GenericTextMatch {
+                        rule: Some(
+                            HttpGenericBodyMatch_GenericTextMatch_oneof_rule::string_match(
+                                pattern.clone(),
+                            ),
+                        ),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+        )),
+        ..Default::default()
+    };
+    MatchPredicate::try_from(&predicate)
+        .map(Predicate::from)
+        .map_err(|error| ConfigError::InvalidBodyMatch {
+            action_set: action_set.to_string(),
+            error,
+        })
+}
+
+fn header_matcher(
+    action_set: &str,
+    condition: &HeaderCondition,
+) -> Result<HeaderMatcher, ConfigError> {
+    let invalid = |error: String| ConfigError::InvalidHeaderMatcher {
+        action_set: action_set.to_string(),
+        header: condition.name.clone(),
+        error,
+    };
+    let matcher = envoy_header_matcher(condition).map_err(invalid)?;
+    HeaderMatcher::try_from(&matcher)
+        .map(|matcher| matcher.treat_missing_as_empty(condition.treat_missing_header_as_empty))
+        .map_err(|error: MatcherError| invalid(error.to_string()))
+}
+
+fn envoy_header_matcher(condition: &HeaderCondition) -> Result<envoy::HeaderMatcher, String> {
+    use HeaderMatcher_oneof_header_match_specifier as Specifier;
+    let specifiers = [
+        condition.exact_match.clone().map(Specifier::exact_match),
+        condition.safe_regex_match.as_ref().map(|regex| {
+            Specifier::safe_regex_match(RegexMatcher {
+                regex: regex.regex.clone(),
+                ..Default::default()
+            })
+        }),
+        condition.range_match.as_ref().map(|range| {
+            Specifier::range_match(Int64Range {
+                start: range.start,
+                end: range.end,
+                ..Default::default()
+            })
+        }),
+        condition.present_match.map(Specifier::present_match),
+        condition.prefix_match.clone().map(Specifier::prefix_match),
+        condition.suffix_match.clone().map(Specifier::suffix_match),
+        condition
+            .contains_match
+            .clone()
+            .map(Specifier::contains_match),
+    ];
+    let mut specifiers = specifiers.into_iter().flatten();
+    let header_match_specifier = specifiers.next();
+    if specifiers.next().is_some() {
+        return Err("more than one match".to_string());
+    }
+    Ok(envoy::HeaderMatcher {
+        name: condition.name.clone(),
+        invert_match: condition.invert_match,
+        header_match_specifier,
+        ..Default::default()
+    })
+}
+
+fn query_param_matcher(
+    action_set: &str,
+    condition: &QueryParamCondition,
+) -> Result<QueryParameterMatcher, ConfigError> {
+    use QueryParameterMatcher_oneof_query_parameter_match_specifier as Specifier;
+    let invalid = |error: String| ConfigError::InvalidQueryParamMatcher {
+        action_set: action_set.to_string(),
+        param: condition.name.clone(),
+        error,
+    };
+    let query_parameter_match_specifier = match (&condition.string_match, condition.present_match) {
+        (Some(_), Some(_)) => return Err(invalid("more than one match".to_string())),
+        (Some(string), None) => Some(Specifier::string_match(
+            string_matcher(string).map_err(invalid)?,
+        )),
+        (None, present) => present.map(Specifier::present_match),
+    };
+    let matcher = envoy::QueryParameterMatcher {
+        name: condition.name.clone(),
+        query_parameter_match_specifier,
+        ..Default::default()
+    };
+    QueryParameterMatcher::try_from(&matcher).map_err(|error| invalid(error.to_string()))
+}
+
+fn string_matcher(condition: &StringCondition) -> Result<envoy::StringMatcher, String> {
+    use StringMatcher_oneof_match_pattern as MatchPattern;
+    let patterns = [
+        condition.exact.clone().map(MatchPattern::exact),
+        condition.prefix.clone().map(MatchPattern::prefix),
+        condition.suffix.clone().map(MatchPattern::suffix),
+        condition.contains.clone().map(MatchPattern::contains),
+        condition.safe_regex.as_ref().map(|regex| {
+            MatchPattern::safe_regex(RegexMatcher {
+                regex: regex.regex.clone(),
+                ..Default::default()
+            })
+        }),
+    ];
+    let mut patterns = patterns.into_iter().flatten();
+    let match_pattern = patterns.next();
+    if patterns.next().is_some() {
+        return Err("more than one match".to_string());
+    }
+    Ok(envoy::StringMatcher {
+        match_pattern,
+        ignore_case: condition.ignore_case,
+        ..Default::default()
+    })
+}
+
+fn regex_rewrite(rewrite: &RewriteCondition) -> Result<RegexRewrite, MatcherError> {
+    RegexRewrite::try_from(&RegexMatchAndSubstitute {
+        pattern: Some(RegexMatcher {
+            regex: rewrite.pattern.regex.clone(),
+            ..Default::default()
+        })
+        .into(),
+        substitution: rewrite.substitution.clone(),
+        ..Default::default()
+    })
+}
+
+impl PipelineFactory {
+    /// Selects action sets with a unified matcher rather than their route rule conditions, its
+    /// actions naming the action set to use, e.g. with
+    /// `TypedExtensionConfig { name: "api", .. }`.
+    pub fn with_action_set_matcher(
+        mut self,
+        matcher: &envoy::Matcher,
+        registry: &DataInputRegistry,
+    ) -> Result<Self, ConfigError> {
+        let action_sets = &self.action_sets;
+        let matcher = MatchTree::compile(matcher, registry, &mut |action| {
+            action_sets
+                .iter()
+                .position(|action_set| action_set.name == action.name)
+                .ok_or_else(|| MatcherError::UnknownAction(action.name.clone()))
+        })
+        .map_err(ConfigError::InvalidActionSetMatcher)?;
+        self.action_set_matcher = Some(matcher);
+        Ok(self)
+    }
+
+    /// Tells the time to local rate limits and pipelines with `clock` rather than the system's.
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Builds the pipeline for the request on `host`, `None` when no action set applies. Route
+    /// predicates, or the action set matcher, are evaluated on the request headers, one still
+    /// pending doesn't hold.
+    pub fn build(&self, host: Box<dyn Host>) -> Option<Pipeline> {
+        let ctx = ReqRespCtx::new(host);
+        let action_set = match &self.action_set_matcher {
+            Some(matcher) => match matcher.evaluate(&ctx) {
+                PendingValue::Resolved(index) => &self.action_sets[*index?],
+                PendingValue::Pending => return None,
+            },
+            None => self.select(&ctx)?,
+        };
+
+        let mut todos: Vec<Box<dyn Task>> = action_set
+            .actions
+            .iter()
+            .map(|action| action.task(&self.clock))
+            .collect();
+        // Last, for the actions to see the path as requested
+        if let Some(rewrite) = &action_set.path_rewrite {
+            todos.push(Box::new(RewritePathTask {
+                rewrite: rewrite.clone(),
+            }));
+        }
+        Some(
+            Pipeline::new(ctx, todos)
+                .with_error_reply(self.error_reply.clone())
+                .with_clock(self.clock.clone()),
+        )
+    }
+
+    fn select(&self, ctx: &ReqRespCtx) -> Option<&CompiledActionSet> {
+        let authority = ctx.host.request_headers().get(":authority")?.to_string();
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+            _ => authority.as_str(),
+        };
+
+        let mut candidates: Vec<_> = self
+            .action_sets
+            .iter()
+            .filter_map(|action_set| Some((action_set.specificity(host)?, action_set)))
+            .collect();
+        // Stable, so that equally specific action sets keep their configured order
+        candidates.sort_by(|(lhs, _), (rhs, _)| rhs.cmp(lhs));
+        let headers = ctx.host.request_headers();
+        let query_params = ctx.query_params();
+        let (_, action_set) = candidates.into_iter().find(|(_, action_set)| {
+            action_set
+                .headers
+                .iter()
+                .all(|matcher| matcher.matches(&headers))
+                && action_set
+                    .query_params
+                    .iter()
+                    .all(|matcher| matcher.matches(&query_params))
+                && action_set.predicate.eval(ctx) == PendingValue::Resolved(true)
+        })?;
+        Some(action_set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Phase;
+    use crate::PipelineError;
+    use crate::envoy::{CheckRequest, RateLimitRequest};
+    use crate::host::MockHost;
+    use protobuf::Message;
+
+    const CONFIG: &str = r#"
+services:
+  authorino:
+    type: auth
+    endpoint: authorino-cluster
+  limitador:
+    type: ratelimit
+    endpoint: limitador-cluster
+    timeout: 0.2s
+    initialMetadata: { x-tenant: acme }
+actionSets:
+  - name: catch-all
+    routeRuleConditions:
+      hostnames: ["*"]
+    actions:
+      - service: limitador
+        scope: default
+  - name: api
+    routeRuleConditions:
+      hostnames: ["*.example.com"]
+      predicates: ['request.url_path.startsWith("/api")']
+    actions:
+      - service: authorino
+        scope: api
+      - service: limitador
+        scope: api
+        predicates: ['request.method == "POST"']
+        data:
+          - static: { key: tier, value: gold }
+          - attribute: { key: method, path: request.method }
+"#;
+
+    fn factory() -> PipelineFactory {
+        let config = PluginConfig::from_yaml(CONFIG).expect("valid configuration");
+        PipelineFactory::try_from(config).expect("valid configuration")
+    }
+
+    fn request(authority: &str, path: &str, method: &str) -> MockHost {
+        headers(vec![
+            (":authority", authority),
+            (":path", path),
+            (":method", method),
+        ])
+    }
+
+    fn headers(headers: Vec<(&str, &str)>) -> MockHost {
+        MockHost::default()
+            .with_request_headers(headers)
+            .with_phase(Phase::RequestHeaders)
+    }
+
+    /// How many tasks the pipeline built for the request on `host` has, `None` when no action
+    /// set applies.
+    fn todos(factory: &PipelineFactory, host: MockHost) -> Option<usize> {
+        factory
+            .build(Box::new(host))
+            .map(|pipeline| pipeline.todos.len())
+    }
+
+    #[test]
+    fn it_parses_json_and_yaml_alike() {
+        let json = r#"{
+            "services": {"limitador": {
+                "type": "ratelimit",
+                "endpoint": "limitador-cluster",
+                "failureMode": "allow"
+            }},
+            "errorReply": {"statusCode": 503, "body": "unavailable"},
+            "actionSets": [{
+                "name": "api",
+                "routeRuleConditions": {"hostnames": ["api.example.com"]},
+                "actions": [{
+                    "service": "limitador",
+                    "scope": "api",
+                    "data": [{"static": {"key": "tier", "value": "gold"}}]
+                }]
+            }]
+        }"#;
+        let yaml = r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster, failureMode: allow }
+errorReply: { statusCode: 503, body: unavailable }
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: [api.example.com] }
+    actions:
+      - service: limitador
+        scope: api
+        data: [ static: { key: tier, value: gold } ]
+"#;
+        let config = PluginConfig::from_json(json).expect("valid json");
+        assert_eq!(config, PluginConfig::from_yaml(yaml).expect("valid yaml"));
+        assert_eq!(config.services["limitador"].kind, ServiceKind::RateLimit);
+        assert_eq!(
+            config.services["limitador"].failure_mode,
+            FailureMode::Allow
+        );
+        assert_eq!(config.error_reply.status_code, 503);
+        assert_eq!(
+            config.action_sets[0].actions[0].data,
+            vec![DataItem::Static {
+                key: "tier".to_string(),
+                value: "gold".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn it_builds_a_pipeline_per_request() -> Result<(), PipelineError> {
+        let factory = factory();
+        for _ in 0..2 {
+            let host = request("api.example.com:8080", "/api/users", "POST");
+            let mut pipeline = factory
+                .build(Box::new(host.clone()))
+                .expect("the api action set applies")
+                .eval()?
+                .expect("waiting on authorino");
+            let calls = host.grpc_calls();
+            assert_eq!(calls.len(), 2);
+            assert_eq!(calls[0].upstream, "authorino-cluster");
+            let check = CheckRequest::parse_from_bytes(&calls[0].message).unwrap();
+            assert_eq!(check.get_attributes().context_extensions["host"], "api");
+            assert_eq!(calls[1].upstream, "limitador-cluster");
+            assert_eq!(
+                calls[1].timeout,
+                Some(std::time::Duration::from_millis(200))
+            );
+            assert_eq!(
+                calls[1].initial_metadata,
+                vec![("x-tenant".to_string(), "acme".to_string())]
+            );
+            let ratelimit = RateLimitRequest::parse_from_bytes(&calls[1].message).unwrap();
+            assert_eq!(ratelimit.domain, "api");
+            let entries: Vec<_> = ratelimit.descriptors[0]
+                .entries
+                .iter()
+                .map(|entry| (entry.key.as_str(), entry.value.as_str()))
+                .collect();
+            assert_eq!(entries, [("tier", "gold"), ("method", "POST")]);
+
+            pipeline.digest(1, Vec::new())?;
+            pipeline.digest(2, Vec::new())?;
+            assert!(pipeline.eval()?.is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn it_selects_the_most_specific_action_set() -> Result<(), PipelineError> {
+        let factory = factory();
+
+        let host = request("www.example.com", "/index.html", "GET");
+        let pipeline = factory.build(Box::new(host.clone())).expect("catch-all");
+        let mut pipeline = pipeline.eval()?.expect("waiting on limitador");
+        let calls = host.grpc_calls();
+        assert_eq!(calls.len(), 1, "route predicates of api don't hold");
+        let ratelimit = RateLimitRequest::parse_from_bytes(&calls[0].message).unwrap();
+        assert_eq!(ratelimit.domain, "default");
+        pipeline.digest(1, Vec::new())?;
+
+        let host = request("API.example.com", "/api", "GET");
+        let pipeline = factory.build(Box::new(host.clone())).expect("api");
+        let mut pipeline = pipeline.eval()?.expect("waiting on authorino");
+        assert_eq!(host.grpc_calls().len(), 1, "only POSTs are rate limited");
+        pipeline.digest(1, Vec::new())?;
+
+        let config = PluginConfig::from_yaml(
+            r#"
+services: {}
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: [api.example.com] }
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        assert!(
+            factory
+                .build(Box::new(request("example.com", "/", "GET")))
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn it_selects_action_sets_by_header() {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: internal
+    routeRuleConditions:
+      hostnames: ["*"]
+      headers:
+        - { name: x-internal, presentMatch: true }
+        - { name: x-tier, safeRegexMatch: { regex: "gold|silver" }, invertMatch: true }
+  - name: default
+    routeRuleConditions: { hostnames: ["*"] }
+    actions: [ { service: limitador, scope: default } ]
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        assert_eq!(
+            todos(&factory, headers(vec![(":authority", "example.com")])),
+            Some(1)
+        );
+        assert_eq!(
+            todos(
+                &factory,
+                headers(vec![
+                    (":authority", "example.com"),
+                    ("x-internal", "1"),
+                    ("x-tier", "bronze")
+                ])
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            todos(
+                &factory,
+                headers(vec![
+                    (":authority", "example.com"),
+                    ("x-internal", "1"),
+                    ("x-tier", "gold")
+                ])
+            ),
+            Some(1)
+        );
+        assert_eq!(
+            todos(
+                &factory,
+                headers(vec![(":authority", "example.com"), ("x-internal", "1")])
+            ),
+            Some(1),
+            "an inverted regex doesn't match a missing header"
+        );
+    }
+
+    #[test]
+    fn it_selects_action_sets_by_query_param() {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: beta
+    routeRuleConditions:
+      hostnames: ["*"]
+      queryParams:
+        - { name: api_key, presentMatch: true }
+        - { name: ui, stringMatch: { exact: NEW, ignoreCase: true } }
+  - name: default
+    routeRuleConditions: { hostnames: ["*"] }
+    actions: [ { service: limitador, scope: default } ]
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        assert_eq!(
+            todos(
+                &factory,
+                request("example.com", "/?api_key=k&ui=new", "GET")
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            todos(&factory, request("example.com", "/?ui=New&api_key", "GET")),
+            Some(0)
+        );
+        assert_eq!(
+            todos(
+                &factory,
+                request("example.com", "/?api_key=k&ui=old&ui=new", "GET")
+            ),
+            Some(1)
+        );
+        assert_eq!(
+            todos(&factory, request("example.com", "/?ui=new", "GET")),
+            Some(1)
+        );
+        assert_eq!(todos(&factory, request("example.com", "/", "GET")), Some(1));
+    }
+
+    #[test]
+    fn it_selects_action_sets_with_a_matcher() {
+        use crate::envoy::{
+            Matcher, Matcher_MatcherTree, Matcher_MatcherTree_MatchMap,
+            Matcher_MatcherTree_oneof_tree_type, Matcher_OnMatch, Matcher_OnMatch_oneof_on_match,
+            Matcher_oneof_matcher_type, TypedExtensionConfig,
+        };
+        use protobuf::SingularPtrField;
+        use protobuf::well_known_types::Any;
+
+        let action = |name: &str| Matcher_OnMatch {
+            on_match: Some(Matcher_OnMatch_oneof_on_match::action(
+                TypedExtensionConfig {
+                    name: name.to_string(),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        };
+        // An `HttpRequestHeaderMatchInput` for `:method`
+        let method = TypedExtensionConfig {
+            typed_config: SingularPtrField::some(Any {
+                type_url: "type.googleapis.com/envoy.type.matcher.v3.HttpRequestHeaderMatchInput"
+                    .to_string(),
+                value: b"\n\x07:method".to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let matcher = |reads: &str| Matcher {
+            on_no_match: SingularPtrField::some(action("default")),
+            matcher_type: Some(Matcher_oneof_matcher_type::matcher_tree(
+                Matcher_MatcherTree {
+                    input: SingularPtrField::some(method.clone()),
+                    tree_type: Some(Matcher_MatcherTree_oneof_tree_type::exact_match_map(
+                        Matcher_MatcherTree_MatchMap {
+                            map: HashMap::from([("GET".to_string(), action(reads))]),
+                            ..Default::default()
+                        },
+                    )),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        };
+        let unmatched = || {
+            let config = PluginConfig::from_yaml(
+                r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: reads
+    routeRuleConditions: { hostnames: [reads.example.com] }
+  - name: default
+    routeRuleConditions: { hostnames: [default.example.com] }
+    actions: [ { service: limitador, scope: default } ]
+"#,
+            )
+            .unwrap();
+            PipelineFactory::try_from(config).unwrap()
+        };
+        let registry = DataInputRegistry::default();
+        let factory = unmatched()
+            .with_action_set_matcher(&matcher("reads"), &registry)
+            .unwrap();
+        assert_eq!(
+            todos(&factory, request("example.com", "/", "GET")),
+            Some(0),
+            "hostnames are left to the matcher"
+        );
+        assert_eq!(
+            todos(&factory, request("example.com", "/", "POST")),
+            Some(1)
+        );
+        assert_eq!(
+            unmatched()
+                .with_action_set_matcher(&matcher("writes"), &registry)
+                .err()
+                .map(|e| e.to_string()),
+            Some("invalid action set matcher: unknown action `writes`".to_string())
+        );
+    }
+
+    #[test]
+    fn it_configures_the_action_set_matcher() {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: reads
+    routeRuleConditions: { hostnames: [reads.example.com] }
+  - name: beta
+    routeRuleConditions: { hostnames: [beta.example.com] }
+    actions: [ { service: limitador, scope: beta }, { service: limitador, scope: beta } ]
+  - name: default
+    routeRuleConditions: { hostnames: [default.example.com] }
+    actions: [ { service: limitador, scope: default } ]
+actionSetMatcher:
+  matcherList:
+    matchers:
+      - predicate:
+          singlePredicate:
+            input:
+              typedConfig:
+                "@type": type.googleapis.com/envoy.type.matcher.v3.HttpRequestQueryParamMatchInput
+                queryParam: ui
+            valueMatch: { exact: beta }
+        onMatch: { action: { name: beta } }
+      - predicate:
+          notMatcher:
+            singlePredicate:
+              input:
+                typedConfig:
+                  "@type": type.googleapis.com/envoy.type.matcher.v3.HttpRequestHeaderMatchInput
+                  headerName: x-internal
+              valueMatch: { safeRegex: { regex: ".+" } }
+        onMatch:
+          matcher:
+            matcherTree:
+              input:
+                name: method
+                typedConfig:
+                  "@type": type.googleapis.com/envoy.type.matcher.v3.HttpRequestHeaderMatchInput
+                  headerName: ":method"
+              exactMatchMap: { map: { GET: { action: { name: reads } } } }
+  onNoMatch: { action: { name: default } }
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        assert_eq!(
+            todos(&factory, request("example.com", "/?ui=beta", "POST")),
+            Some(2)
+        );
+        assert_eq!(todos(&factory, request("example.com", "/", "GET")), Some(0));
+        assert_eq!(
+            todos(
+                &factory,
+                headers(vec![
+                    (":authority", "example.com"),
+                    (":path", "/"),
+                    (":method", "GET"),
+                    ("x-internal", "1")
+                ])
+            ),
+            Some(1)
+        );
+        assert_eq!(
+            todos(&factory, request("example.com", "/", "POST")),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn it_rewrites_the_path_after_the_actions() -> Result<(), PipelineError> {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: limitador
+        scope: api
+        data: [ attribute: { key: path, path: request.url_path } ]
+    pathRewrite: { pattern: { regex: "^/v1/(.*)$" }, substitution: '/\1' }
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        let host = request("api.example.com", "/v1/users?page=/v1/2", "GET");
+        factory.build(Box::new(host.clone())).expect("api").eval()?;
+
+        let request = RateLimitRequest::parse_from_bytes(&host.grpc_calls()[0].message).unwrap();
+        assert_eq!(request.descriptors[0].entries[0].value, "/v1/users");
+        assert_eq!(
+            host.request_headers().get(":path"),
+            Some("/users?page=/v1/2")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn it_waits_for_the_body_to_match() -> Result<(), PipelineError> {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: llm
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: limitador
+        scope: llm
+        requestBodyMatch: { bytesLimit: 1024, patterns: ['"model":"gpt-4"'] }
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        // How many calls were made, and whether the pipeline still waits on something
+        let body = |chunks: &[&str]| -> Result<(usize, bool), PipelineError> {
+            let host = request("llm.example.com", "/v1/chat/completions", "POST");
+            let mut pipeline = factory
+                .build(Box::new(host.clone()))
+                .expect("llm applies")
+                .eval()?;
+            assert!(host.grpc_calls().is_empty(), "the body is yet to come");
+            host.set_phase(Phase::RequestBody);
+            for (i, chunk) in chunks.iter().enumerate() {
+                host.append_request_body(chunk.as_bytes());
+                host.set_end_of_stream(i == chunks.len() - 1);
+                pipeline = pipeline.expect("the body is still pending").eval()?;
+            }
+            Ok((host.grpc_calls().len(), pipeline.is_some()))
+        };
+
+        assert_eq!(
+            body(&[r#"{"model":"gp"#, r#"t-4","messages":[]}"#])?,
+            (1, true),
+            "waiting on limitador"
+        );
+        assert_eq!(body(&[r#"{"model":"gpt-3.5"}"#])?, (0, false));
+        Ok(())
+    }
+
+    #[test]
+    fn it_sheds_requests_locally_first() -> Result<(), PipelineError> {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  shed:
+    type: localratelimit
+    descriptors:
+      - entries: [ { key: user } ]
+        tokenBucket: { maxTokens: 1, fillInterval: 60s }
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: shed
+        scope: api
+        data: [ attribute: { key: user, path: request.headers.x-user } ]
+      - service: limitador
+        scope: api
+"#,
+        )
+        .unwrap();
+        let clock = Rc::new(crate::clock::ManualClock::default());
+        let factory = PipelineFactory::try_from(config)
+            .unwrap()
+            .with_clock(clock.clone());
+        // The status of the local reply, if any, and how many calls were made
+        let send = |user: &str| -> Result<(Option<u32>, usize), PipelineError> {
+            let host = request("example.com", "/", "GET");
+            let mut headers = host.request_headers();
+            headers.add("x-user", user);
+            host.set_request_headers(headers);
+            factory.build(Box::new(host.clone())).expect("api").eval()?;
+            let reply = host.local_reply().map(|reply| reply.status_code);
+            Ok((reply, host.grpc_calls().len()))
+        };
+
+        assert_eq!(send("alice")?, (None, 1));
+        assert_eq!(send("alice")?, (Some(429), 0), "limitador isn't asked");
+        assert_eq!(send("bob")?, (None, 1), "the bucket is per user");
+        clock.advance(std::time::Duration::from_secs(60));
+        assert_eq!(send("alice")?, (None, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_token_usage() -> Result<(), PipelineError> {
+        use crate::envoy::RateLimitRequest;
+        use protobuf::Message;
+
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: llm
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: limitador
+        scope: llm
+      - service: limitador
+        scope: llm
+        predicates: ['response.code == 200']
+        hitsAddend: { responseBody: usage.total_tokens }
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        let host = request("llm.example.com", "/v1/chat/completions", "POST");
+        let mut pipeline = factory.build(Box::new(host.clone())).expect("llm").eval()?;
+        pipeline.as_mut().expect("checking").digest(1, Vec::new())?;
+
+        host.set_phase(Phase::ResponseHeaders);
+        host.set_response_headers(vec![(":status", "200")].into());
+        pipeline = pipeline.expect("reporting").eval()?;
+        host.set_phase(Phase::ResponseBody);
+        host.append_response_body(br#"{"usage":{"total_tokens":1200}}"#);
+        host.set_end_of_stream(true);
+        pipeline = pipeline.expect("reporting").eval()?;
+
+        let hits = host
+            .grpc_calls()
+            .iter()
+            .map(|call| {
+                RateLimitRequest::parse_from_bytes(&call.message)
+                    .unwrap()
+                    .hits_addend
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(hits, vec![1, 1200]);
+        assert!(!pipeline.expect("the report is pending").is_blocked());
+        Ok(())
+    }
+
+    #[test]
+    fn it_sends_the_descriptors_of_rate_limits() -> Result<(), PipelineError> {
+        use crate::envoy::RateLimitRequest;
+        use protobuf::Message;
+
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: limitador
+        scope: api
+        data: [ static: { key: tier, value: gold } ]
+        rateLimits:
+          - actions:
+              - requestHeaders: { headerName: x-user, descriptorKey: user }
+              - headerValueMatch:
+                  descriptorValue: write
+                  headers: [ { name: ":method", exactMatch: GET, invertMatch: true } ]
+          - actions: [ genericKey: { descriptorValue: api } ]
+            limit: { dynamicMetadata: { metadataKey: { key: acme, path: [ key: limit ] } } }
+          - actions:
+              - headerValueMatch:
+                  descriptorValue: untiered
+                  headers:
+                    - { name: x-tier, safeRegexMatch: { regex: "" }, treatMissingHeaderAsEmpty: true }
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        let host = MockHost::default()
+            .with_request_headers(vec![
+                (":authority", "api.example.com"),
+                (":method", "POST"),
+                ("x-user", "alice"),
+            ])
+            .with_phase(Phase::RequestHeaders);
+        factory.build(Box::new(host.clone())).expect("api").eval()?;
+
+        let request = RateLimitRequest::parse_from_bytes(&host.grpc_calls()[0].message).unwrap();
+        let descriptors = request
+            .descriptors
+            .iter()
+            .map(|descriptor| {
+                descriptor
+                    .entries
+                    .iter()
+                    .map(|entry| (entry.key.as_str(), entry.value.as_str()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            descriptors,
+            vec![
+                vec![("tier", "gold")],
+                vec![("user", "alice"), ("header_match", "write")],
+                vec![("generic_key", "api")],
+                vec![("header_match", "untiered")],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn it_only_sends_the_allowed_headers_to_auth_services() -> Result<(), PipelineError> {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  authorino:
+    type: auth
+    endpoint: authorino-cluster
+    allowedHeaders: [ { exact: authorization }, { prefix: x-, ignoreCase: true } ]
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    actions: [ { service: authorino, scope: api } ]
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        let host = headers(vec![
+            (":authority", "api.example.com"),
+            ("authorization", "Bearer t"),
+            ("X-Tenant", "acme"),
+            ("cookie", "secret"),
+        ]);
+        factory.build(Box::new(host.clone())).expect("api").eval()?;
+
+        let request = CheckRequest::parse_from_bytes(&host.grpc_calls()[0].message).unwrap();
+        let mut headers: Vec<_> = request
+            .get_attributes()
+            .get_request()
+            .get_http()
+            .headers
+            .keys()
+            .cloned()
+            .collect();
+        headers.sort();
+        assert_eq!(headers, vec!["authorization", "x-tenant"]);
+        Ok(())
+    }
+
+    #[test]
+    fn it_calls_independent_rate_limits_at_once() -> Result<(), PipelineError> {
+        let config = PluginConfig::from_yaml(
+            r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: limitador
+        scope: per-user
+        rateLimits: [ actions: [ requestHeaders: { headerName: x-user, descriptorKey: user } ] ]
+      - service: limitador
+        scope: per-tenant
+        rateLimits:
+          - actions:
+              - genericKey: { descriptorValue: api }
+              - dynamicMetadata:
+                  descriptorKey: tenant
+                  metadataKey: { key: acme, path: [ key: tenant ] }
+                  defaultValue: unknown
+"#,
+        )
+        .unwrap();
+        let factory = PipelineFactory::try_from(config).unwrap();
+        let host = MockHost::default()
+            .with_request_headers(vec![(":authority", "api.example.com"), ("x-user", "alice")])
+            .with_phase(Phase::RequestHeaders);
+        let pipeline = factory
+            .build(Box::new(host.clone()))
+            .expect("api")
+            .eval()?
+            .expect("waiting for limitador");
+
+        assert_eq!(host.grpc_calls().len(), 2);
+        assert!(!pipeline.reads("request.body"));
+        Ok(())
+    }
+
+    #[test]
+    fn it_rejects_invalid_configurations() {
+        let error = |yaml: &str| {
+            PluginConfig::from_yaml(yaml)
+                .and_then(PipelineFactory::try_from)
+                .err()
+                .expect("invalid configuration")
+                .to_string()
+        };
+        let action_set = |action: &str| {
+            format!(
+                r#"
+services:
+  authorino: {{ type: auth, endpoint: authorino-cluster }}
+actionSets:
+  - name: api
+    routeRuleConditions: {{ hostnames: ["*"] }}
+    actions: [ {action} ]
+"#
+            )
+        };
+
+        assert_eq!(
+            error(&action_set("{ service: limitador, scope: api }")),
+            "action set `api` uses unknown service `limitador`"
+        );
+        assert_eq!(
+            error(
+                r#"
+services: {}
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+actionSetMatcher:
+  matcherTree:
+    input:
+      typedConfig:
+        "@type": type.googleapis.com/envoy.type.matcher.v3.HttpRequestHeaderMatchInput
+        headerName: ":method"
+    exactMatchMap: { map: { GET: { action: { name: api } } } }
+    prefixMatchMap: { map: { G: { action: { name: api } } } }
+"#
+            ),
+            "invalid action set matcher: more than one `tree_type`"
+        );
+        assert_eq!(
+            error(&action_set(
+                "{ service: authorino, scope: api, predicates: ['request.method =='] }"
+            )),
+            "action set `api` has invalid predicate `request.method ==`: expected an \
+             expression, got end of input at position 17"
+        );
+        assert_eq!(
+            error(&action_set(
+                "{ service: authorino, scope: api, data: [ static: { key: a, value: b } ] }"
+            )),
+            "action set `api` sends data to `authorino`, which doesn't take any"
+        );
+        assert_eq!(
+            error(&action_set(
+                "{ service: authorino, scope: api, hitsAddend: { responseBody: usage.tokens } }"
+            )),
+            "action set `api` reports hits to `authorino`, which isn't a rate limit service"
+        );
+        assert_eq!(
+            error(&action_set(
+                "{ service: authorino, scope: api, rateLimits: [ actions: [ remoteAddress: {} ] ] }"
+            )),
+            "action set `api` sends rate limits to `authorino`, which isn't a rate limit service"
+        );
+        assert_eq!(
+            error(
+                r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: limitador
+        scope: api
+        rateLimits:
+          - actions:
+              - headerValueMatch:
+                  descriptorValue: tiered
+                  headers: [ { name: x-tier, safeRegexMatch: { regex: "(" } } ]
+"#
+            ),
+            "action set `api` has invalid rate limit: invalid regex `(`: error parsing regex"
+        );
+        assert_eq!(
+            error(
+                r#"
+services: {}
+actionSets:
+  - name: api
+    routeRuleConditions:
+      hostnames: ["*"]
+      headers: [ { name: x-tier, exactMatch: gold, prefixMatch: g } ]
+"#
+            ),
+            "action set `api` has invalid matcher for header `x-tier`: more than one match"
+        );
+        assert_eq!(
+            error(
+                r#"
+services: {}
+actionSets:
+  - name: api
+    routeRuleConditions:
+      hostnames: ["*"]
+      queryParams: [ { name: ui, stringMatch: { exact: new, prefix: n } } ]
+"#
+            ),
+            "action set `api` has invalid matcher for query parameter `ui`: more than one match"
+        );
+        assert_eq!(
+            error(
+                r#"
+services:
+  limitador: { type: ratelimit, endpoint: limitador-cluster }
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    actions:
+      - service: limitador
+        scope: api
+        data:
+          - attribute:
+              key: path
+              path: request.path
+              rewrite: { pattern: { regex: "/([0-9]+)" }, substitution: '/\2' }
+"#
+            ),
+            "action set `api` has invalid rewrite for `path`: invalid substitution `/\\2`: `\\2` \
+             but the regex only has 1 group(s)"
+        );
+        assert_eq!(
+            error(
+                r#"
+services: {}
+actionSets:
+  - name: api
+    routeRuleConditions: { hostnames: ["*"] }
+    pathRewrite: { pattern: { regex: "(" }, substitution: / }
+"#
+            ),
+            "action set `api` has invalid rewrite for `:path`: invalid regex `(`: error parsing \
+             regex"
+        );
+        assert_eq!(
+            error("services: { limitador: { type: ratelimit } }"),
+            "service `limitador` has no endpoint"
+        );
+        assert_eq!(
+            error("services: { limitador: { type: ratelimit, endpoint: rl, timeout: 200ms } }"),
+            "service `limitador` is invalid: invalid timeout `200ms`"
+        );
+        assert_eq!(
+            error(
+                "services: { limitador: { type: ratelimit, endpoint: rl, allowedHeaders: [ \
+                 { exact: authorization } ] } }"
+            ),
+            "service `limitador` has invalid allowed headers: only an auth service takes them"
+        );
+        assert_eq!(
+            error("services: { authorino: { type: auth, endpoint: authz, allowedHeaders: [] } }"),
+            "service `authorino` has invalid allowed headers: no pattern to match"
+        );
+        assert_eq!(
+            error(
+                r#"
+services:
+  shed:
+    type: localratelimit
+    tokenBucket: { maxTokens: 10, fillInterval: 1m }
+"#
+            ),
+            "local rate limit `shed` is invalid: invalid fill interval `1m`"
+        );
+        assert_eq!(
+            error(
+                r#"
+services:
+  shed:
+    type: localratelimit
+    descriptors: [ { entries: [ { key: user } ], tokenBucket: { maxTokens: 0, fillInterval: 1s } } ]
+"#
+            ),
+            "local rate limit `shed` is invalid: token bucket without any token"
+        );
+        assert!(
+            error("services: { x: { type: grpc, endpoint: x } }").starts_with(
+                "invalid configuration: services.x.type: unknown variant `grpc`, expected one of \
+             `auth`, `ratelimit`, `localratelimit`"
+            )
+        );
+    }
+}
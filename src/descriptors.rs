@@ -0,0 +1,685 @@
+use crate::attributes::AttributeValue;
+use crate::envoy::{
+    MetadataKey, MetadataKey_PathSegment_oneof_segment, RateLimit,
+    RateLimit_Action_oneof_action_specifier, RateLimit_Override_oneof_override_specifier,
+    RateLimitDescriptor, RateLimitDescriptor_Entry, RateLimitDescriptor_RateLimitOverride,
+    RateLimitUnit,
+};
+use crate::headers::HeaderMap;
+use crate::matchers::{HeaderMatcher, MatcherError};
+use crate::metadata::{MetadataSource, MetadataValue};
+use crate::{PendingValue, Phase, ReqRespCtx};
+use protobuf::well_known_types::{Value, Value_oneof_kind};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// Builds the descriptor of a `RateLimit` from the route configuration, as Envoy's rate limit
+/// filter does: should any action not produce its entry, there is no descriptor at all.
+#[derive(Clone, Debug)]
+pub struct DescriptorBuilder {
+    actions: Vec<Action>,
+    /// Where to find the limit overriding the one of the rate limit service, in the dynamic
+    /// metadata.
+    limit: Option<MetadataKey>,
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    SourceCluster,
+    DestinationCluster,
+    RequestHeader {
+        header_name: String,
+        descriptor_key: String,
+        skip_if_absent: bool,
+    },
+    RemoteAddress,
+    GenericKey {
+        descriptor_key: String,
+        descriptor_value: String,
+    },
+    HeaderValueMatch {
+        descriptor_value: String,
+        expect_match: bool,
+        headers: Vec<HeaderMatcher>,
+    },
+    Metadata {
+        descriptor_key: String,
+        metadata_key: MetadataKey,
+        default_value: String,
+        source: MetadataSource,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DescriptorError {
+    MissingAction,
+    MissingMetadataKey(String),
+    InvalidMatcher(MatcherError),
+    Unsupported(String),
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptorError::MissingAction => write!(f, "rate limit action without a specifier"),
+            DescriptorError::MissingMetadataKey(descriptor_key) => {
+                write!(f, "metadata action `{descriptor_key}` has no metadata_key")
+            }
+            DescriptorError::InvalidMatcher(error) => write!(f, "{error}"),
+            DescriptorError::Unsupported(what) => write!(f, "unsupported {what}"),
+        }
+    }
+}
+
+impl std::error::Error for DescriptorError {}
+
+impl TryFrom<&RateLimit> for DescriptorBuilder {
+    type Error = DescriptorError;
+
+    fn try_from(rate_limit: &RateLimit) -> Result<Self, Self::Error> {
+        let actions = rate_limit
+            .actions
+            .iter()
+            .map(|action| {
+                use RateLimit_Action_oneof_action_specifier as Specifier;
+                Ok(match &action.action_specifier {
+                    None => return Err(DescriptorError::MissingAction),
+                    Some(Specifier::source_cluster(_)) => Action::SourceCluster,
+                    Some(Specifier::destination_cluster(_)) => Action::DestinationCluster,
+                    Some(Specifier::request_headers(action)) => Action::RequestHeader {
+                        header_name: action.header_name.to_ascii_lowercase(),
+                        descriptor_key: action.descriptor_key.clone(),
+                        skip_if_absent: action.skip_if_absent,
+                    },
+                    Some(Specifier::remote_address(_)) => Action::RemoteAddress,
+                    Some(Specifier::generic_key(action)) => Action::GenericKey {
+                        descriptor_key: Some(action.descriptor_key.clone())
+                            .filter(|key| !key.is_empty())
+                            .unwrap_or_else(|| "generic_key".to_string()),
+                        descriptor_value: action.descriptor_value.clone(),
+                    },
+                    Some(Specifier::header_value_match(action)) => Action::HeaderValueMatch {
+                        descriptor_value: action.descriptor_value.clone(),
+                        expect_match: action
+                            .expect_match
+                            .as_ref()
+                            .is_none_or(|expect| expect.value),
+                        headers: action
+                            .headers
+                            .iter()
+                            .map(HeaderMatcher::try_from)
+                            .collect::<Result<_, _>>()
+                            .map_err(DescriptorError::InvalidMatcher)?,
+                    },
+                    Some(Specifier::dynamic_metadata(action)) => Action::Metadata {
+                        descriptor_key: action.descriptor_key.clone(),
+                        metadata_key: action.metadata_key.clone().into_option().ok_or_else(
+                            || DescriptorError::MissingMetadataKey(action.descriptor_key.clone()),
+                        )?,
+                        default_value: action.default_value.clone(),
+                        source: MetadataSource::Request,
+                    },
+                    Some(Specifier::metadata(action)) => Action::Metadata {
+                        descriptor_key: action.descriptor_key.clone(),
+                        metadata_key: action.metadata_key.clone().into_option().ok_or_else(
+                            || DescriptorError::MissingMetadataKey(action.descriptor_key.clone()),
+                        )?,
+                        default_value: action.default_value.clone(),
+                        source: action.source.into(),
+                    },
+                    Some(Specifier::extension(extension)) => {
+                        return Err(DescriptorError::Unsupported(format!(
+                            "rate limit action extension `{}`",
+                            extension.name
+                        )));
+                    }
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let limit = match rate_limit
+            .limit
+            .as_ref()
+            .map(|limit| &limit.override_specifier)
+        {
+            None | Some(None) => None,
+            Some(Some(RateLimit_Override_oneof_override_specifier::dynamic_metadata(limit))) => {
+                Some(
+                    limit
+                        .metadata_key
+                        .clone()
+                        .into_option()
+                        .ok_or_else(|| DescriptorError::MissingMetadataKey("limit".to_string()))?,
+                )
+            }
+        };
+        Ok(Self { actions, limit })
+    }
+}
+
+impl DescriptorBuilder {
+    /// Has the `header`th matcher of the `action`th action, a header value match, match a
+    /// missing header as if it were empty, see `HeaderMatcher::treat_missing_as_empty`.
+    pub fn treat_missing_as_empty(mut self, action: usize, header: usize) -> Self {
+        if let Some(Action::HeaderValueMatch { headers, .. }) = self.actions.get_mut(action)
+            && let Some(matcher) = headers.get_mut(header)
+        {
+            *matcher = matcher.clone().treat_missing_as_empty(true);
+        }
+        self
+    }
+
+    /// The descriptor for the request, `None` when an action couldn't produce its entry, and
+    /// `Pending` until the request headers are known.
+    pub fn build(&self, ctx: &ReqRespCtx) -> PendingValue<Option<RateLimitDescriptor>> {
+        if ctx
+            .phase()
+            .is_none_or(|phase| phase < Phase::RequestHeaders)
+        {
+            return PendingValue::Pending;
+        }
+        let headers = ctx.host.request_headers();
+        let mut entries = Vec::with_capacity(self.actions.len());
+        for action in &self.actions {
+            match action.entry(ctx, &headers) {
+                Populated::Entry(key, value) => entries.push(RateLimitDescriptor_Entry {
+                    key,
+                    value,
+                    ..Default::default()
+                }),
+                Populated::Skipped => {}
+                Populated::Missing => return PendingValue::Resolved(None),
+            }
+        }
+        let limit = self.limit.as_ref().and_then(|key| {
+            limit_override(MetadataSource::Request.resolve(ctx, key).ok()?.into_value())
+        });
+        PendingValue::Resolved(Some(RateLimitDescriptor {
+            entries: entries.into(),
+            limit: limit.into(),
+            ..Default::default()
+        }))
+    }
+
+    /// The attributes the descriptor is made of, those the pipeline may still write anyway:
+    /// request headers and dynamic metadata, the rest is known from the start.
+    pub fn reads(&self) -> Vec<String> {
+        let mut reads = Vec::new();
+        for action in &self.actions {
+            match action {
+                Action::RequestHeader { header_name, .. } => {
+                    reads.push(format!("request.headers.{header_name}"));
+                }
+                Action::HeaderValueMatch { headers, .. } => {
+                    reads.extend(headers.iter().map(|matcher| {
+                        format!("request.headers.{}", matcher.name().to_ascii_lowercase())
+                    }))
+                }
+                Action::Metadata {
+                    metadata_key,
+                    source: MetadataSource::Request,
+                    ..
+                } => reads.push(metadata_attribute(metadata_key)),
+                Action::SourceCluster
+                | Action::DestinationCluster
+                | Action::RemoteAddress
+                | Action::GenericKey { .. }
+                | Action::Metadata { .. } => {}
+            }
+        }
+        reads.extend(self.limit.as_ref().map(metadata_attribute));
+        reads
+    }
+}
+
+/// The attribute of the dynamic metadata at `key`, e.g. `metadata.acme.limit`.
+fn metadata_attribute(key: &MetadataKey) -> String {
+    let mut attribute = format!("metadata.{}", key.key);
+    for segment in &key.path {
+        if let Some(MetadataKey_PathSegment_oneof_segment::key(key)) = &segment.segment {
+            attribute.push('.');
+            attribute.push_str(key);
+        }
+    }
+    attribute
+}
+
+/// An override is a struct with a numeric `requests_per_unit` and a `unit` naming a
+/// `RateLimitUnit`, e.g. `{"requests_per_unit": 42, "unit": "MINUTE"}`, anything else is
+/// ignored, as by Envoy.
+fn limit_override(value: Value) -> Option<RateLimitDescriptor_RateLimitOverride> {
+    let Some(Value_oneof_kind::struct_value(mut limit)) = value.kind else {
+        return None;
+    };
+    let Some(Value_oneof_kind::number_value(requests_per_unit)) =
+        limit.fields.remove("requests_per_unit")?.kind
+    else {
+        return None;
+    };
+    let Some(Value_oneof_kind::string_value(unit)) = limit.fields.remove("unit")?.kind else {
+        return None;
+    };
+    let unit = match unit.as_str() {
+        "UNKNOWN" => RateLimitUnit::UNKNOWN,
+        "SECOND" => RateLimitUnit::SECOND,
+        "MINUTE" => RateLimitUnit::MINUTE,
+        "HOUR" => RateLimitUnit::HOUR,
+        "DAY" => RateLimitUnit::DAY,
+        _ => return None,
+    };
+    Some(RateLimitDescriptor_RateLimitOverride {
+        // Saturating, like the cast of a double to an unsigned integer
+        requests_per_unit: requests_per_unit as u32,
+        unit,
+        ..Default::default()
+    })
+}
+
+enum Populated {
+    Entry(String, String),
+    /// The action has nothing to add, but doesn't prevent the descriptor either.
+    Skipped,
+    Missing,
+}
+
+impl Action {
+    fn entry(&self, ctx: &ReqRespCtx, headers: &HeaderMap) -> Populated {
+        let entry = |key: &str, value: String| Populated::Entry(key.to_string(), value);
+        match self {
+            Action::SourceCluster => {
+                let cluster = ctx
+                    .host
+                    .get_property(&["node", "cluster"])
+                    .unwrap_or_default();
+                entry(
+                    "source_cluster",
+                    String::from_utf8_lossy(&cluster).into_owned(),
+                )
+            }
+            Action::DestinationCluster => match ctx.get_attribute("xds.cluster_name") {
+                PendingValue::Resolved(Some(cluster)) => {
+                    entry("destination_cluster", cluster.to_string())
+                }
+                _ => Populated::Missing,
+            },
+            Action::RequestHeader {
+                header_name,
+                descriptor_key,
+                skip_if_absent,
+            } => match headers.get(header_name) {
+                // Only the first value, should the header be repeated
+                Some(value) => entry(descriptor_key, value.to_string()),
+                None if *skip_if_absent => Populated::Skipped,
+                None => Populated::Missing,
+            },
+            Action::RemoteAddress => match ctx.get_attribute("source.address") {
+                PendingValue::Resolved(Some(AttributeValue::String(address))) => {
+                    match address
+                        .parse::<SocketAddr>()
+                        .map(|address| address.ip())
+                        .or_else(|_| address.parse::<IpAddr>())
+                    {
+                        Ok(ip) => entry("remote_address", ip.to_string()),
+                        // e.g. a pipe
+                        Err(_) => Populated::Missing,
+                    }
+                }
+                _ => Populated::Missing,
+            },
+            Action::GenericKey {
+                descriptor_key,
+                descriptor_value,
+            } => entry(descriptor_key, descriptor_value.clone()),
+            Action::HeaderValueMatch {
+                descriptor_value,
+                expect_match,
+                headers: matchers,
+            } => {
+                if *expect_match == matchers.iter().all(|m| m.matches(headers)) {
+                    entry("header_match", descriptor_value.clone())
+                } else {
+                    Populated::Missing
+                }
+            }
+            Action::Metadata {
+                descriptor_key,
+                metadata_key,
+                default_value,
+                source,
+            } => {
+                // Only string values are considered, anything else is as good as absent
+                let value = source.resolve(ctx, metadata_key);
+                match value.as_ref().map(MetadataValue::as_str) {
+                    Ok(Ok(value)) if !value.is_empty() => entry(descriptor_key, value.to_string()),
+                    _ if !default_value.is_empty() => entry(descriptor_key, default_value.clone()),
+                    _ => Populated::Missing,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{
+        Metadata, MetadataKey_PathSegment, MetadataKey_PathSegment_oneof_segment, RateLimit_Action,
+        RateLimit_Action_DynamicMetaData, RateLimit_Action_GenericKey,
+        RateLimit_Action_HeaderValueMatch, RateLimit_Action_MetaData,
+        RateLimit_Action_MetaData_Source, RateLimit_Action_RemoteAddress,
+        RateLimit_Action_RequestHeaders, RateLimit_Action_SourceCluster,
+    };
+    use crate::host::MockHost;
+    use protobuf::well_known_types::{BoolValue, Struct};
+    use protobuf::{Message, RepeatedField, SingularPtrField};
+
+    type Specifier = RateLimit_Action_oneof_action_specifier;
+
+    fn rate_limit(actions: Vec<Specifier>) -> DescriptorBuilder {
+        let rate_limit = RateLimit {
+            actions: actions
+                .into_iter()
+                .map(|specifier| RateLimit_Action {
+                    action_specifier: Some(specifier),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        DescriptorBuilder::try_from(&rate_limit).expect("valid rate limit")
+    }
+
+    fn request_headers(name: &str, key: &str, skip_if_absent: bool) -> Specifier {
+        Specifier::request_headers(RateLimit_Action_RequestHeaders {
+            header_name: name.to_string(),
+            descriptor_key: key.to_string(),
+            skip_if_absent,
+            ..Default::default()
+        })
+    }
+
+    fn entries(builder: &DescriptorBuilder, host: &MockHost) -> Option<Vec<(String, String)>> {
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        match builder.build(&ctx) {
+            PendingValue::Resolved(descriptor) => descriptor.map(|descriptor| {
+                descriptor
+                    .entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect()
+            }),
+            PendingValue::Pending => panic!("the request headers are known"),
+        }
+    }
+
+    fn pairs(entries: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn it_builds_descriptors_from_request_attributes() {
+        let builder = rate_limit(vec![
+            Specifier::generic_key(RateLimit_Action_GenericKey {
+                descriptor_value: "api".to_string(),
+                ..Default::default()
+            }),
+            request_headers("X-User", "user", false),
+            Specifier::remote_address(RateLimit_Action_RemoteAddress::default()),
+            Specifier::source_cluster(RateLimit_Action_SourceCluster::default()),
+        ]);
+        let host = MockHost::default()
+            .with_request_headers(vec![("x-user", "alice"), ("x-user", "bob")])
+            .with_property(&["source", "address"], "10.0.0.1:5000")
+            .with_property(&["node", "cluster"], "ingress");
+
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        assert_eq!(builder.build(&ctx), PendingValue::Pending);
+
+        host.set_phase(Phase::RequestHeaders);
+        assert_eq!(
+            entries(&builder, &host),
+            pairs(&[
+                ("generic_key", "api"),
+                ("user", "alice"),
+                ("remote_address", "10.0.0.1"),
+                ("source_cluster", "ingress"),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_skips_absent_headers_only_when_told_to() {
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let skipping = rate_limit(vec![
+            request_headers("x-user", "user", true),
+            request_headers(":method", "method", true),
+        ]);
+        assert_eq!(entries(&skipping, &host), pairs(&[]));
+
+        let host = host.with_request_headers(vec![(":method", "GET")]);
+        assert_eq!(entries(&skipping, &host), pairs(&[("method", "GET")]));
+
+        let strict = rate_limit(vec![
+            request_headers(":method", "method", false),
+            request_headers("x-user", "user", false),
+        ]);
+        assert_eq!(entries(&strict, &host), None);
+    }
+
+    #[test]
+    fn it_expects_header_values_to_match() {
+        let matcher = |expect_match: Option<bool>| {
+            Specifier::header_value_match(RateLimit_Action_HeaderValueMatch {
+                descriptor_value: "get".to_string(),
+                expect_match: expect_match
+                    .map(|value| BoolValue {
+                        value,
+                        ..Default::default()
+                    })
+                    .into(),
+                headers: RepeatedField::from_vec(vec![crate::envoy::HeaderMatcher {
+                    name: ":method".to_string(),
+                    header_match_specifier: Some(
+                        crate::envoy::HeaderMatcher_oneof_header_match_specifier::exact_match(
+                            "GET".to_string(),
+                        ),
+                    ),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            })
+        };
+        let get = MockHost::default()
+            .with_request_headers(vec![(":method", "GET")])
+            .with_phase(Phase::RequestHeaders);
+        let post = MockHost::default()
+            .with_request_headers(vec![(":method", "POST")])
+            .with_phase(Phase::RequestHeaders);
+
+        let expecting = rate_limit(vec![matcher(None)]);
+        assert_eq!(entries(&expecting, &get), pairs(&[("header_match", "get")]));
+        assert_eq!(entries(&expecting, &post), None);
+
+        let not_expecting = rate_limit(vec![matcher(Some(false))]);
+        assert_eq!(entries(&not_expecting, &get), None);
+        assert_eq!(
+            entries(&not_expecting, &post),
+            pairs(&[("header_match", "get")])
+        );
+    }
+
+    #[test]
+    fn it_reads_metadata_with_a_default_value() {
+        let metadata_key = |path: &[&str]| MetadataKey {
+            key: "envoy.filters.http.ext_authz".to_string(),
+            path: path
+                .iter()
+                .map(|key| MetadataKey_PathSegment {
+                    segment: Some(MetadataKey_PathSegment_oneof_segment::key(key.to_string())),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let builder = |path: &[&str], default_value: &str| {
+            rate_limit(vec![Specifier::metadata(RateLimit_Action_MetaData {
+                descriptor_key: "tenant".to_string(),
+                metadata_key: SingularPtrField::some(metadata_key(path)),
+                default_value: default_value.to_string(),
+                source: RateLimit_Action_MetaData_Source::DYNAMIC,
+                ..Default::default()
+            })])
+        };
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host));
+        let mut tenant = Value::new();
+        tenant.set_string_value("acme".to_string());
+        let mut age = Value::new();
+        age.set_number_value(7.0);
+        let mut identity = Struct::new();
+        identity.fields.insert("tenant".to_string(), tenant);
+        identity.fields.insert("age".to_string(), age);
+        let mut value = Value::new();
+        value.set_struct_value(identity);
+        let mut metadata = Struct::new();
+        metadata.fields.insert("identity".to_string(), value);
+        ctx.dynamic_metadata
+            .filter_metadata
+            .insert("envoy.filters.http.ext_authz".to_string(), metadata);
+        let tenant = |builder: DescriptorBuilder| match builder.build(&ctx) {
+            PendingValue::Resolved(descriptor) => {
+                descriptor.map(|d| d.entries.first().map(|e| e.value.clone()))
+            }
+            PendingValue::Pending => panic!("the request headers are known"),
+        };
+
+        assert_eq!(
+            tenant(builder(&["identity", "tenant"], "")),
+            Some(Some("acme".to_string()))
+        );
+        assert_eq!(tenant(builder(&["identity", "age"], "")), None);
+        assert_eq!(tenant(builder(&["identity", "missing"], "")), None);
+        assert_eq!(
+            tenant(builder(&["identity", "missing"], "unknown")),
+            Some(Some("unknown".to_string()))
+        );
+
+        let deprecated = rate_limit(vec![Specifier::dynamic_metadata(
+            RateLimit_Action_DynamicMetaData {
+                descriptor_key: "tenant".to_string(),
+                metadata_key: SingularPtrField::some(metadata_key(&["identity", "tenant"])),
+                ..Default::default()
+            },
+        )]);
+        assert_eq!(tenant(deprecated), Some(Some("acme".to_string())));
+    }
+
+    #[test]
+    fn it_overrides_the_limit_from_dynamic_metadata() {
+        use crate::envoy::{RateLimit_Override, RateLimit_Override_DynamicMetadata};
+
+        let builder = DescriptorBuilder::try_from(&RateLimit {
+            actions: vec![RateLimit_Action {
+                action_specifier: Some(Specifier::generic_key(RateLimit_Action_GenericKey {
+                    descriptor_value: "api".to_string(),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }]
+            .into(),
+            limit: SingularPtrField::some(RateLimit_Override {
+                override_specifier: Some(
+                    RateLimit_Override_oneof_override_specifier::dynamic_metadata(
+                        RateLimit_Override_DynamicMetadata {
+                            metadata_key: SingularPtrField::some(MetadataKey {
+                                key: "tenant".to_string(),
+                                path: vec![MetadataKey_PathSegment {
+                                    segment: Some(MetadataKey_PathSegment_oneof_segment::key(
+                                        "limit".to_string(),
+                                    )),
+                                    ..Default::default()
+                                }]
+                                .into(),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        },
+                    ),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .expect("valid rate limit");
+        let metadata = |requests_per_unit: f64, unit: &str| {
+            let mut requests = Value::new();
+            requests.set_number_value(requests_per_unit);
+            let mut per = Value::new();
+            per.set_string_value(unit.to_string());
+            let mut limit = Struct::new();
+            limit
+                .fields
+                .insert("requests_per_unit".to_string(), requests);
+            limit.fields.insert("unit".to_string(), per);
+            let mut value = Value::new();
+            value.set_struct_value(limit);
+            let mut tenant = Struct::new();
+            tenant.fields.insert("limit".to_string(), value);
+            let mut metadata = Metadata::new();
+            metadata
+                .filter_metadata
+                .insert("tenant".to_string(), tenant);
+            metadata
+        };
+        let limit = |ctx: &ReqRespCtx| match builder.build(ctx) {
+            PendingValue::Resolved(Some(descriptor)) => descriptor.limit.into_option(),
+            _ => panic!("the generic key always produces a descriptor"),
+        };
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(limit(&ctx), None);
+
+        ctx.dynamic_metadata = metadata(42.0, "MINUTE");
+        assert_eq!(
+            limit(&ctx),
+            Some(RateLimitDescriptor_RateLimitOverride {
+                requests_per_unit: 42,
+                unit: RateLimitUnit::MINUTE,
+                ..Default::default()
+            })
+        );
+
+        ctx.dynamic_metadata = metadata(42.0, "FORTNIGHT");
+        assert_eq!(limit(&ctx), None, "not a RateLimitUnit");
+
+        let host = MockHost::default()
+            .with_property(
+                &["metadata"],
+                metadata(7.0, "SECOND").write_to_bytes().unwrap(),
+            )
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(
+            limit(&ctx).map(|limit| (limit.requests_per_unit, limit.unit)),
+            Some((7, RateLimitUnit::SECOND))
+        );
+    }
+
+    #[test]
+    fn it_rejects_unsupported_actions() {
+        let rate_limit = RateLimit {
+            actions: RepeatedField::from_vec(vec![RateLimit_Action::default()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            DescriptorBuilder::try_from(&rate_limit).err(),
+            Some(DescriptorError::MissingAction)
+        );
+    }
+}
@@ -31,19 +31,66 @@ mod token_bucket;
 mod value;
 
 pub use {
-    address::{Address, SocketAddress},
+    address::{Address, Address_oneof_address, SocketAddress, SocketAddress_oneof_port_specifier},
     attribute_context::{
         AttributeContext, AttributeContext_HttpRequest, AttributeContext_Peer,
         AttributeContext_Request,
     },
-    base::{HeaderValue, HeaderValueOption, Metadata},
+    base::{HeaderValue, HeaderValueOption, HeaderValueOption_HeaderAppendAction, Metadata},
+    extension::TypedExtensionConfig,
     external_auth::{
         CheckRequest, CheckResponse, CheckResponse_oneof_http_response, DeniedHttpResponse,
+        OkHttpResponse,
+    },
+    grpc_service::{
+        GrpcService, GrpcService_EnvoyGrpc, GrpcService_GoogleGrpc,
+        GrpcService_oneof_target_specifier,
+    },
+    http_status::{HttpStatus, StatusCode},
+    matcher::{
+        HttpGenericBodyMatch, HttpGenericBodyMatch_GenericTextMatch,
+        HttpGenericBodyMatch_GenericTextMatch_oneof_rule, HttpHeadersMatch, MatchPredicate,
+        MatchPredicate_MatchSet, MatchPredicate_oneof_rule, Matcher, Matcher_MatcherList,
+        Matcher_MatcherList_FieldMatcher, Matcher_MatcherList_Predicate,
+        Matcher_MatcherList_Predicate_PredicateList, Matcher_MatcherList_Predicate_SinglePredicate,
+        Matcher_MatcherList_Predicate_SinglePredicate_oneof_matcher,
+        Matcher_MatcherList_Predicate_oneof_match_type, Matcher_MatcherTree,
+        Matcher_MatcherTree_MatchMap, Matcher_MatcherTree_oneof_tree_type, Matcher_OnMatch,
+        Matcher_OnMatch_oneof_on_match, Matcher_oneof_matcher_type,
+    },
+    metadata::{
+        MetadataKey, MetadataKey_PathSegment, MetadataKey_PathSegment_oneof_segment, MetadataKind,
+        MetadataKind_Cluster, MetadataKind_Host, MetadataKind_Request, MetadataKind_Route,
+        MetadataKind_oneof_kind, MetadataMatcher, MetadataMatcher_PathSegment,
+        MetadataMatcher_PathSegment_oneof_segment,
+    },
+    number::{DoubleMatcher, DoubleMatcher_oneof_match_pattern},
+    range::{DoubleRange, Int64Range},
+    ratelimit::{
+        LocalRateLimitDescriptor, RateLimitDescriptor, RateLimitDescriptor_Entry,
+        RateLimitDescriptor_RateLimitOverride,
+    },
+    ratelimit_unit::RateLimitUnit,
+    regex::{
+        RegexMatchAndSubstitute, RegexMatcher, RegexMatcher_GoogleRE2,
+        RegexMatcher_oneof_engine_type,
     },
-    http_status::StatusCode,
-    ratelimit::{RateLimitDescriptor, RateLimitDescriptor_Entry},
     rls::{RateLimitRequest, RateLimitResponse, RateLimitResponse_Code},
+    route_components::{
+        HeaderMatcher, HeaderMatcher_oneof_header_match_specifier, QueryParameterMatcher,
+        QueryParameterMatcher_oneof_query_parameter_match_specifier, RateLimit, RateLimit_Action,
+        RateLimit_Action_DestinationCluster, RateLimit_Action_DynamicMetaData,
+        RateLimit_Action_GenericKey, RateLimit_Action_HeaderValueMatch, RateLimit_Action_MetaData,
+        RateLimit_Action_MetaData_Source, RateLimit_Action_RemoteAddress,
+        RateLimit_Action_RequestHeaders, RateLimit_Action_SourceCluster,
+        RateLimit_Action_oneof_action_specifier, RateLimit_Override,
+        RateLimit_Override_DynamicMetadata, RateLimit_Override_oneof_override_specifier,
+    },
+    status::Status,
+    string::{ListStringMatcher, StringMatcher, StringMatcher_oneof_match_pattern},
+    token_bucket::TokenBucket,
+    value::{
+        ListMatcher, ListMatcher_oneof_match_pattern, ValueMatcher, ValueMatcher_NullMatch,
+        ValueMatcher_oneof_match_pattern,
+    },
 };
-
-#[cfg(test)]
-pub use {external_auth::OkHttpResponse, http_status::HttpStatus};
@@ -0,0 +1,229 @@
+use crate::configuration::{PipelineFactory, PluginConfig};
+use crate::host::{Host, ProxyWasmHost};
+use crate::{Phase, Pipeline};
+use proxy_wasm::hostcalls;
+use proxy_wasm::traits::{Context, HttpContext, RootContext};
+use proxy_wasm::types::{Action, ContextType, LogLevel};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// How often the calls of the pipelines are checked for their deadline.
+const TICK_PERIOD: Duration = Duration::from_millis(100);
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Warn);
+    proxy_wasm::set_root_context(|_| Box::new(FilterRoot::default()));
+}}
+
+/// The pipelines of the streams still being processed, by the id of their `HttpContext`.
+type Pipelines = Rc<RefCell<HashMap<u32, Pipeline>>>;
+
+/// Builds the pipeline factory from the plugin configuration, and times out the calls of the
+/// pipelines on its ticks.
+#[derive(Default)]
+struct FilterRoot {
+    factory: Option<Rc<PipelineFactory>>,
+    pipelines: Pipelines,
+}
+
+impl FilterRoot {
+    /// Hands what the call behind `token_id` got to the pipeline waiting for it. Calls made on
+    /// a tick, e.g. the reports following a timeout, are answered to the root context rather than
+    /// to the `HttpContext` of their stream.
+    fn forward(&self, token_id: u32, response: Result<Vec<u8>, u32>) {
+        let context_id = self
+            .pipelines
+            .borrow()
+            .iter()
+            .find(|(_, pipeline)| pipeline.awaits(token_id as usize))
+            .map(|(context_id, _)| *context_id);
+        if let Some(context_id) = context_id {
+            let _ = hostcalls::set_effective_context(context_id);
+            digest(&self.pipelines, context_id, token_id, response);
+        }
+    }
+}
+
+impl Context for FilterRoot {
+    fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, response_size: usize) {
+        let response = response(self, status_code, response_size);
+        self.forward(token_id, response);
+    }
+}
+
+impl RootContext for FilterRoot {
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let source = self.get_plugin_configuration().unwrap_or_default();
+        let source = String::from_utf8_lossy(&source);
+        let config = if source.trim_start().starts_with('{') {
+            PluginConfig::from_json(&source)
+        } else {
+            PluginConfig::from_yaml(&source)
+        };
+        match config.and_then(PipelineFactory::try_from) {
+            Ok(factory) => {
+                self.factory = Some(Rc::new(factory));
+                self.set_tick_period(TICK_PERIOD);
+                true
+            }
+            Err(error) => {
+                warn(&format!("invalid configuration: {error}"));
+                false
+            }
+        }
+    }
+
+    fn on_tick(&mut self) {
+        let context_ids: Vec<u32> = self.pipelines.borrow().keys().copied().collect();
+        for context_id in context_ids {
+            // Out of the map while it runs, as resuming the stream may call back into it
+            let Some(mut pipeline) = self.pipelines.borrow_mut().remove(&context_id) else {
+                continue;
+            };
+            let _ = hostcalls::set_effective_context(context_id);
+            match pipeline.tick() {
+                Ok(()) => {
+                    self.pipelines.borrow_mut().insert(context_id, pipeline);
+                }
+                Err(error) => warn(&format!("pipeline failed: {error}")),
+            }
+        }
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(Filter {
+            context_id,
+            factory: self.factory.clone(),
+            host: ProxyWasmHost::default(),
+            pipelines: self.pipelines.clone(),
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+/// Runs the pipeline of a stream, telling the host what phase the stream is in as it goes.
+struct Filter {
+    context_id: u32,
+    factory: Option<Rc<PipelineFactory>>,
+    host: ProxyWasmHost,
+    pipelines: Pipelines,
+}
+
+impl Filter {
+    /// Lets the pipeline of the stream, if any, go on in the phase the host just entered.
+    fn proceed(&mut self, body: Option<&str>) -> Action {
+        let pipeline = self.pipelines.borrow_mut().remove(&self.context_id);
+        match pipeline {
+            Some(pipeline) => self.eval(pipeline, body),
+            None => Action::Continue,
+        }
+    }
+
+    /// Pauses the stream while the pipeline is blocked, once it replied, or while it waits on
+    /// the `body` attribute, which Envoy then buffers until the end of the stream.
+    fn eval(&mut self, pipeline: Pipeline, body: Option<&str>) -> Action {
+        let pause = match pipeline.eval() {
+            Ok(Some(pipeline)) => {
+                let pause = pipeline.is_blocked()
+                    || body.is_some_and(|body| !self.host.end_of_stream() && pipeline.reads(body));
+                self.pipelines
+                    .borrow_mut()
+                    .insert(self.context_id, pipeline);
+                pause
+            }
+            Ok(None) => false,
+            Err(error) => {
+                warn(&format!("pipeline failed: {error}"));
+                false
+            }
+        };
+        if pause || self.host.replied() {
+            Action::Pause
+        } else {
+            Action::Continue
+        }
+    }
+}
+
+impl Context for Filter {
+    fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, response_size: usize) {
+        let response = response(self, status_code, response_size);
+        digest(&self.pipelines, self.context_id, token_id, response);
+    }
+
+    fn on_done(&mut self) -> bool {
+        self.pipelines.borrow_mut().remove(&self.context_id);
+        true
+    }
+}
+
+impl HttpContext for Filter {
+    fn on_http_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        self.host.enter(Phase::RequestHeaders, end_of_stream);
+        let pipeline = self
+            .factory
+            .as_ref()
+            .and_then(|factory| factory.build(Box::new(self.host.clone())));
+        match pipeline {
+            Some(pipeline) => self.eval(pipeline, None),
+            None => Action::Continue,
+        }
+    }
+
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        self.host
+            .enter_body(Phase::RequestBody, body_size, end_of_stream);
+        self.proceed(Some("request.body"))
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        self.host.enter(Phase::ResponseHeaders, end_of_stream);
+        self.proceed(None)
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        self.host
+            .enter_body(Phase::ResponseBody, body_size, end_of_stream);
+        self.proceed(Some("response.body"))
+    }
+}
+
+/// The response of a call, or the gRPC status it failed with.
+fn response(context: &dyn Context, status_code: u32, response_size: usize) -> Result<Vec<u8>, u32> {
+    match status_code {
+        0 => Ok(context
+            .get_grpc_call_response_body(0, response_size)
+            .unwrap_or_default()),
+        status_code => Err(status_code),
+    }
+}
+
+fn digest(pipelines: &Pipelines, context_id: u32, token_id: u32, response: Result<Vec<u8>, u32>) {
+    // Out of the map while it runs, as resuming the stream may call back into it
+    let Some(mut pipeline) = pipelines.borrow_mut().remove(&context_id) else {
+        return;
+    };
+    let token_id = token_id as usize;
+    let result = match response {
+        Ok(message) => pipeline.digest(token_id, message),
+        Err(status_code) => pipeline.digest_failure(
+            token_id,
+            format!("call failed with gRPC status {status_code}"),
+        ),
+    };
+    match result {
+        Ok(()) => {
+            pipelines.borrow_mut().insert(context_id, pipeline);
+        }
+        Err(error) => warn(&format!("pipeline failed: {error}")),
+    }
+}
+
+fn warn(message: &str) {
+    let _ = hostcalls::log(LogLevel::Warn, message);
+}
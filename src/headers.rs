@@ -0,0 +1,334 @@
+use crate::envoy::{HeaderValueOption, HeaderValueOption_HeaderAppendAction};
+use protobuf::Message;
+
+/// An ordered multimap of HTTP headers, with case-insensitive names.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeaderMap {
+    headers: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a value for `name`, keeping any existing one.
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces all existing values of `name` with `value`.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.add(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+impl From<Vec<(String, String)>> for HeaderMap {
+    fn from(headers: Vec<(String, String)>) -> Self {
+        Self { headers }
+    }
+}
+
+impl<'a> From<Vec<(&'a str, &'a str)>> for HeaderMap {
+    fn from(headers: Vec<(&'a str, &'a str)>) -> Self {
+        headers
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+/// How a [`HeaderMutation`] treats values already present for its header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HeaderAppendAction {
+    AppendIfExistsOrAdd,
+    AddIfAbsent,
+    OverwriteIfExistsOrAdd,
+    OverwriteIfExists,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderMutation {
+    name: String,
+    value: String,
+    action: HeaderAppendAction,
+    keep_empty_value: bool,
+}
+
+// Our bindings predate `OVERWRITE_IF_EXISTS` and `keep_empty_value`, so these end up in the
+// unknown fields when sent by a newer control plane or service.
+const APPEND_ACTION_FIELD: u32 = 3;
+const KEEP_EMPTY_VALUE_FIELD: u32 = 4;
+const OVERWRITE_IF_EXISTS: u64 = 3;
+
+impl HeaderMutation {
+    pub fn new(name: &str, value: &str, action: HeaderAppendAction) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            action,
+            keep_empty_value: false,
+        }
+    }
+
+    pub fn append(name: &str, value: &str) -> Self {
+        Self::new(name, value, HeaderAppendAction::AppendIfExistsOrAdd)
+    }
+
+    pub fn keep_empty_value(mut self, keep_empty_value: bool) -> Self {
+        self.keep_empty_value = keep_empty_value;
+        self
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if self.value.is_empty() && !self.keep_empty_value {
+            return;
+        }
+        match self.action {
+            HeaderAppendAction::AppendIfExistsOrAdd => headers.add(&self.name, &self.value),
+            HeaderAppendAction::AddIfAbsent => {
+                if !headers.contains(&self.name) {
+                    headers.add(&self.name, &self.value)
+                }
+            }
+            HeaderAppendAction::OverwriteIfExistsOrAdd => headers.set(&self.name, &self.value),
+            HeaderAppendAction::OverwriteIfExists => {
+                if headers.contains(&self.name) {
+                    headers.set(&self.name, &self.value)
+                }
+            }
+        }
+    }
+}
+
+impl From<HeaderValueOption> for HeaderMutation {
+    fn from(mut option: HeaderValueOption) -> Self {
+        let unknown_varint = |field| {
+            option
+                .get_unknown_fields()
+                .get(field)
+                .and_then(|values| values.varint.last().copied())
+        };
+        // The deprecated `append` takes precedence over `append_action` when set
+        let action = match option.append.as_ref().map(|append| append.value) {
+            Some(true) => HeaderAppendAction::AppendIfExistsOrAdd,
+            Some(false) => HeaderAppendAction::OverwriteIfExistsOrAdd,
+            None if unknown_varint(APPEND_ACTION_FIELD) == Some(OVERWRITE_IF_EXISTS) => {
+                HeaderAppendAction::OverwriteIfExists
+            }
+            None => match option.append_action {
+                HeaderValueOption_HeaderAppendAction::APPEND_IF_EXISTS_OR_ADD => {
+                    HeaderAppendAction::AppendIfExistsOrAdd
+                }
+                HeaderValueOption_HeaderAppendAction::ADD_IF_ABSENT => {
+                    HeaderAppendAction::AddIfAbsent
+                }
+                HeaderValueOption_HeaderAppendAction::OVERWRITE_IF_EXISTS_OR_ADD => {
+                    HeaderAppendAction::OverwriteIfExistsOrAdd
+                }
+            },
+        };
+        let keep_empty_value = unknown_varint(KEEP_EMPTY_VALUE_FIELD).unwrap_or(0) != 0;
+        let header = option.take_header();
+        Self::new(&header.key, &header.value, action).keep_empty_value(keep_empty_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::HeaderValue;
+    use protobuf::SingularPtrField;
+    use protobuf::well_known_types::BoolValue;
+
+    fn headers() -> HeaderMap {
+        vec![("X-Existing", "a"), ("x-existing", "b")].into()
+    }
+
+    fn mutated(mutation: HeaderMutation) -> HeaderMap {
+        let mut headers = headers();
+        mutation.apply(&mut headers);
+        headers
+    }
+
+    #[test]
+    fn it_looks_headers_up_case_insensitively() {
+        let headers = headers();
+        assert_eq!(headers.get("x-EXISTING"), Some("a"));
+        assert_eq!(
+            headers.get_all("x-existing").collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(headers.get("x-missing"), None);
+    }
+
+    #[test]
+    fn it_appends_if_exists_or_adds() {
+        let action = HeaderAppendAction::AppendIfExistsOrAdd;
+        assert_eq!(
+            mutated(HeaderMutation::new("x-existing", "c", action)),
+            vec![
+                ("X-Existing", "a"),
+                ("x-existing", "b"),
+                ("x-existing", "c")
+            ]
+            .into()
+        );
+        assert_eq!(
+            mutated(HeaderMutation::new("x-new", "c", action)),
+            vec![("X-Existing", "a"), ("x-existing", "b"), ("x-new", "c")].into()
+        );
+    }
+
+    #[test]
+    fn it_adds_if_absent() {
+        let action = HeaderAppendAction::AddIfAbsent;
+        assert_eq!(
+            mutated(HeaderMutation::new("x-existing", "c", action)),
+            headers()
+        );
+        assert_eq!(
+            mutated(HeaderMutation::new("x-new", "c", action)),
+            vec![("X-Existing", "a"), ("x-existing", "b"), ("x-new", "c")].into()
+        );
+    }
+
+    #[test]
+    fn it_overwrites_if_exists_or_adds() {
+        let action = HeaderAppendAction::OverwriteIfExistsOrAdd;
+        assert_eq!(
+            mutated(HeaderMutation::new("x-existing", "c", action)),
+            vec![("x-existing", "c")].into()
+        );
+        assert_eq!(
+            mutated(HeaderMutation::new("x-new", "c", action)),
+            vec![("X-Existing", "a"), ("x-existing", "b"), ("x-new", "c")].into()
+        );
+    }
+
+    #[test]
+    fn it_overwrites_if_exists() {
+        let action = HeaderAppendAction::OverwriteIfExists;
+        assert_eq!(
+            mutated(HeaderMutation::new("x-existing", "c", action)),
+            vec![("x-existing", "c")].into()
+        );
+        assert_eq!(
+            mutated(HeaderMutation::new("x-new", "c", action)),
+            headers()
+        );
+    }
+
+    #[test]
+    fn it_drops_empty_values_unless_kept() {
+        assert_eq!(
+            mutated(HeaderMutation::new(
+                "x-existing",
+                "",
+                HeaderAppendAction::OverwriteIfExistsOrAdd
+            )),
+            headers()
+        );
+        assert_eq!(
+            mutated(
+                HeaderMutation::new("x-existing", "", HeaderAppendAction::OverwriteIfExistsOrAdd)
+                    .keep_empty_value(true)
+            ),
+            vec![("x-existing", "")].into()
+        );
+    }
+
+    #[test]
+    fn it_converts_header_value_options() {
+        let option = |append: Option<bool>, append_action| HeaderValueOption {
+            header: SingularPtrField::some(HeaderValue {
+                key: "x-key".to_string(),
+                value: "value".to_string(),
+                ..Default::default()
+            }),
+            append: append
+                .map(|value| BoolValue {
+                    value,
+                    ..Default::default()
+                })
+                .into(),
+            append_action,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            HeaderMutation::from(option(
+                None,
+                HeaderValueOption_HeaderAppendAction::ADD_IF_ABSENT
+            )),
+            HeaderMutation::new("x-key", "value", HeaderAppendAction::AddIfAbsent)
+        );
+        assert_eq!(
+            HeaderMutation::from(option(
+                Some(false),
+                HeaderValueOption_HeaderAppendAction::ADD_IF_ABSENT
+            )),
+            HeaderMutation::new("x-key", "value", HeaderAppendAction::OverwriteIfExistsOrAdd)
+        );
+        assert_eq!(
+            HeaderMutation::from(option(
+                Some(true),
+                HeaderValueOption_HeaderAppendAction::OVERWRITE_IF_EXISTS_OR_ADD
+            )),
+            HeaderMutation::append("x-key", "value")
+        );
+    }
+
+    #[test]
+    fn it_converts_fields_unknown_to_our_bindings() {
+        let mut bytes = HeaderValueOption {
+            header: SingularPtrField::some(HeaderValue {
+                key: "x-key".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap();
+        // append_action: OVERWRITE_IF_EXISTS, keep_empty_value: true
+        bytes.extend([
+            (APPEND_ACTION_FIELD as u8) << 3,
+            3,
+            (KEEP_EMPTY_VALUE_FIELD as u8) << 3,
+            1,
+        ]);
+
+        assert_eq!(
+            HeaderMutation::from(HeaderValueOption::parse_from_bytes(&bytes).unwrap()),
+            HeaderMutation::new("x-key", "", HeaderAppendAction::OverwriteIfExists)
+                .keep_empty_value(true)
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use super::{Host, HostError};
+use crate::Phase;
+use crate::headers::HeaderMap;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrpcCall {
+    pub upstream: String,
+    pub service: String,
+    pub method: String,
+    pub initial_metadata: Vec<(String, String)>,
+    pub message: Vec<u8>,
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalReply {
+    pub status_code: u32,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct State {
+    phase: Option<Phase>,
+    request_headers: HeaderMap,
+    response_headers: HeaderMap,
+    request_body: Vec<u8>,
+    response_body: Vec<u8>,
+    end_of_stream: bool,
+    properties: HashMap<String, Vec<u8>>,
+    failing_upstreams: Vec<String>,
+    grpc_calls: Vec<GrpcCall>,
+    cancelled_calls: Vec<usize>,
+    local_reply: Option<LocalReply>,
+    resumed: usize,
+}
+
+/// An in-memory host, clones share their state so that tests can drive the lifecycle and
+/// inspect the outcome while the pipeline owns its own copy. gRPC calls are recorded and get
+/// consecutive token ids, starting at 1.
+#[derive(Clone, Default)]
+pub struct MockHost {
+    state: Rc<RefCell<State>>,
+}
+
+impl MockHost {
+    pub fn with_request_headers(self, headers: Vec<(&str, &str)>) -> Self {
+        self.state.borrow_mut().request_headers = headers.into();
+        self
+    }
+
+    pub fn with_phase(self, phase: Phase) -> Self {
+        self.set_phase(phase);
+        self
+    }
+
+    pub fn with_property(self, path: &[&str], value: impl Into<Vec<u8>>) -> Self {
+        self.state
+            .borrow_mut()
+            .properties
+            .insert(path.join("."), value.into());
+        self
+    }
+
+    /// Calls to `upstream` fail to be dispatched, as if the cluster didn't exist.
+    pub fn with_failing_upstream(self, upstream: &str) -> Self {
+        self.state
+            .borrow_mut()
+            .failing_upstreams
+            .push(upstream.to_string());
+        self
+    }
+
+    pub fn set_phase(&self, phase: Phase) {
+        self.state.borrow_mut().phase = Some(phase);
+    }
+
+    pub fn append_request_body(&self, chunk: &[u8]) {
+        self.state
+            .borrow_mut()
+            .request_body
+            .extend_from_slice(chunk);
+    }
+
+    /// Marks the body of the current body phase as complete, or not, or the headers of a
+    /// headers phase as the end of the stream.
+    pub fn set_end_of_stream(&self, end_of_stream: bool) {
+        self.state.borrow_mut().end_of_stream = end_of_stream;
+    }
+
+    pub fn append_response_body(&self, chunk: &[u8]) {
+        self.state
+            .borrow_mut()
+            .response_body
+            .extend_from_slice(chunk);
+    }
+
+    pub fn grpc_calls(&self) -> Vec<GrpcCall> {
+        self.state.borrow().grpc_calls.clone()
+    }
+
+    /// The token ids of the calls given up on.
+    pub fn cancelled_calls(&self) -> Vec<usize> {
+        self.state.borrow().cancelled_calls.clone()
+    }
+
+    pub fn local_reply(&self) -> Option<LocalReply> {
+        self.state.borrow().local_reply.clone()
+    }
+
+    /// How many times the stream was resumed.
+    pub fn resumed(&self) -> usize {
+        self.state.borrow().resumed
+    }
+}
+
+impl Host for MockHost {
+    fn phase(&self) -> Option<Phase> {
+        self.state.borrow().phase
+    }
+
+    fn request_headers(&self) -> HeaderMap {
+        self.state.borrow().request_headers.clone()
+    }
+
+    fn set_request_headers(&self, headers: HeaderMap) {
+        self.state.borrow_mut().request_headers = headers;
+    }
+
+    fn response_headers(&self) -> HeaderMap {
+        self.state.borrow().response_headers.clone()
+    }
+
+    fn set_response_headers(&self, headers: HeaderMap) {
+        self.state.borrow_mut().response_headers = headers;
+    }
+
+    fn request_body(&self) -> Option<Vec<u8>> {
+        match self.phase() {
+            Some(phase) if phase >= Phase::RequestBody => {
+                Some(self.state.borrow().request_body.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn response_body(&self) -> Option<Vec<u8>> {
+        match self.phase() {
+            Some(Phase::ResponseBody) => Some(self.state.borrow().response_body.clone()),
+            _ => None,
+        }
+    }
+
+    fn end_of_stream(&self) -> bool {
+        self.state.borrow().end_of_stream
+    }
+
+    fn get_property(&self, path: &[&str]) -> Option<Vec<u8>> {
+        self.state.borrow().properties.get(&path.join(".")).cloned()
+    }
+
+    fn dispatch_grpc_call(
+        &self,
+        upstream: &str,
+        service: &str,
+        method: &str,
+        initial_metadata: &[(String, String)],
+        message: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<usize, HostError> {
+        let mut state = self.state.borrow_mut();
+        if state.failing_upstreams.iter().any(|u| u == upstream) {
+            return Err(HostError::new(format!("unknown upstream `{upstream}`")));
+        }
+        state.grpc_calls.push(GrpcCall {
+            upstream: upstream.to_string(),
+            service: service.to_string(),
+            method: method.to_string(),
+            initial_metadata: initial_metadata.to_vec(),
+            message: message.to_vec(),
+            timeout,
+        });
+        Ok(state.grpc_calls.len())
+    }
+
+    fn cancel_grpc_call(&self, token_id: usize) {
+        self.state.borrow_mut().cancelled_calls.push(token_id);
+    }
+
+    fn send_local_reply(&self, status_code: u32, headers: &HeaderMap, body: Option<&[u8]>) {
+        self.state.borrow_mut().local_reply = Some(LocalReply {
+            status_code,
+            headers: headers.clone(),
+            body: body.map(<[u8]>::to_vec),
+        });
+    }
+
+    fn resume(&self) {
+        self.state.borrow_mut().resumed += 1;
+    }
+}
@@ -0,0 +1,78 @@
+use crate::Phase;
+use crate::headers::HeaderMap;
+use std::fmt;
+use std::time::Duration;
+
+#[cfg(test)]
+mod mock;
+mod wasm;
+
+#[cfg(test)]
+pub use mock::{LocalReply, MockHost};
+pub use wasm::ProxyWasmHost;
+
+/// What the pipeline needs from the proxy it runs in.
+pub trait Host {
+    /// The phase of the request/response lifecycle the proxy is currently in, if any.
+    fn phase(&self) -> Option<Phase>;
+
+    fn request_headers(&self) -> HeaderMap;
+    fn set_request_headers(&self, headers: HeaderMap);
+    fn response_headers(&self) -> HeaderMap;
+    fn set_response_headers(&self, headers: HeaderMap);
+
+    /// The body received so far, `None` when not available, e.g. before the body phase. The
+    /// request body remains available in the response phases, the response body doesn't.
+    fn request_body(&self) -> Option<Vec<u8>>;
+    fn response_body(&self) -> Option<Vec<u8>>;
+    /// Whether the body phase the proxy is in got the last of the body, which is then complete,
+    /// or, in a headers phase, whether there's no body at all.
+    fn end_of_stream(&self) -> bool;
+
+    /// A property as encoded by Envoy: strings as UTF-8, integers, timestamps and durations
+    /// as 8 bytes little-endian (the latter two in nanoseconds), and booleans as a single byte.
+    fn get_property(&self, path: &[&str]) -> Option<Vec<u8>>;
+
+    /// Sends `message` to `service`/`method` on the `upstream` cluster, the response is later
+    /// handed to `Pipeline::digest` along with the returned token id, or the failure to
+    /// `Pipeline::digest_failure`, e.g. once `timeout`, if any, elapsed.
+    fn dispatch_grpc_call(
+        &self,
+        upstream: &str,
+        service: &str,
+        method: &str,
+        initial_metadata: &[(String, String)],
+        message: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<usize, HostError>;
+
+    /// Gives up on a call, whose response, if any, is then never handed over.
+    fn cancel_grpc_call(&self, token_id: usize);
+
+    /// Replies to the downstream client directly, the request never reaches the upstream.
+    fn send_local_reply(&self, status_code: u32, headers: &HeaderMap, body: Option<&[u8]>);
+
+    /// Lets the request, or the response, depending on the phase, continue its way.
+    fn resume(&self);
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HostError {
+    message: String,
+}
+
+impl HostError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HostError {}
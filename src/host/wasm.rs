@@ -0,0 +1,200 @@
+use super::{Host, HostError};
+use crate::Phase;
+use crate::headers::HeaderMap;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, LogLevel, MapType, Status};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Default)]
+struct State {
+    phase: Option<Phase>,
+    request_body_size: usize,
+    response_body_size: usize,
+    /// The whole request body, once complete, which Envoy no longer exposes past its phase.
+    request_body: Vec<u8>,
+    end_of_stream: bool,
+    /// Whether a local reply was sent, after which the stream mustn't go on.
+    replied: bool,
+}
+
+/// The host, as seen from a proxy-wasm `HttpContext`. The hostcalls don't tell what phase the
+/// stream is in, nor how much of the body was buffered, so the context reports both from its
+/// callbacks through a clone of the host. The request body is kept once complete, for the
+/// response phases to still read it.
+#[derive(Clone, Default)]
+pub struct ProxyWasmHost {
+    state: Rc<RefCell<State>>,
+}
+
+impl ProxyWasmHost {
+    /// To be called from `on_http_request_headers` and `on_http_response_headers`, with
+    /// whether the headers end the stream, leaving no body.
+    pub fn enter(&self, phase: Phase, end_of_stream: bool) {
+        let mut state = self.state.borrow_mut();
+        state.phase = Some(phase);
+        state.end_of_stream = end_of_stream;
+    }
+
+    /// To be called from `on_http_request_body` and `on_http_response_body`, with the size of
+    /// the body buffered so far and whether that's all of it.
+    pub fn enter_body(&self, phase: Phase, body_size: usize, end_of_stream: bool) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.phase = Some(phase);
+            state.end_of_stream = end_of_stream;
+            match phase {
+                Phase::RequestBody => state.request_body_size = body_size,
+                Phase::ResponseBody => state.response_body_size = body_size,
+                Phase::RequestHeaders | Phase::ResponseHeaders => {}
+            }
+        }
+        if phase == Phase::RequestBody && end_of_stream {
+            let body = self.body(Phase::RequestBody, BufferType::HttpRequestBody, body_size);
+            self.state.borrow_mut().request_body = body.unwrap_or_default();
+        }
+    }
+
+    pub fn replied(&self) -> bool {
+        self.state.borrow().replied
+    }
+
+    fn body(&self, phase: Phase, buffer: BufferType, size: usize) -> Option<Vec<u8>> {
+        // Envoy only exposes the body buffer while in its own phase
+        if self.phase() != Some(phase) {
+            return None;
+        }
+        match hostcalls::get_buffer(buffer, 0, size) {
+            Ok(body) => Some(body.unwrap_or_default()),
+            Err(status) => {
+                warn(&format!("failed to read the {buffer:?} buffer: {status:?}"));
+                None
+            }
+        }
+    }
+
+    fn headers(map: MapType) -> HeaderMap {
+        match hostcalls::get_map(map) {
+            Ok(headers) => headers.into(),
+            Err(status) => {
+                warn(&format!("failed to read the {map:?}: {status:?}"));
+                HeaderMap::default()
+            }
+        }
+    }
+
+    fn set_headers(map: MapType, headers: HeaderMap) {
+        if let Err(status) = hostcalls::set_map(map, headers.iter().collect()) {
+            warn(&format!("failed to set the {map:?}: {status:?}"));
+        }
+    }
+}
+
+fn warn(message: &str) {
+    let _ = hostcalls::log(LogLevel::Warn, message);
+}
+
+impl Host for ProxyWasmHost {
+    fn phase(&self) -> Option<Phase> {
+        self.state.borrow().phase
+    }
+
+    fn request_headers(&self) -> HeaderMap {
+        Self::headers(MapType::HttpRequestHeaders)
+    }
+
+    fn set_request_headers(&self, headers: HeaderMap) {
+        Self::set_headers(MapType::HttpRequestHeaders, headers)
+    }
+
+    fn response_headers(&self) -> HeaderMap {
+        Self::headers(MapType::HttpResponseHeaders)
+    }
+
+    fn set_response_headers(&self, headers: HeaderMap) {
+        Self::set_headers(MapType::HttpResponseHeaders, headers)
+    }
+
+    fn request_body(&self) -> Option<Vec<u8>> {
+        let (phase, size) = {
+            let state = self.state.borrow();
+            (state.phase, state.request_body_size)
+        };
+        match phase {
+            Some(Phase::RequestBody) => {
+                self.body(Phase::RequestBody, BufferType::HttpRequestBody, size)
+            }
+            Some(phase) if phase > Phase::RequestBody => {
+                Some(self.state.borrow().request_body.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn response_body(&self) -> Option<Vec<u8>> {
+        let size = self.state.borrow().response_body_size;
+        self.body(Phase::ResponseBody, BufferType::HttpResponseBody, size)
+    }
+
+    fn end_of_stream(&self) -> bool {
+        self.state.borrow().end_of_stream
+    }
+
+    fn get_property(&self, path: &[&str]) -> Option<Vec<u8>> {
+        hostcalls::get_property(path.to_vec()).ok().flatten()
+    }
+
+    fn dispatch_grpc_call(
+        &self,
+        upstream: &str,
+        service: &str,
+        method: &str,
+        initial_metadata: &[(String, String)],
+        message: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<usize, HostError> {
+        hostcalls::dispatch_grpc_call(
+            upstream,
+            service,
+            method,
+            initial_metadata
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_bytes()))
+                .collect(),
+            Some(message),
+            // Envoy takes a zero timeout for none
+            timeout.unwrap_or(Duration::ZERO),
+        )
+        .map(|token_id| token_id as usize)
+        .map_err(|status| match status {
+            Status::ParseFailure => HostError::new(format!("unknown upstream `{upstream}`")),
+            status => HostError::new(format!("failed to call {service}/{method}: {status:?}")),
+        })
+    }
+
+    fn cancel_grpc_call(&self, token_id: usize) {
+        // Not found when it completed in the meantime, which is as good
+        let _ = hostcalls::cancel_grpc_call(token_id as u32);
+    }
+
+    fn send_local_reply(&self, status_code: u32, headers: &HeaderMap, body: Option<&[u8]>) {
+        if let Err(status) =
+            hostcalls::send_http_response(status_code, headers.iter().collect(), body)
+        {
+            warn(&format!("failed to send the local reply: {status:?}"));
+        } else {
+            self.state.borrow_mut().replied = true;
+        }
+    }
+
+    fn resume(&self) {
+        let result = match self.phase() {
+            Some(Phase::ResponseHeaders | Phase::ResponseBody) => hostcalls::resume_http_response(),
+            _ => hostcalls::resume_http_request(),
+        };
+        if let Err(status) = result {
+            warn(&format!("failed to resume: {status:?}"));
+        }
+    }
+}
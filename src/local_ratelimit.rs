@@ -0,0 +1,524 @@
+use crate::clock::Clock;
+use crate::envoy::{LocalRateLimitDescriptor, RateLimitDescriptor, RateLimitDescriptor_Entry};
+use crate::predicate::Predicate;
+use crate::services::DescriptorValue;
+use crate::{PendingValue, ReqRespCtx, Task, TaskOutcome, TooManyRequestsTask};
+use protobuf::RepeatedField;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+pub enum LocalRateLimitError {
+    MissingTokenBucket,
+    ZeroMaxTokens,
+    ZeroTokensPerFill,
+    ZeroFillInterval,
+    /// A descriptor without any entry.
+    EmptyDescriptor,
+}
+
+impl fmt::Display for LocalRateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalRateLimitError::MissingTokenBucket => {
+                write!(f, "descriptor without a token bucket")
+            }
+            LocalRateLimitError::ZeroMaxTokens => write!(f, "token bucket without any token"),
+            LocalRateLimitError::ZeroTokensPerFill => {
+                write!(f, "token bucket filled with no token")
+            }
+            LocalRateLimitError::ZeroFillInterval => {
+                write!(f, "token bucket without a fill interval")
+            }
+            LocalRateLimitError::EmptyDescriptor => write!(f, "descriptor without any entry"),
+        }
+    }
+}
+
+impl std::error::Error for LocalRateLimitError {}
+
+/// Envoy's default `max_dynamic_descriptors`, how many buckets a descriptor with wildcards
+/// keeps, the least recently used being dropped first.
+const MAX_DYNAMIC_BUCKETS: usize = 20;
+
+/// How a bucket is filled: holding `max_tokens`, full at first, it gets `tokens_per_fill` back
+/// at the end of every `fill_interval`, as Envoy's do, rather than continuously.
+#[derive(Clone, Copy, Debug)]
+struct BucketConfig {
+    max_tokens: u32,
+    tokens_per_fill: u32,
+    fill_interval: Duration,
+}
+
+impl TryFrom<&crate::envoy::TokenBucket> for BucketConfig {
+    type Error = LocalRateLimitError;
+
+    fn try_from(bucket: &crate::envoy::TokenBucket) -> Result<Self, Self::Error> {
+        let tokens_per_fill = bucket
+            .tokens_per_fill
+            .as_ref()
+            .map_or(1, |tokens| tokens.value);
+        let fill_interval = bucket
+            .fill_interval
+            .as_ref()
+            .map_or(Duration::ZERO, |interval| {
+                Duration::new(interval.seconds.max(0) as u64, interval.nanos.max(0) as u32)
+            });
+        if bucket.max_tokens == 0 {
+            return Err(LocalRateLimitError::ZeroMaxTokens);
+        }
+        if tokens_per_fill == 0 {
+            return Err(LocalRateLimitError::ZeroTokensPerFill);
+        }
+        if fill_interval.is_zero() {
+            return Err(LocalRateLimitError::ZeroFillInterval);
+        }
+        Ok(Self {
+            max_tokens: bucket.max_tokens,
+            tokens_per_fill,
+            fill_interval,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: u32,
+    filled_at: Duration,
+    used_at: Duration,
+}
+
+/// The buckets of a descriptor, one for each of the values its wildcard entries were given.
+#[derive(Debug)]
+struct Buckets {
+    config: BucketConfig,
+    buckets: RefCell<HashMap<Vec<String>, TokenBucket>>,
+}
+
+impl Buckets {
+    fn new(config: BucketConfig) -> Self {
+        Self {
+            config,
+            buckets: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The tokens left in the bucket of `values` at `now`, once the fill intervals over since
+    /// it was last filled added theirs.
+    fn fill(&self, values: &[String], now: Duration) -> u32 {
+        let mut buckets = self.buckets.borrow_mut();
+        if buckets.len() >= MAX_DYNAMIC_BUCKETS
+            && !buckets.contains_key(values)
+            && let Some(lru) = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.used_at)
+                .map(|(values, _)| values.clone())
+        {
+            buckets.remove(&lru);
+        }
+        let bucket = buckets
+            .entry(values.to_vec())
+            .or_insert_with(|| TokenBucket {
+                tokens: self.config.max_tokens,
+                filled_at: now,
+                used_at: now,
+            });
+        let intervals =
+            now.saturating_sub(bucket.filled_at).as_nanos() / self.config.fill_interval.as_nanos();
+        if intervals > 0 {
+            let added = intervals.saturating_mul(u128::from(self.config.tokens_per_fill));
+            bucket.tokens =
+                (u128::from(bucket.tokens) + added).min(u128::from(self.config.max_tokens)) as u32;
+            bucket.filled_at += self.config.fill_interval * intervals.min(u32::MAX.into()) as u32;
+        }
+        bucket.used_at = now;
+        bucket.tokens
+    }
+
+    fn take(&self, values: &[String]) {
+        if let Some(bucket) = self.buckets.borrow_mut().get_mut(values) {
+            bucket.tokens = bucket.tokens.saturating_sub(1);
+        }
+    }
+}
+
+/// Limits requests in process, with buckets shared by all the requests whose descriptors match
+/// theirs. A configured entry matches the request's with the same key and value. When empty,
+/// its value is a wildcard, matching any value with a bucket of its own. Requests matching none
+/// of the descriptors take from the default bucket, if any.
+#[derive(Debug)]
+pub struct LocalRateLimiter {
+    descriptors: Vec<(Vec<RateLimitDescriptor_Entry>, Buckets)>,
+    default_buckets: Option<Buckets>,
+}
+
+impl LocalRateLimiter {
+    pub fn new(
+        descriptors: &[LocalRateLimitDescriptor],
+        default_bucket: Option<&crate::envoy::TokenBucket>,
+    ) -> Result<Self, LocalRateLimitError> {
+        let descriptors = descriptors
+            .iter()
+            .map(|descriptor| {
+                if descriptor.entries.is_empty() {
+                    return Err(LocalRateLimitError::EmptyDescriptor);
+                }
+                let bucket = descriptor
+                    .token_bucket
+                    .as_ref()
+                    .ok_or(LocalRateLimitError::MissingTokenBucket)?;
+                let buckets = Buckets::new(BucketConfig::try_from(bucket)?);
+                Ok((descriptor.entries.to_vec(), buckets))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            descriptors,
+            default_buckets: default_bucket
+                .map(|bucket| BucketConfig::try_from(bucket).map(Buckets::new))
+                .transpose()?,
+        })
+    }
+
+    /// Takes a token from every bucket of the request's `descriptors`, unless one of them is
+    /// empty, in which case the request is limited and none is taken.
+    pub fn allow(&self, descriptors: &[RateLimitDescriptor], now: Duration) -> bool {
+        let mut matched: Vec<(&Buckets, Vec<String>)> = Vec::new();
+        for (configured, buckets) in &self.descriptors {
+            for descriptor in descriptors {
+                if let Some(values) = Self::wildcard_values(configured, &descriptor.entries)
+                    && !matched.iter().any(|(other, other_values)| {
+                        std::ptr::eq(*other, buckets) && *other_values == values
+                    })
+                {
+                    matched.push((buckets, values));
+                }
+            }
+        }
+        if matched.is_empty() {
+            matched.extend(
+                self.default_buckets
+                    .iter()
+                    .map(|buckets| (buckets, Vec::new())),
+            );
+        }
+        // Evaluated for all of them, so that they're all filled
+        let tokens: Vec<_> = matched
+            .iter()
+            .map(|(buckets, values)| buckets.fill(values, now))
+            .collect();
+        if tokens.contains(&0) {
+            return false;
+        }
+        for (buckets, values) in matched {
+            buckets.take(&values);
+        }
+        true
+    }
+
+    /// The values of the request's `entries` matching the wildcards of the `configured` ones,
+    /// `None` when they don't match.
+    fn wildcard_values(
+        configured: &[RateLimitDescriptor_Entry],
+        entries: &[RateLimitDescriptor_Entry],
+    ) -> Option<Vec<String>> {
+        if configured.len() != entries.len() {
+            return None;
+        }
+        let mut values = Vec::new();
+        for (configured, entry) in configured.iter().zip(entries) {
+            if configured.key != entry.key {
+                return None;
+            }
+            if configured.value.is_empty() {
+                values.push(entry.value.clone());
+            } else if configured.value != entry.value {
+                return None;
+            }
+        }
+        Some(values)
+    }
+}
+
+/// Limits the request on the spot when its predicate holds, denying it with a `429`. Its
+/// descriptor is made of the entries that have a value.
+pub struct LocalRateLimitTask {
+    pub predicate: Predicate,
+    pub limiter: Rc<LocalRateLimiter>,
+    pub clock: Rc<dyn Clock>,
+    pub descriptor_entries: Vec<(String, DescriptorValue)>,
+}
+
+impl Task for LocalRateLimitTask {
+    fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
+        match self.predicate.eval(ctx) {
+            PendingValue::Resolved(true) => {}
+            PendingValue::Resolved(false) => return TaskOutcome::Done,
+            PendingValue::Pending => return TaskOutcome::Pending(self),
+        }
+        let mut entries = RepeatedField::new();
+        for (key, value) in &self.descriptor_entries {
+            match value.resolve(ctx) {
+                PendingValue::Resolved(Some(value)) => entries.push(RateLimitDescriptor_Entry {
+                    key: key.clone(),
+                    value,
+                    ..Default::default()
+                }),
+                PendingValue::Resolved(None) => {}
+                PendingValue::Pending => return TaskOutcome::Pending(self),
+            }
+        }
+        let descriptor = RateLimitDescriptor {
+            entries,
+            ..Default::default()
+        };
+        if self.limiter.allow(&[descriptor], self.clock.now()) {
+            TaskOutcome::Done
+        } else {
+            Box::new(TooManyRequestsTask {}).apply(ctx)
+        }
+    }
+
+    fn reads(&self) -> Vec<String> {
+        let mut reads = self.predicate.attributes();
+        reads.extend(
+            self.descriptor_entries
+                .iter()
+                .filter_map(|(_, value)| value.attribute().map(str::to_string)),
+        );
+        reads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Phase;
+    use crate::clock::ManualClock;
+    use crate::host::MockHost;
+    use protobuf::well_known_types::UInt32Value;
+
+    fn bucket(max_tokens: u32, tokens_per_fill: u32, seconds: i64) -> crate::envoy::TokenBucket {
+        crate::envoy::TokenBucket {
+            max_tokens,
+            tokens_per_fill: Some(UInt32Value {
+                value: tokens_per_fill,
+                ..Default::default()
+            })
+            .into(),
+            fill_interval: Some(protobuf::well_known_types::Duration {
+                seconds,
+                ..Default::default()
+            })
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    fn entries(entries: &[(&str, &str)]) -> Vec<RateLimitDescriptor_Entry> {
+        entries
+            .iter()
+            .map(|(key, value)| RateLimitDescriptor_Entry {
+                key: key.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn descriptor(pairs: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries(pairs).into(),
+            ..Default::default()
+        }
+    }
+
+    fn local(
+        pairs: &[(&str, &str)],
+        bucket: crate::envoy::TokenBucket,
+    ) -> LocalRateLimitDescriptor {
+        LocalRateLimitDescriptor {
+            entries: entries(pairs).into(),
+            token_bucket: Some(bucket).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_refills_buckets_every_interval() {
+        let limiter = LocalRateLimiter::new(&[local(&[("user", "")], bucket(2, 1, 10))], None)
+            .expect("valid descriptors");
+        let alice = [descriptor(&[("user", "alice")])];
+        let bob = [descriptor(&[("user", "bob")])];
+        let at = Duration::from_secs;
+
+        assert!(limiter.allow(&alice, at(0)));
+        assert!(limiter.allow(&alice, at(1)));
+        assert!(!limiter.allow(&alice, at(9)));
+        assert!(limiter.allow(&bob, at(9)), "a bucket per user");
+        assert!(limiter.allow(&alice, at(10)), "one token back");
+        assert!(!limiter.allow(&alice, at(19)));
+        assert!(limiter.allow(&alice, at(100)));
+        assert!(limiter.allow(&alice, at(100)));
+        assert!(!limiter.allow(&alice, at(100)), "filled up to its max only");
+    }
+
+    #[test]
+    fn it_drops_the_least_recently_used_buckets() {
+        let limiter = LocalRateLimiter::new(&[local(&[("user", "")], bucket(1, 1, 60))], None)
+            .expect("valid descriptors");
+        let user = |name: &str| [descriptor(&[("user", name)])];
+        let at = Duration::from_secs;
+
+        assert!(limiter.allow(&user("alice"), at(0)));
+        assert!(!limiter.allow(&user("alice"), at(1)));
+        for i in 1..MAX_DYNAMIC_BUCKETS {
+            assert!(limiter.allow(&user(&i.to_string()), at(2)));
+        }
+        assert!(limiter.allow(&user("bob"), at(3)));
+        assert!(
+            limiter.allow(&user("alice"), at(4)),
+            "dropped for bob, then back full"
+        );
+    }
+
+    #[test]
+    fn it_takes_from_all_matching_buckets() {
+        let limiter = LocalRateLimiter::new(
+            &[
+                local(&[("path", "/login")], bucket(1, 1, 60)),
+                local(&[("user", "alice")], bucket(5, 5, 60)),
+            ],
+            Some(&bucket(1, 1, 1)),
+        )
+        .expect("valid descriptors");
+        let now = Duration::from_secs(1);
+        let both = [
+            descriptor(&[("path", "/login")]),
+            descriptor(&[("user", "alice")]),
+        ];
+        assert!(limiter.allow(&both, now));
+        assert!(!limiter.allow(&both, now));
+        let alice = [descriptor(&[("user", "alice")])];
+        for _ in 0..4 {
+            assert!(limiter.allow(&alice, now), "none taken when limited");
+        }
+        assert!(!limiter.allow(&alice, now));
+
+        let other = [descriptor(&[("path", "/login"), ("user", "alice")])];
+        assert!(limiter.allow(&other, now), "from the default bucket");
+        assert!(!limiter.allow(&[], now));
+
+        let unlimited = LocalRateLimiter::new(&[], None).unwrap();
+        assert!(unlimited.allow(&other, now));
+    }
+
+    #[test]
+    fn it_rejects_invalid_buckets() {
+        let invalid =
+            |descriptor: LocalRateLimitDescriptor| LocalRateLimiter::new(&[descriptor], None).err();
+        assert_eq!(
+            invalid(local(&[("a", "b")], bucket(0, 1, 1))),
+            Some(LocalRateLimitError::ZeroMaxTokens)
+        );
+        assert_eq!(
+            invalid(local(&[("a", "b")], bucket(1, 0, 1))),
+            Some(LocalRateLimitError::ZeroTokensPerFill)
+        );
+        assert_eq!(
+            invalid(local(&[("a", "b")], bucket(1, 1, 0))),
+            Some(LocalRateLimitError::ZeroFillInterval)
+        );
+        assert_eq!(
+            invalid(local(&[], bucket(1, 1, 1))),
+            Some(LocalRateLimitError::EmptyDescriptor)
+        );
+        assert_eq!(
+            invalid(LocalRateLimitDescriptor {
+                entries: entries(&[("a", "b")]).into(),
+                ..Default::default()
+            }),
+            Some(LocalRateLimitError::MissingTokenBucket)
+        );
+    }
+
+    #[test]
+    fn it_denies_requests_over_the_limit() {
+        let clock = Rc::new(ManualClock::default());
+        let limiter = Rc::new(
+            LocalRateLimiter::new(&[local(&[("method", "POST")], bucket(1, 1, 1))], None).unwrap(),
+        );
+        let task = || {
+            Box::new(LocalRateLimitTask {
+                predicate: Predicate::new(r#"request.url_path == "/""#).unwrap(),
+                limiter: limiter.clone(),
+                clock: clock.clone(),
+                descriptor_entries: vec![
+                    (
+                        "method".to_string(),
+                        DescriptorValue::Attribute("request.method".to_string()),
+                    ),
+                    (
+                        "missing".to_string(),
+                        DescriptorValue::Attribute("request.headers.x-missing".to_string()),
+                    ),
+                ],
+            })
+        };
+        let ctx = || {
+            let host = MockHost::default()
+                .with_request_headers(vec![(":method", "POST"), (":path", "/")])
+                .with_phase(Phase::RequestHeaders);
+            ReqRespCtx::new(Box::new(host))
+        };
+
+        let mut first = ctx();
+        assert!(matches!(task().apply(&mut first), TaskOutcome::Done));
+        assert_eq!(first.status_code, None);
+        let mut second = ctx();
+        assert!(matches!(task().apply(&mut second), TaskOutcome::Done));
+        assert_eq!(second.status_code, Some(429));
+
+        clock.advance(Duration::from_secs(1));
+        let mut third = ctx();
+        assert!(matches!(task().apply(&mut third), TaskOutcome::Done));
+        assert_eq!(third.status_code, None);
+
+        let mut early = ReqRespCtx::new(Box::new(MockHost::default()));
+        assert!(matches!(task().apply(&mut early), TaskOutcome::Pending(_)));
+    }
+
+    #[test]
+    fn it_waits_for_every_descriptor_entry() {
+        let limiter = Rc::new(
+            LocalRateLimiter::new(&[local(&[("body", "big")], bucket(1, 1, 1))], None).unwrap(),
+        );
+        let task = Box::new(LocalRateLimitTask {
+            predicate: Predicate::new("true").unwrap(),
+            limiter: limiter.clone(),
+            clock: Rc::new(ManualClock::default()),
+            descriptor_entries: vec![(
+                "body".to_string(),
+                DescriptorValue::Attribute("request.body".to_string()),
+            )],
+        });
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+
+        let TaskOutcome::Pending(task) = task.apply(&mut ctx) else {
+            panic!("the body isn't known yet");
+        };
+        assert!(
+            limiter.allow(&[descriptor(&[("body", "big")])], Duration::ZERO),
+            "no token taken while pending"
+        );
+
+        host.set_phase(Phase::RequestBody);
+        host.append_request_body(b"big");
+        host.set_end_of_stream(true);
+        assert!(matches!(task.apply(&mut ctx), TaskOutcome::Done));
+        assert_eq!(ctx.status_code, Some(429));
+    }
+}
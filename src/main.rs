@@ -1,4 +1,8 @@
-use std::cell::RefCell;
+use protobuf::well_known_types::Struct;
+use serde::Deserialize;
+use std::collections::btree_map::Entry;
+use std::fmt;
+use std::time::Duration;
 use std::{collections::BTreeMap, rc::Rc};
 
 #[allow(
@@ -12,96 +16,456 @@ use std::{collections::BTreeMap, rc::Rc};
 )]
 mod envoy;
 
+mod attributes;
+mod clock;
+mod configuration;
+mod descriptors;
+mod filter;
+mod headers;
+mod host;
+mod local_ratelimit;
+mod matchers;
+mod metadata;
+mod predicate;
+mod query;
 mod services;
 
+use attributes::AttributeValue;
+use clock::{Clock, SystemClock};
+use headers::{HeaderMap, HeaderMutation};
+use host::{Host, HostError};
+use matchers::RegexRewrite;
+use predicate::Predicate;
+use query::QueryParams;
+use services::{HitsAddend, RateLimitService};
+
 trait Service {
     type Response;
-    fn dispatch(&self, ctx: &mut ReqRespCtx) -> usize;
+    /// Sends the request to the service, `None` when there was nothing to ask, which counts as
+    /// an allowed request, and `Pending` while what to ask isn't known yet.
+    fn dispatch(&self, ctx: &mut ReqRespCtx) -> Result<PendingValue<Option<usize>>, HostError>;
     fn parse_message(&self, message: Vec<u8>) -> Self::Response;
+
+    /// The attributes the request sent to the service is made of.
+    fn reads(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The attributes the effects of the service's response may write.
+    fn writes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// How long to wait for the response, which otherwise counts as a failure.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Whether the dotted attribute paths `a` and `b` name some of the same data, one of them being
+/// within the other, e.g. `metadata` and `metadata.envoy.filters.http.ext_authz.identity`.
+fn overlaps(a: &str, b: &str) -> bool {
+    let (outer, inner) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    inner
+        .strip_prefix(outer)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// What a service decided about the request.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Allow(Effects),
+    Deny(Effects),
+    /// The service failed to decide, or its response couldn't be understood.
+    Error(String),
+}
+
+/// What becomes of the request when a service can't be reached, or fails to decide.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum FailureMode {
+    /// As if the service allowed the request.
+    Allow,
+    /// The pipeline fails, replying with its error reply.
+    #[default]
+    Deny,
+}
+
+/// The local reply sent when the pipeline fails.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ErrorReply {
+    status_code: u32,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+impl Default for ErrorReply {
+    fn default() -> Self {
+        Self {
+            status_code: 500,
+            headers: BTreeMap::new(),
+            body: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum PipelineError {
+    DuplicateTokenId(usize),
+    UnknownTokenId(usize),
+    /// A service call couldn't be dispatched.
+    Dispatch(HostError),
+    /// A service call failed, or its response couldn't be understood.
+    Service(String),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::DuplicateTokenId(token_id) => write!(f, "duplicate token_id={token_id}"),
+            PipelineError::UnknownTokenId(token_id) => write!(f, "token_id={token_id} not found"),
+            PipelineError::Dispatch(error) => write!(f, "failed to dispatch: {error}"),
+            PipelineError::Service(message) => write!(f, "service failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// What a service asked to be done to the request and response, alongside its decision.
+/// When denied, the response headers, status code and body make up the local reply.
+#[derive(Debug, Default, PartialEq)]
+struct Effects {
+    request_headers_to_add: Vec<HeaderMutation>,
+    request_headers_to_remove: Vec<String>,
+    response_headers_to_add: Vec<HeaderMutation>,
+    dynamic_metadata: Option<(String, Struct)>,
+    status_code: Option<u32>,
+    body: Option<String>,
+}
+
+impl Effects {
+    fn into_allow_tasks(self) -> Vec<Box<dyn Task>> {
+        let mut tasks: Vec<Box<dyn Task>> = Vec::new();
+        if let Some((namespace, metadata)) = self.dynamic_metadata {
+            tasks.push(Box::new(SetDynamicMetadataTask {
+                namespace,
+                metadata,
+            }));
+        }
+        if !self.request_headers_to_add.is_empty() || !self.request_headers_to_remove.is_empty() {
+            tasks.push(Box::new(ModifyRequestHeadersTask {
+                mutations: self.request_headers_to_add,
+                remove: self.request_headers_to_remove,
+            }));
+        }
+        if !self.response_headers_to_add.is_empty() {
+            tasks.push(Box::new(AddResponseHeadersTask {
+                mutations: self.response_headers_to_add,
+            }));
+        }
+        tasks
+    }
+
+    fn into_deny_tasks(self) -> Vec<Box<dyn Task>> {
+        let mut tasks: Vec<Box<dyn Task>> = Vec::new();
+        if let Some((namespace, metadata)) = self.dynamic_metadata {
+            tasks.push(Box::new(SetDynamicMetadataTask {
+                namespace,
+                metadata,
+            }));
+        }
+        if self.status_code.is_some()
+            || self.body.is_some()
+            || !self.response_headers_to_add.is_empty()
+        {
+            tasks.push(Box::new(LocalReplyTask {
+                status_code: self.status_code,
+                headers: self.response_headers_to_add,
+                body: self.body,
+            }));
+        }
+        tasks
+    }
 }
 
+#[cfg(test)]
 struct FakeService {}
 
+#[cfg(test)]
 impl Service for FakeService {
-    type Response = bool;
+    type Response = Outcome;
 
-    fn dispatch(&self, ctx: &mut ReqRespCtx) -> usize {
-        ctx.next_token_id()
+    fn dispatch(&self, ctx: &mut ReqRespCtx) -> Result<PendingValue<Option<usize>>, HostError> {
+        ctx.host
+            .dispatch_grpc_call("fake", "fake", "fake", &[], &[], None)
+            .map(|token_id| PendingValue::Resolved(Some(token_id)))
     }
-    fn parse_message(&self, mut message: Vec<u8>) -> bool {
-        Some(1u8) == message.pop()
+    fn parse_message(&self, mut message: Vec<u8>) -> Outcome {
+        if Some(1u8) == message.pop() {
+            Outcome::Deny(Effects::default())
+        } else {
+            Outcome::Allow(Effects::default())
+        }
     }
 }
 
 struct Pipeline {
     ctx: ReqRespCtx,
-    todos: Vec<Box<dyn Task>>,
-    pending_tasks: BTreeMap<usize, PendingTask>,
+    /// With the position of the task they come from, as tasks only depend on earlier ones.
+    todos: Vec<(usize, Box<dyn Task>)>,
+    pending_tasks: BTreeMap<usize, (usize, PendingTask)>,
+    /// When the calls of the pending tasks time out, for those whose service has a timeout.
+    deadlines: BTreeMap<usize, Duration>,
+    error_reply: ErrorReply,
+    clock: Rc<dyn Clock>,
 }
 
 impl Pipeline {
-    fn eval(mut self) -> Option<Self> {
-        self.todos = self
-            .todos
-            .drain(..)
-            .into_iter()
-            .filter_map(|todo| match todo.apply(&mut self.ctx) {
-                TaskOutcome::Done => None,
-                TaskOutcome::Deferred((token_id, t)) => {
-                    if self.pending_tasks.insert(token_id, t).is_some() {
-                        panic!("Duplicate token_id={}", token_id);
-                    }
-                    None
-                }
-                TaskOutcome::Pending(action) => Some(action),
-            })
-            .collect();
+    fn new(ctx: ReqRespCtx, todos: Vec<Box<dyn Task>>) -> Self {
+        Self {
+            ctx,
+            todos: todos.into_iter().enumerate().collect(),
+            pending_tasks: BTreeMap::new(),
+            deadlines: BTreeMap::new(),
+            error_reply: ErrorReply::default(),
+            clock: Rc::new(SystemClock),
+        }
+    }
+
+    fn with_error_reply(mut self, error_reply: ErrorReply) -> Self {
+        self.error_reply = error_reply;
+        self
+    }
+
+    fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Applies whatever can be in the current phase, `None` once there is nothing left to do.
+    /// On error, the error reply was sent and the request is over.
+    fn eval(mut self) -> Result<Option<Self>, PipelineError> {
+        if let Err(error) = self.run_todos() {
+            return Err(self.fail(error, false));
+        }
+        // Denied on the spot, e.g. by a local rate limit, there's no point in going on
+        if self.ctx.status_code.is_some() {
+            self.reply_or_resume(false);
+            return Ok(None);
+        }
 
         if self.pending_tasks.is_empty() && self.todos.is_empty() {
-            None
+            Ok(None)
         } else {
-            Some(self)
+            Ok(Some(self))
+        }
+    }
+
+    /// Hands a service response to the task waiting for it, then either sends the local reply
+    /// the tasks built, or lets the stream continue if nothing else blocks it.
+    fn digest(&mut self, token_id: usize, response: Vec<u8>) -> Result<(), PipelineError> {
+        self.complete(token_id, |pending| pending.service.parse_message(response))
+    }
+
+    /// For when the call behind `token_id` failed, as per the failure mode of its service.
+    fn digest_failure(&mut self, token_id: usize, message: String) -> Result<(), PipelineError> {
+        self.complete(token_id, |_| Outcome::Error(message))
+    }
+
+    /// For when the call behind `token_id` took too long: it's cancelled, and then handled as a
+    /// failure.
+    fn on_timeout(&mut self, token_id: usize) -> Result<(), PipelineError> {
+        self.ctx.host.cancel_grpc_call(token_id);
+        self.digest_failure(token_id, "timed out".to_string())
+    }
+
+    /// Times out the calls past their deadline, to be called periodically, e.g. from `on_tick`.
+    fn tick(&mut self) -> Result<(), PipelineError> {
+        let now = self.clock.now();
+        let expired: Vec<usize> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(token_id, _)| *token_id)
+            .collect();
+        for token_id in expired {
+            // Unless an earlier one ended the request
+            if self.pending_tasks.contains_key(&token_id) {
+                self.on_timeout(token_id)?;
+            }
         }
+        Ok(())
     }
 
-    fn digest(&mut self, token_id: usize, response: Vec<u8>) {
-        if let Some(pending) = self.pending_tasks.remove(&token_id) {
-            // Process the response
-            if let Some(action) = pending.process_response(response) {
-                match action.apply(&mut self.ctx) {
-                    TaskOutcome::Done => {}
-                    TaskOutcome::Deferred((token_id, pending_task)) => {
-                        if self.pending_tasks.insert(token_id, pending_task).is_some() {
-                            panic!("Duplicate token_id={}", token_id);
+    fn complete(
+        &mut self,
+        token_id: usize,
+        outcome: impl FnOnce(&PendingTask) -> Outcome,
+    ) -> Result<(), PipelineError> {
+        let was_blocked = self.is_blocked();
+        self.deadlines.remove(&token_id);
+        let result = match self.pending_tasks.remove(&token_id) {
+            Some((position, pending)) => {
+                let outcome = outcome(&pending);
+                pending
+                    .process(outcome)
+                    .and_then(|tasks| {
+                        tasks
+                            .into_iter()
+                            .try_for_each(|task| self.apply(position, task))
+                    })
+                    .and_then(|()| match self.ctx.status_code {
+                        Some(_) => Ok(()),
+                        // Whatever waited on the call can go now
+                        None => self.run_todos(),
+                    })
+            }
+            None => Err(PipelineError::UnknownTokenId(token_id)),
+        };
+        match result {
+            Ok(()) => {
+                self.reply_or_resume(was_blocked);
+                Ok(())
+            }
+            Err(error) => Err(self.fail(error, was_blocked)),
+        }
+    }
+
+    /// Applies the todos in order, all the independent ones at once, so their calls are made
+    /// concurrently. Those reading what an earlier task may still write, once its call is
+    /// answered or once it's applied, are held back until then.
+    fn run_todos(&mut self) -> Result<(), PipelineError> {
+        // Follow-up tasks were appended, whatever their position
+        self.todos.sort_by_key(|(position, _)| *position);
+        // What the todos held back so far may write
+        let mut held: Vec<(usize, String)> = Vec::new();
+        for (position, todo) in std::mem::take(&mut self.todos) {
+            let reads = todo.reads();
+            let depends = self
+                .pending_tasks
+                .values()
+                .filter(|(earlier, _)| *earlier < position)
+                .flat_map(|(_, pending)| pending.writes())
+                .chain(
+                    held.iter()
+                        .filter(|(earlier, _)| *earlier < position)
+                        .map(|(_, write)| write.clone()),
+                )
+                .any(|write| reads.iter().any(|read| overlaps(read, &write)));
+            let writes = todo.writes().into_iter().map(|write| (position, write));
+            if depends {
+                held.extend(writes);
+                self.todos.push((position, todo));
+                continue;
+            }
+            let todos = self.todos.len();
+            self.apply(position, todo)?;
+            if self.ctx.status_code.is_some() {
+                return Ok(());
+            }
+            // Still to do, e.g. waiting on the body
+            if self.todos.len() > todos {
+                held.extend(writes);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, position: usize, task: Box<dyn Task>) -> Result<(), PipelineError> {
+        match task.apply(&mut self.ctx) {
+            TaskOutcome::Done => Ok(()),
+            TaskOutcome::Deferred((token_id, pending)) => {
+                match self.pending_tasks.entry(token_id) {
+                    Entry::Vacant(entry) => {
+                        if let Some(timeout) = pending.service.timeout() {
+                            self.deadlines.insert(token_id, self.clock.now() + timeout);
                         }
+                        entry.insert((position, pending));
+                        Ok(())
                     }
-                    TaskOutcome::Pending(action) => self.todos.push(action),
+                    Entry::Occupied(_) => Err(PipelineError::DuplicateTokenId(token_id)),
                 }
-            };
-        } else {
-            panic!("token_id={} not found", token_id);
+            }
+            TaskOutcome::Pending(task) => {
+                self.todos.push((position, task));
+                Ok(())
+            }
+            TaskOutcome::Failed(error) => Err(error),
         }
     }
 
-    fn is_blocked(&self) -> bool {
-        self.pending_tasks.values().any(PendingTask::is_blocking)
+    /// Sends the local reply the tasks built, which they only do until the response body, or
+    /// lets the stream continue if nothing else blocks it.
+    fn reply_or_resume(&mut self, was_blocked: bool) {
+        if let Some(status_code) = self.ctx.status_code {
+            self.ctx.host.send_local_reply(
+                status_code,
+                &self.ctx.local_reply_headers,
+                self.ctx.local_reply_body.as_deref().map(str::as_bytes),
+            );
+            // The request is over, whatever was left to do
+            self.todos.clear();
+            self.pending_tasks.clear();
+            self.deadlines.clear();
+        } else if was_blocked && !self.is_blocked() {
+            self.ctx.host.resume();
+        }
     }
-}
 
-#[cfg(test)]
-impl Drop for Pipeline {
-    fn drop(&mut self) {
-        if !self.todos.is_empty() || !self.pending_tasks.is_empty() {
-            panic!("Pipeline dropped with pending tasks");
+    /// Replies with the error reply, unless the response is already on its way downstream, and
+    /// drops whatever was left to do.
+    fn fail(&mut self, error: PipelineError, was_blocked: bool) -> PipelineError {
+        if self.ctx.phase() < Some(Phase::ResponseBody) {
+            self.ctx.status_code = Some(self.error_reply.status_code);
+            self.ctx.local_reply_headers = self
+                .error_reply
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect::<Vec<_>>()
+                .into();
+            self.ctx.local_reply_body = self.error_reply.body.clone();
         }
+        self.todos.clear();
+        self.pending_tasks.clear();
+        self.deadlines.clear();
+        self.reply_or_resume(was_blocked);
+        error
+    }
+
+    /// Whether a task still to do reads `attribute`, e.g. waiting on the request body.
+    fn reads(&self, attribute: &str) -> bool {
+        self.todos
+            .iter()
+            .flat_map(|(_, todo)| todo.reads())
+            .any(|read| overlaps(&read, attribute))
+    }
+
+    /// Whether a pending task waits on the call behind `token_id`.
+    fn awaits(&self, token_id: usize) -> bool {
+        self.pending_tasks.contains_key(&token_id)
+    }
+
+    /// Whether the stream has to wait on a service, as what it answers may still change the
+    /// current phase.
+    fn is_blocked(&self) -> bool {
+        let phase = self.ctx.phase();
+        self.pending_tasks
+            .values()
+            .any(|(_, pending)| pending.is_blocking(phase))
     }
 }
 
 struct RLTask {
     predicate: Predicate,
-    service: Rc<dyn Service<Response = bool>>,
+    service: Rc<dyn Service<Response = Outcome>>,
     allow_task: Option<Box<dyn Task>>,
     deny_task: Box<dyn Task>,
+    failure_mode: FailureMode,
 }
 
 impl Task for RLTask {
@@ -109,13 +473,33 @@ impl Task for RLTask {
         match self.predicate.eval(ctx) {
             PendingValue::Resolved(exec) => {
                 if exec {
-                    let token_id: usize = self.service.dispatch(ctx);
+                    let token_id = match self.service.dispatch(ctx) {
+                        Ok(PendingValue::Resolved(Some(token_id))) => token_id,
+                        // Some descriptor entry isn't known yet, e.g. read from the body
+                        Ok(PendingValue::Pending) => return TaskOutcome::Pending(self),
+                        Ok(PendingValue::Resolved(None)) => {
+                            return match self.allow_task {
+                                Some(allow_task) => allow_task.apply(ctx),
+                                None => TaskOutcome::Done,
+                            };
+                        }
+                        Err(error) => {
+                            return match (self.failure_mode, self.allow_task) {
+                                (FailureMode::Allow, Some(allow_task)) => allow_task.apply(ctx),
+                                (FailureMode::Allow, None) => TaskOutcome::Done,
+                                (FailureMode::Deny, _) => {
+                                    TaskOutcome::Failed(PipelineError::Dispatch(error))
+                                }
+                            };
+                        }
+                    };
                     TaskOutcome::Deferred((
                         token_id,
                         PendingTask {
-                            is_blocking: true,
+                            is_report: false,
                             allow_task: self.allow_task,
-                            deny_task: self.deny_task,
+                            deny_task: Some(self.deny_task),
+                            failure_mode: self.failure_mode,
                             service: self.service,
                         },
                     ))
@@ -126,29 +510,126 @@ impl Task for RLTask {
             PendingValue::Pending => TaskOutcome::Pending(self),
         }
     }
+
+    fn reads(&self) -> Vec<String> {
+        let mut reads = self.predicate.attributes();
+        reads.extend(self.service.reads());
+        reads
+    }
+
+    fn writes(&self) -> Vec<String> {
+        let mut writes = self.service.writes();
+        writes.extend(self.allow_task.iter().flat_map(|task| task.writes()));
+        writes
+    }
 }
 
+/// A task waiting on a service, and what to do with its outcome. That of a report is of no
+/// consequence: the request, or the response, went on regardless.
 struct PendingTask {
-    is_blocking: bool,
+    is_report: bool,
     allow_task: Option<Box<dyn Task>>,
-    deny_task: Box<dyn Task>,
-    service: Rc<dyn Service<Response = bool>>,
+    deny_task: Option<Box<dyn Task>>,
+    failure_mode: FailureMode,
+    service: Rc<dyn Service<Response = Outcome>>,
 }
 
 impl PendingTask {
-    fn process_response(self, response: Vec<u8>) -> Option<Box<dyn Task>> {
-        if self.service.parse_message(response) {
-            Some(self.deny_task)
-        } else if let Some(action) = self.allow_task {
-            Some(action)
-        } else {
-            None
+    fn process(self, outcome: Outcome) -> Result<Vec<Box<dyn Task>>, PipelineError> {
+        if self.is_report {
+            return Ok(Vec::new());
+        }
+        match outcome {
+            Outcome::Deny(effects) => {
+                let mut tasks: Vec<_> = self.deny_task.into_iter().collect();
+                tasks.extend(effects.into_deny_tasks());
+                Ok(tasks)
+            }
+            Outcome::Allow(effects) => {
+                let mut tasks = effects.into_allow_tasks();
+                tasks.extend(self.allow_task);
+                Ok(tasks)
+            }
+            Outcome::Error(message) => match self.failure_mode {
+                FailureMode::Allow => Ok(self.allow_task.into_iter().collect()),
+                FailureMode::Deny => Err(PipelineError::Service(message)),
+            },
+        }
+    }
+
+    /// What the outcome may write, through the service's effects or the follow-up tasks.
+    fn writes(&self) -> Vec<String> {
+        if self.is_report {
+            return Vec::new();
+        }
+        let mut writes = self.service.writes();
+        writes.extend(self.allow_task.iter().flat_map(|task| task.writes()));
+        writes
+    }
+
+    /// Whether the outcome may still change `phase`: a denial or a failure ends in a local
+    /// reply until the response is sent downstream, and otherwise it's up to the follow-up tasks.
+    fn is_blocking(&self, phase: Option<Phase>) -> bool {
+        if self.is_report {
+            return false;
+        }
+        let Some(phase) = phase else {
+            return true;
+        };
+        phase < Phase::ResponseBody
+            || self
+                .allow_task
+                .iter()
+                .chain(&self.deny_task)
+                .any(|task| task.mutates(phase))
+    }
+}
+
+/// Reports the hits of the request to the rate limit service once its predicate holds and they
+/// are known, typically after the response, e.g. the tokens an LLM used. It's fire-and-forget:
+/// nothing waits on the report, and it's never retried.
+struct ReportTask {
+    predicate: Predicate,
+    service: Rc<RateLimitService>,
+    hits_addend: HitsAddend,
+}
+
+impl Task for ReportTask {
+    fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
+        match self.predicate.eval(ctx) {
+            PendingValue::Resolved(true) => {}
+            PendingValue::Resolved(false) => return TaskOutcome::Done,
+            PendingValue::Pending => return TaskOutcome::Pending(self),
+        }
+        let hits_addend = match self.hits_addend.resolve(ctx) {
+            PendingValue::Resolved(Some(hits_addend)) => hits_addend,
+            PendingValue::Resolved(None) => return TaskOutcome::Done,
+            PendingValue::Pending => return TaskOutcome::Pending(self),
+        };
+        match self.service.send(ctx, hits_addend) {
+            Ok(PendingValue::Pending) => TaskOutcome::Pending(self),
+            Ok(PendingValue::Resolved(Some(token_id))) => TaskOutcome::Deferred((
+                token_id,
+                PendingTask {
+                    is_report: true,
+                    allow_task: None,
+                    deny_task: None,
+                    failure_mode: FailureMode::Allow,
+                    service: self.service,
+                },
+            )),
+            // A lost report is no reason to fail the request
+            Ok(PendingValue::Resolved(None)) | Err(_) => TaskOutcome::Done,
         }
     }
 
-    fn is_blocking(&self) -> bool {
-        // This would need to peak into `ok_action` AND `rl_action` to see if we need to block
-        self.is_blocking
+    fn reads(&self) -> Vec<String> {
+        let mut reads = self.predicate.attributes();
+        reads.extend(self.service.reads());
+        match &self.hits_addend {
+            HitsAddend::ResponseBody(_) => reads.push("response.body".to_string()),
+        }
+        reads
     }
 }
 
@@ -156,25 +637,177 @@ enum TaskOutcome {
     Done,
     Deferred((usize, PendingTask)),
     Pending(Box<dyn Task>),
+    Failed(PipelineError),
 }
 
 trait Task {
     fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome;
+
+    /// Whether applying the task may change the stream in `phase`, which then has to wait for
+    /// it. Assumed, unless the task knows better.
+    fn mutates(&self, _phase: Phase) -> bool {
+        true
+    }
+
+    /// The attributes the task reads, it isn't applied while they may still be written.
+    fn reads(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The attributes the task may write, directly or once its call is answered.
+    fn writes(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 #[derive(Clone)]
 struct AddResponseHeadersTask {
-    headers: Vec<(String, String)>,
+    mutations: Vec<HeaderMutation>,
 }
 
 impl Task for AddResponseHeadersTask {
     fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
-        if *ctx.test_current_phase.borrow() == Some(Phase::ResponseHeaders) {
-            ctx.response_headers = self.headers.clone();
-            TaskOutcome::Done
-        } else {
-            TaskOutcome::Pending(self)
+        match ctx.phase() {
+            Some(Phase::ResponseHeaders) => {
+                let mut headers = ctx.host.response_headers();
+                for mutation in &self.mutations {
+                    mutation.apply(&mut headers);
+                }
+                ctx.host.set_response_headers(headers);
+                TaskOutcome::Done
+            }
+            // The headers were sent downstream already
+            Some(Phase::ResponseBody) => TaskOutcome::Done,
+            _ => TaskOutcome::Pending(self),
+        }
+    }
+
+    fn mutates(&self, phase: Phase) -> bool {
+        phase == Phase::ResponseHeaders
+    }
+
+    fn writes(&self) -> Vec<String> {
+        vec!["response.headers".to_string()]
+    }
+}
+
+/// Rewrites the path of the request, its query string left as is, as Envoy's route
+/// `regex_rewrite` does once the filters are done with the original path.
+struct RewritePathTask {
+    rewrite: RegexRewrite,
+}
+
+impl Task for RewritePathTask {
+    fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
+        match ctx.phase() {
+            Some(Phase::RequestHeaders) => {}
+            // The request went upstream already, there is nothing left to rewrite
+            Some(_) => return TaskOutcome::Done,
+            None => return TaskOutcome::Pending(self),
+        }
+        let mut headers = ctx.host.request_headers();
+        if let Some(path) = headers.get(":path") {
+            let path = self.rewrite.apply_to_path(path);
+            headers.set(":path", &path);
+            ctx.host.set_request_headers(headers);
+        }
+        TaskOutcome::Done
+    }
+
+    fn mutates(&self, phase: Phase) -> bool {
+        phase == Phase::RequestHeaders
+    }
+
+    fn writes(&self) -> Vec<String> {
+        vec!["request.headers".to_string(), "request.path".to_string()]
+    }
+}
+
+struct ModifyRequestHeadersTask {
+    mutations: Vec<HeaderMutation>,
+    remove: Vec<String>,
+}
+
+impl Task for ModifyRequestHeadersTask {
+    fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
+        match ctx.phase() {
+            Some(Phase::RequestHeaders) => {}
+            // The request went upstream already, there is nothing left to modify
+            Some(_) => return TaskOutcome::Done,
+            None => return TaskOutcome::Pending(self),
         }
+        let mut headers = ctx.host.request_headers();
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for mutation in &self.mutations {
+            mutation.apply(&mut headers);
+        }
+        ctx.host.set_request_headers(headers);
+        TaskOutcome::Done
+    }
+
+    fn mutates(&self, phase: Phase) -> bool {
+        phase == Phase::RequestHeaders
+    }
+
+    fn writes(&self) -> Vec<String> {
+        vec!["request.headers".to_string()]
+    }
+}
+
+struct SetDynamicMetadataTask {
+    namespace: String,
+    metadata: Struct,
+}
+
+impl Task for SetDynamicMetadataTask {
+    fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
+        ctx.dynamic_metadata
+            .filter_metadata
+            .entry(self.namespace)
+            .or_default()
+            .fields
+            .extend(self.metadata.fields);
+        TaskOutcome::Done
+    }
+
+    fn mutates(&self, _phase: Phase) -> bool {
+        false
+    }
+
+    fn writes(&self) -> Vec<String> {
+        vec![format!("metadata.{}", self.namespace)]
+    }
+}
+
+/// Overrides whatever the local reply was set to so far with what the service asked for.
+struct LocalReplyTask {
+    status_code: Option<u32>,
+    headers: Vec<HeaderMutation>,
+    body: Option<String>,
+}
+
+impl Task for LocalReplyTask {
+    fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
+        // Too late to reply, the response is on its way downstream
+        if ctx.phase() >= Some(Phase::ResponseBody) {
+            return TaskOutcome::Done;
+        }
+        if let Some(status_code) = self.status_code {
+            ctx.status_code = Some(status_code);
+        }
+        for mutation in &self.headers {
+            mutation.apply(&mut ctx.local_reply_headers);
+        }
+        if self.body.is_some() {
+            ctx.local_reply_body = self.body;
+        }
+        TaskOutcome::Done
+    }
+
+    fn mutates(&self, phase: Phase) -> bool {
+        phase < Phase::ResponseBody
     }
 }
 
@@ -182,9 +815,15 @@ struct TooManyRequestsTask {}
 
 impl Task for TooManyRequestsTask {
     fn apply(self: Box<Self>, ctx: &mut ReqRespCtx) -> TaskOutcome {
-        ctx.status_code = Some(429);
+        if ctx.phase() < Some(Phase::ResponseBody) {
+            ctx.status_code = Some(429);
+        }
         TaskOutcome::Done
     }
+
+    fn mutates(&self, phase: Phase) -> bool {
+        phase < Phase::ResponseBody
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -193,15 +832,7 @@ enum PendingValue<T> {
     Pending,
 }
 
-struct Predicate {}
-
-impl Predicate {
-    fn eval(&self, ctx: &mut ReqRespCtx) -> PendingValue<bool> {
-        ctx.test_pop_predicate_value()
-    }
-}
-
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 enum Phase {
     RequestHeaders,
     RequestBody,
@@ -209,63 +840,98 @@ enum Phase {
     ResponseBody,
 }
 
-#[derive(Default)]
 struct ReqRespCtx {
-    test_token_id: usize,
-    test_current_phase: Rc<RefCell<Option<Phase>>>,
-    test_predicate_values: Vec<PendingValue<bool>>,
+    host: Box<dyn Host>,
     status_code: Option<u32>,
-    response_headers: Vec<(String, String)>,
+    local_reply_headers: HeaderMap,
+    local_reply_body: Option<String>,
+    dynamic_metadata: envoy::Metadata,
 }
 
 impl ReqRespCtx {
-    fn test_pop_predicate_value(&mut self) -> PendingValue<bool> {
-        self.test_predicate_values.pop().expect("Expected a value")
+    fn new(host: Box<dyn Host>) -> Self {
+        Self {
+            host,
+            status_code: None,
+            local_reply_headers: HeaderMap::default(),
+            local_reply_body: None,
+            dynamic_metadata: envoy::Metadata::default(),
+        }
     }
 
-    fn next_token_id(&mut self) -> usize {
-        self.test_token_id += 1;
-        self.test_token_id
+    fn phase(&self) -> Option<Phase> {
+        self.host.phase()
     }
 
-    fn get_attribute(&self, key: &str) -> PendingValue<Option<String>> {
-        match key {
-            "ratelimit.domain" => PendingValue::Resolved(Some("example".to_string())),
-            _ => PendingValue::Resolved(None),
-        }
+    /// The query parameters of the request's `:path`, none until its headers are known.
+    fn query_params(&self) -> QueryParams {
+        self.host
+            .request_headers()
+            .get(":path")
+            .map(QueryParams::from_path)
+            .unwrap_or_default()
+    }
+
+    /// Resolves a dot separated attribute, e.g. `request.headers.x-api-key`.
+    fn get_attribute(&self, key: &str) -> PendingValue<Option<AttributeValue>> {
+        self.get_attribute_path(&key.split('.').collect::<Vec<_>>())
+    }
+
+    fn get_attribute_path(&self, path: &[&str]) -> PendingValue<Option<AttributeValue>> {
+        attributes::resolve(self, path)
     }
 }
 
+fn main() {
+    // tests::it_rate_limits();
+    // tests::it_not_rate_limits();
+    // tests::it_token_rate_limits();
+    // tests::it_gets_attributes();
+    // println!("ok")
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::headers::HeaderAppendAction;
+    use crate::host::{LocalReply, MockHost};
 
     #[test]
-    fn it_rate_limits() {
+    fn it_rate_limits() -> Result<(), PipelineError> {
         // on_request_headers() {
-        let mut ctx = ReqRespCtx::default();
-        ctx.test_predicate_values.push(PendingValue::Resolved(true));
-        let mut pipeline = Pipeline {
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(
             ctx,
-            todos: vec![Box::new(RLTask {
-                predicate: Predicate {},
+            vec![Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
                 service: Rc::new(FakeService {}),
                 allow_task: None,
                 deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
             })],
-            pending_tasks: Default::default(),
-        };
+        );
 
         pipeline = pipeline
-            .eval()
+            .eval()?
             .expect("Pipeline should be waiting for limitador");
         assert!(pipeline.is_blocked(), "Filter should be paused");
 
         // fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, resp_size: usize) {
         let buffer: Vec<u8> = vec![1u8];
         let token_id = 1;
-        pipeline.digest(token_id, buffer);
+        pipeline.digest(token_id, buffer)?;
         assert!(!pipeline.is_blocked(), "Filter should be continued");
         assert_eq!(pipeline.ctx.status_code, Some(429));
+        assert_eq!(
+            host.local_reply(),
+            Some(LocalReply {
+                status_code: 429,
+                headers: HeaderMap::default(),
+                body: None,
+            })
+        );
+        assert_eq!(host.resumed(), 0, "The local reply ends the request");
 
         // on_request_body() {
 
@@ -273,54 +939,55 @@ mod tests {
 
         // on_response_body() {
         // pipeline.eval();
+        Ok(())
     }
 
     #[test]
-    fn it_not_rate_limits() {
+    fn it_not_rate_limits() -> Result<(), PipelineError> {
         // on_request_headers() {
-        let mut ctx = ReqRespCtx::default();
-        let rc = Rc::new(RefCell::new(Some(Phase::RequestHeaders)));
-        ctx.test_current_phase = rc.clone();
-        ctx.test_predicate_values.push(PendingValue::Resolved(true));
-        let mut pipeline = Pipeline {
-            ctx: ctx,
-            todos: vec![Box::new(RLTask {
-                predicate: Predicate {},
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
                 service: Rc::new(FakeService {}),
                 allow_task: Some(Box::new(AddResponseHeadersTask {
-                    headers: vec![("X-RateLimit-Limit".to_string(), "10".to_string())],
+                    mutations: vec![HeaderMutation::append("X-RateLimit-Limit", "10")],
                 })),
                 deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
             })],
-            pending_tasks: Default::default(),
-        };
+        );
 
         pipeline = pipeline
-            .eval()
+            .eval()?
             .expect("Pipeline should be waiting for limitador");
         assert!(pipeline.is_blocked(), "Filter should be paused");
 
         // fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, resp_size: usize) {
         let buffer: Vec<u8> = Vec::new();
         let token_id = 1;
-        pipeline.digest(token_id, buffer);
+        pipeline.digest(token_id, buffer)?;
         assert!(!pipeline.is_blocked(), "Filter should be continued");
         assert_eq!(pipeline.ctx.status_code, None);
+        assert_eq!(host.local_reply(), None);
+        assert_eq!(host.resumed(), 1);
         assert!(
-            pipeline.ctx.response_headers.is_empty(),
+            host.response_headers().iter().next().is_none(),
             "Headers should be empty"
         );
 
         // on_request_body() {
-        pipeline = pipeline.eval().expect("Not done yet");
+        pipeline = pipeline.eval()?.expect("Not done yet");
         assert!(
-            pipeline.ctx.response_headers.is_empty(),
+            host.response_headers().iter().next().is_none(),
             "Headers should be empty"
         );
 
         // on_response_headers() {
-        rc.replace(Some(Phase::ResponseHeaders));
-        assert!(pipeline.eval().is_none(), "Done now");
+        host.set_phase(Phase::ResponseHeaders);
+        assert!(pipeline.eval()?.is_none(), "Done now");
         // assert_eq!(
         //     pipeline.ctx.headers,
         //     vec![("x".to_string(), "y".to_string())]
@@ -328,87 +995,738 @@ mod tests {
 
         // on_response_body() {
         // pipeline.eval();
+        Ok(())
     }
 
     #[test]
-    fn it_token_rate_limits() {
+    fn it_token_rate_limits() -> Result<(), PipelineError> {
         // on_request_headers() {
-        let mut ctx = ReqRespCtx::default();
-        let rc = Rc::new(RefCell::new(Some(Phase::RequestHeaders)));
-        ctx.test_current_phase = rc.clone();
-        ctx.test_predicate_values
-            .insert(0, PendingValue::Resolved(true));
-        ctx.test_predicate_values.insert(0, PendingValue::Pending);
-        ctx.test_predicate_values.insert(0, PendingValue::Pending);
-        ctx.test_predicate_values.insert(0, PendingValue::Pending);
-        ctx.test_predicate_values
-            .insert(0, PendingValue::Resolved(true));
-        let mut pipeline = Pipeline {
-            ctx: ctx,
-            todos: vec![
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![
                 Box::new(RLTask {
-                    predicate: Predicate {},
+                    predicate: Predicate::new("true").unwrap(),
                     service: Rc::new(FakeService {}),
                     allow_task: None,
                     deny_task: Box::new(TooManyRequestsTask {}),
+                    failure_mode: FailureMode::Deny,
                 }),
                 Box::new(RLTask {
-                    predicate: Predicate {},
+                    predicate: Predicate::new("response.code == 200").unwrap(),
                     service: Rc::new(FakeService {}),
                     allow_task: None,
                     deny_task: Box::new(TooManyRequestsTask {}),
+                    failure_mode: FailureMode::Deny,
                 }),
             ],
-            pending_tasks: Default::default(),
-        };
+        );
 
         pipeline = pipeline
-            .eval()
+            .eval()?
             .expect("Pipeline should be waiting for limitador");
         assert!(pipeline.is_blocked(), "Filter should be paused");
 
         // fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, resp_size: usize) {
         let buffer: Vec<u8> = Vec::new();
         let token_id = 1;
-        pipeline.digest(token_id, buffer);
+        pipeline.digest(token_id, buffer)?;
         assert!(!pipeline.is_blocked(), "Filter should be continued");
         assert_eq!(pipeline.ctx.status_code, None);
 
         // on_request_body() {
-        pipeline = pipeline.eval().expect("Not done yet");
+        host.set_phase(Phase::RequestBody);
+        pipeline = pipeline.eval()?.expect("Not done yet");
+        assert!(pipeline.pending_tasks.is_empty(), "No response code yet");
 
         // on_response_headers() {
-        pipeline = pipeline.eval().expect("Not done yet");
+        host.set_phase(Phase::ResponseHeaders);
+        host.set_response_headers(vec![(":status", "200")].into());
+        pipeline = pipeline.eval()?.expect("Not done yet");
+        assert!(pipeline.todos.is_empty(), "Response code is known");
         // assert_eq!(
         //     pipeline.ctx.headers,
         //     vec![("x".to_string(), "y".to_string())]
         // );
 
         // on_response_body() {
-        pipeline = pipeline.eval().expect("Not done yet");
+        host.set_phase(Phase::ResponseBody);
+        pipeline = pipeline.eval()?.expect("Not done yet");
+        assert!(
+            !pipeline.is_blocked(),
+            "Too late for a local reply, the body can stream"
+        );
 
         // on_grpc_response
-        pipeline.digest(2, vec![1u8]);
+        pipeline.digest(2, vec![1u8])?;
+        Ok(())
+    }
+
+    #[test]
+    fn it_rate_limits_with_rate_limit_service() -> Result<(), PipelineError> {
+        use crate::envoy::{RateLimitResponse, RateLimitResponse_Code};
+        use protobuf::Message;
+
+        let ctx = ReqRespCtx::new(Box::new(MockHost::default()));
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(services::RateLimitService::new(
+                    "limitador",
+                    "example",
+                    vec![],
+                )),
+                allow_task: None,
+                deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
+            })],
+        );
+
+        pipeline = pipeline
+            .eval()?
+            .expect("Pipeline should be waiting for limitador");
+        assert!(pipeline.is_blocked(), "Filter should be paused");
+
+        let response = RateLimitResponse {
+            overall_code: RateLimitResponse_Code::OVER_LIMIT,
+            ..Default::default()
+        };
+        pipeline.digest(1, response.write_to_bytes().unwrap())?;
+        assert!(!pipeline.is_blocked(), "Filter should be continued");
+        assert_eq!(pipeline.ctx.status_code, Some(429));
+        Ok(())
+    }
+
+    #[test]
+    fn it_reports_hits_after_the_response_body() -> Result<(), PipelineError> {
+        use crate::envoy::RateLimitRequest;
+        use protobuf::Message;
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![Box::new(ReportTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(RateLimitService::new("limitador", "llm", vec![])),
+                hits_addend: HitsAddend::ResponseBody(vec![
+                    "usage".to_string(),
+                    "total_tokens".to_string(),
+                ]),
+            })],
+        );
+
+        pipeline = pipeline.eval()?.expect("Waiting for the response body");
+        host.set_phase(Phase::ResponseBody);
+        host.append_response_body(br#"{"usage":{"total_tokens":"#);
+        pipeline = pipeline.eval()?.expect("Waiting for the rest of the body");
+        assert!(host.grpc_calls().is_empty());
+
+        host.append_response_body(b"42}}");
+        host.set_end_of_stream(true);
+        pipeline = pipeline.eval()?.expect("Waiting for limitador");
+        assert!(!pipeline.is_blocked(), "Nothing waits on a report");
+        let calls = host.grpc_calls();
+        assert_eq!(calls.len(), 1);
+        let request = RateLimitRequest::parse_from_bytes(&calls[0].message).unwrap();
+        assert_eq!(request.hits_addend, 42);
+
+        pipeline.digest_failure(1, "unreachable".to_string())?;
+        assert!(pipeline.pending_tasks.is_empty());
+        assert_eq!(host.local_reply(), None, "A lost report fails nothing");
+        assert_eq!(host.resumed(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn it_blocks_only_on_what_may_change_the_phase() {
+        let pending = |is_report: bool, allow_task: Option<Box<dyn Task>>| PendingTask {
+            is_report,
+            allow_task,
+            deny_task: Some(Box::new(TooManyRequestsTask {})),
+            failure_mode: FailureMode::Deny,
+            service: Rc::new(FakeService {}),
+        };
+
+        let check = pending(false, None);
+        assert!(check.is_blocking(None));
+        assert!(check.is_blocking(Some(Phase::RequestHeaders)));
+        assert!(check.is_blocking(Some(Phase::ResponseHeaders)));
+        assert!(!check.is_blocking(Some(Phase::ResponseBody)));
+
+        let headers = pending(
+            false,
+            Some(Box::new(AddResponseHeadersTask {
+                mutations: vec![HeaderMutation::append("x-checked", "true")],
+            })),
+        );
+        assert!(!headers.is_blocking(Some(Phase::ResponseBody)));
+
+        let anything = pending(
+            false,
+            Some(Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(FakeService {}),
+                allow_task: None,
+                deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
+            })),
+        );
+        assert!(
+            anything.is_blocking(Some(Phase::ResponseBody)),
+            "Unknown tasks may change anything"
+        );
+
+        let report = pending(true, None);
+        assert!(!report.is_blocking(None));
+        assert!(!report.is_blocking(Some(Phase::RequestHeaders)));
+    }
+
+    #[test]
+    fn it_routes_service_effects_to_follow_up_tasks() -> Result<(), PipelineError> {
+        let host = MockHost::default()
+            .with_request_headers(vec![("Authorization", "secret")])
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(EffectsService {}),
+                allow_task: None,
+                deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
+            })],
+        );
+
+        pipeline = pipeline.eval()?.expect("Pipeline should be waiting");
+        pipeline.digest(1, Vec::new())?;
+        assert_eq!(host.request_headers(), vec![("x-user", "alice")].into());
+        assert!(
+            pipeline
+                .ctx
+                .dynamic_metadata
+                .filter_metadata
+                .contains_key("auth")
+        );
+        assert!(host.response_headers().iter().next().is_none());
+
+        host.set_phase(Phase::ResponseHeaders);
+        assert!(pipeline.eval()?.is_none(), "Done now");
+        Ok(())
+    }
+
+    #[test]
+    fn it_lets_the_response_body_through_once_too_late_to_deny() -> Result<(), PipelineError> {
+        let host = MockHost::default().with_phase(Phase::ResponseBody);
+        let task = |failure_mode| -> Vec<Box<dyn Task>> {
+            vec![Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(FakeService {}),
+                allow_task: None,
+                deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode,
+            })]
+        };
+
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(ctx, task(FailureMode::Deny))
+            .eval()?
+            .expect("waiting on the service");
+        pipeline.digest(1, vec![1])?;
+        assert_eq!(pipeline.ctx.status_code, None);
+        assert!(pipeline.eval()?.is_none());
+
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(ctx, task(FailureMode::Deny))
+            .eval()?
+            .expect("waiting on the service");
+        assert_eq!(
+            pipeline.digest_failure(2, "unavailable".to_string()),
+            Err(PipelineError::Service("unavailable".to_string()))
+        );
+        assert_eq!(pipeline.ctx.status_code, None);
+        assert_eq!(host.local_reply(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn it_replies_with_the_denied_effects() -> Result<(), PipelineError> {
+        let ctx = ReqRespCtx::new(Box::new(MockHost::default()));
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(EffectsService {}),
+                allow_task: None,
+                deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
+            })],
+        );
+
+        pipeline = pipeline.eval()?.expect("Pipeline should be waiting");
+        pipeline.digest(1, vec![1u8])?;
+        assert_eq!(pipeline.ctx.status_code, Some(401));
+        assert_eq!(pipeline.ctx.local_reply_body, Some("denied".to_string()));
+        assert_eq!(
+            pipeline.ctx.local_reply_headers,
+            vec![("www-authenticate", "Bearer")].into()
+        );
+        Ok(())
+    }
+
+    struct EffectsService {}
+
+    impl Service for EffectsService {
+        type Response = Outcome;
+
+        fn dispatch(&self, ctx: &mut ReqRespCtx) -> Result<PendingValue<Option<usize>>, HostError> {
+            ctx.host
+                .dispatch_grpc_call("effects", "effects", "effects", &[], &[], None)
+                .map(|token_id| PendingValue::Resolved(Some(token_id)))
+        }
+
+        fn parse_message(&self, mut message: Vec<u8>) -> Outcome {
+            if Some(1u8) == message.pop() {
+                Outcome::Deny(Effects {
+                    response_headers_to_add: vec![HeaderMutation::append(
+                        "www-authenticate",
+                        "Bearer",
+                    )],
+                    status_code: Some(401),
+                    body: Some("denied".to_string()),
+                    ..Default::default()
+                })
+            } else {
+                Outcome::Allow(Effects {
+                    request_headers_to_add: vec![HeaderMutation::append("x-user", "alice")],
+                    request_headers_to_remove: vec!["authorization".to_string()],
+                    response_headers_to_add: vec![HeaderMutation::append("x-auth", "ok")],
+                    dynamic_metadata: Some(("auth".to_string(), Struct::default())),
+                    ..Default::default()
+                })
+            }
+        }
+
+        fn writes(&self) -> Vec<String> {
+            vec!["request.headers".to_string(), "metadata.auth".to_string()]
+        }
+    }
+
+    #[test]
+    fn it_dispatches_independent_calls_at_once() -> Result<(), PipelineError> {
+        use crate::envoy::{RateLimitRequest, RateLimitResponse};
+        use protobuf::Message;
+        use services::DescriptorValue;
+
+        let host = MockHost::default()
+            .with_request_headers(vec![("Authorization", "secret")])
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let limit = |entry: (&str, DescriptorValue)| -> Box<dyn Task> {
+            Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(RateLimitService::new(
+                    "limitador",
+                    "api",
+                    vec![(entry.0.to_string(), entry.1)],
+                )),
+                allow_task: None,
+                deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
+            })
+        };
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![
+                Box::new(RLTask {
+                    predicate: Predicate::new("true").unwrap(),
+                    service: Rc::new(EffectsService {}),
+                    allow_task: None,
+                    deny_task: Box::new(TooManyRequestsTask {}),
+                    failure_mode: FailureMode::Deny,
+                }),
+                limit((
+                    "user",
+                    DescriptorValue::Attribute("request.headers.x-user".to_string()),
+                )),
+                limit(("tier", DescriptorValue::Static("gold".to_string()))),
+            ],
+        );
+
+        pipeline = pipeline.eval()?.expect("Waiting on both calls");
+        let upstreams = |host: &MockHost| {
+            host.grpc_calls()
+                .into_iter()
+                .map(|call| call.upstream)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            upstreams(&host),
+            vec!["effects", "limitador"],
+            "The user is only known once authenticated"
+        );
+
+        pipeline.digest(1, Vec::new())?;
+        assert_eq!(upstreams(&host), vec!["effects", "limitador", "limitador"]);
+        let request = RateLimitRequest::parse_from_bytes(&host.grpc_calls()[2].message).unwrap();
+        assert_eq!(request.descriptors[0].entries[0].value, "alice");
+        assert!(pipeline.is_blocked());
+
+        let allowed = RateLimitResponse::default().write_to_bytes().unwrap();
+        pipeline.digest(2, allowed.clone())?;
+        pipeline.digest(3, allowed)?;
+        assert!(!pipeline.is_blocked());
+        assert_eq!(host.resumed(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn it_adds_rate_limit_headers_to_the_local_reply() -> Result<(), PipelineError> {
+        use crate::envoy::{HeaderValue, RateLimitResponse, RateLimitResponse_Code};
+        use protobuf::Message;
+
+        let ctx = ReqRespCtx::new(Box::new(MockHost::default()));
+        let mut pipeline = Pipeline::new(
+            ctx,
+            vec![Box::new(RLTask {
+                predicate: Predicate::new("true").unwrap(),
+                service: Rc::new(services::RateLimitService::new(
+                    "limitador",
+                    "example",
+                    vec![],
+                )),
+                allow_task: None,
+                deny_task: Box::new(TooManyRequestsTask {}),
+                failure_mode: FailureMode::Deny,
+            })],
+        );
+
+        pipeline = pipeline
+            .eval()?
+            .expect("Pipeline should be waiting for limitador");
+        let response = RateLimitResponse {
+            overall_code: RateLimitResponse_Code::OVER_LIMIT,
+            response_headers_to_add: vec![HeaderValue {
+                key: "X-RateLimit-Remaining".to_string(),
+                value: "0".to_string(),
+                ..Default::default()
+            }]
+            .into(),
+            ..Default::default()
+        };
+        pipeline.digest(1, response.write_to_bytes().unwrap())?;
+        assert_eq!(pipeline.ctx.status_code, Some(429));
+        assert_eq!(
+            pipeline.ctx.local_reply_headers,
+            vec![("X-RateLimit-Remaining", "0")].into()
+        );
+        Ok(())
+    }
+
+    fn rate_limit_task(service: Rc<dyn Service<Response = Outcome>>, mode: FailureMode) -> RLTask {
+        RLTask {
+            predicate: Predicate::new("true").unwrap(),
+            service,
+            allow_task: Some(Box::new(AddResponseHeadersTask {
+                mutations: vec![HeaderMutation::append("X-RateLimit-Limit", "10")],
+            })),
+            deny_task: Box::new(TooManyRequestsTask {}),
+            failure_mode: mode,
+        }
+    }
+
+    #[test]
+    fn it_fails_open_when_the_service_cannot_be_reached() -> Result<(), PipelineError> {
+        let host = MockHost::default()
+            .with_failing_upstream("fake")
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let task = rate_limit_task(Rc::new(FakeService {}), FailureMode::Allow);
+        let pipeline = Pipeline::new(ctx, vec![Box::new(task)]);
+
+        let pipeline = pipeline.eval()?.expect("Response headers still to add");
+        assert!(!pipeline.is_blocked());
+        assert_eq!(host.local_reply(), None);
+
+        host.set_phase(Phase::ResponseHeaders);
+        assert!(pipeline.eval()?.is_none(), "Done now");
+        assert_eq!(
+            host.response_headers(),
+            vec![("X-RateLimit-Limit", "10")].into()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn it_fails_closed_when_the_service_cannot_be_reached() {
+        let host = MockHost::default()
+            .with_failing_upstream("fake")
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let task = rate_limit_task(Rc::new(FakeService {}), FailureMode::Deny);
+        let pipeline = Pipeline::new(ctx, vec![Box::new(task)]);
+
+        let Err(error) = pipeline.eval() else {
+            panic!("Pipeline should have failed");
+        };
+        assert_eq!(
+            error,
+            PipelineError::Dispatch(HostError::new("unknown upstream `fake`"))
+        );
+        assert_eq!(
+            host.local_reply(),
+            Some(LocalReply {
+                status_code: 500,
+                headers: HeaderMap::default(),
+                body: None,
+            })
+        );
     }
 
-    // #[test]
-    pub fn it_gets_attributes() {
-        let ctx = ReqRespCtx::default();
+    #[test]
+    fn it_times_out_calls_as_per_the_failure_mode() -> Result<(), PipelineError> {
+        use crate::clock::ManualClock;
+        use services::GrpcService;
+
+        let clock = Rc::new(ManualClock::default());
+        let pipeline = |host: &MockHost, mode: FailureMode| -> Result<Pipeline, PipelineError> {
+            let service = RateLimitService::new(
+                GrpcService::new("limitador")
+                    .with_timeout(Duration::from_millis(100))
+                    .with_initial_metadata(vec![("x-tenant".to_string(), "acme".to_string())]),
+                "api",
+                vec![],
+            );
+            let ctx = ReqRespCtx::new(Box::new(host.clone()));
+            let pipeline =
+                Pipeline::new(ctx, vec![Box::new(rate_limit_task(Rc::new(service), mode))])
+                    .with_clock(clock.clone());
+            Ok(pipeline.eval()?.expect("Waiting for limitador"))
+        };
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut allowing = pipeline(&host, FailureMode::Allow)?;
+        let call = &host.grpc_calls()[0];
+        assert_eq!(call.timeout, Some(Duration::from_millis(100)));
+        assert_eq!(
+            call.initial_metadata,
+            vec![("x-tenant".to_string(), "acme".to_string())]
+        );
+
+        clock.advance(Duration::from_millis(99));
+        allowing.tick()?;
+        assert!(allowing.is_blocked());
+        clock.advance(Duration::from_millis(1));
+        allowing.tick()?;
+        assert_eq!(host.cancelled_calls(), vec![1]);
+        assert!(!allowing.is_blocked());
+        assert_eq!(host.resumed(), 1);
+        assert_eq!(host.local_reply(), None);
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut denying = pipeline(&host, FailureMode::Deny)?;
+        assert_eq!(
+            denying.on_timeout(1),
+            Err(PipelineError::Service("timed out".to_string()))
+        );
+        assert_eq!(host.cancelled_calls(), vec![1]);
+        assert_eq!(host.local_reply().map(|reply| reply.status_code), Some(500));
+        Ok(())
+    }
+
+    #[test]
+    fn it_applies_the_failure_mode_to_invalid_responses() -> Result<(), PipelineError> {
+        let service = Rc::new(services::RateLimitService::new(
+            "limitador",
+            "example",
+            vec![],
+        ));
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let task = rate_limit_task(service.clone(), FailureMode::Allow);
+        let mut pipeline = Pipeline::new(ctx, vec![Box::new(task)])
+            .eval()?
+            .expect("Pipeline should be waiting for limitador");
+        pipeline.digest(1, vec![0xff, 0xff])?;
+        assert_eq!(host.local_reply(), None);
+        assert_eq!(host.resumed(), 1);
+        assert_eq!(pipeline.todos.len(), 1, "Response headers still to add");
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let task = rate_limit_task(service, FailureMode::Deny);
+        let mut pipeline = Pipeline::new(ctx, vec![Box::new(task)])
+            .eval()?
+            .expect("Pipeline should be waiting for limitador");
+        let error = pipeline.digest(1, vec![0xff, 0xff]).unwrap_err();
+        assert!(matches!(error, PipelineError::Service(_)));
+        assert_eq!(host.local_reply().map(|reply| reply.status_code), Some(500));
+        assert_eq!(host.resumed(), 0);
+        assert!(pipeline.todos.is_empty() && pipeline.pending_tasks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn it_replies_with_the_configured_error_reply() -> Result<(), PipelineError> {
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let task = rate_limit_task(Rc::new(FakeService {}), FailureMode::Deny);
+        let mut pipeline = Pipeline::new(ctx, vec![Box::new(task)])
+            .with_error_reply(ErrorReply {
+                status_code: 503,
+                headers: BTreeMap::from([("retry-after".to_string(), "5".to_string())]),
+                body: Some("try again later".to_string()),
+            })
+            .eval()?
+            .expect("Pipeline should be waiting");
+
+        assert_eq!(
+            pipeline.digest_failure(1, "deadline exceeded".to_string()),
+            Err(PipelineError::Service("deadline exceeded".to_string()))
+        );
+        assert_eq!(
+            host.local_reply(),
+            Some(LocalReply {
+                status_code: 503,
+                headers: vec![("retry-after", "5")].into(),
+                body: Some(b"try again later".to_vec()),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn it_rejects_unknown_and_duplicate_token_ids() -> Result<(), PipelineError> {
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let mut pipeline = Pipeline::new(ctx, vec![]);
+        assert_eq!(
+            pipeline.digest(42, Vec::new()),
+            Err(PipelineError::UnknownTokenId(42))
+        );
+        assert_eq!(host.local_reply().map(|reply| reply.status_code), Some(500));
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let task = || rate_limit_task(Rc::new(ReusedTokenService {}), FailureMode::Deny);
+        let pipeline = Pipeline::new(ctx, vec![Box::new(task()), Box::new(task())]);
+        assert_eq!(
+            pipeline.eval().err(),
+            Some(PipelineError::DuplicateTokenId(1))
+        );
+        assert_eq!(host.local_reply().map(|reply| reply.status_code), Some(500));
+        Ok(())
+    }
+
+    struct ReusedTokenService {}
+
+    impl Service for ReusedTokenService {
+        type Response = Outcome;
+
+        fn dispatch(
+            &self,
+            _ctx: &mut ReqRespCtx,
+        ) -> Result<PendingValue<Option<usize>>, HostError> {
+            Ok(PendingValue::Resolved(Some(1)))
+        }
+
+        fn parse_message(&self, _message: Vec<u8>) -> Outcome {
+            Outcome::Allow(Effects::default())
+        }
+    }
+
+    #[test]
+    fn it_tells_what_it_waits_on() -> Result<(), PipelineError> {
+        let host = MockHost::default()
+            .with_phase(Phase::RequestHeaders)
+            .with_request_headers(vec![(":method", "POST")]);
+        let ctx = ReqRespCtx::new(Box::new(host));
+        let task = |predicate: &str| RLTask {
+            predicate: Predicate::new(predicate).unwrap(),
+            service: Rc::new(FakeService {}),
+            allow_task: None,
+            deny_task: Box::new(TooManyRequestsTask {}),
+            failure_mode: FailureMode::Deny,
+        };
+        let pipeline = Pipeline::new(
+            ctx,
+            vec![
+                Box::new(task("request.method == \"POST\"")),
+                Box::new(task("request.body.contains(\"usage\")")),
+            ],
+        )
+        .eval()?
+        .expect("Pipeline should be waiting for the body");
+
+        assert!(pipeline.awaits(1));
+        assert!(!pipeline.awaits(2));
+        assert!(pipeline.reads("request.body"));
+        assert!(!pipeline.reads("response.body"));
+        Ok(())
+    }
+
+    #[test]
+    fn it_mutates_headers_in_their_phase() {
+        let host = MockHost::default();
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+        let request_task = Box::new(ModifyRequestHeadersTask {
+            mutations: vec![HeaderMutation::new(
+                "x-ratelimit",
+                "ok",
+                HeaderAppendAction::OverwriteIfExistsOrAdd,
+            )],
+            remove: vec![],
+        });
+        let response_task = Box::new(AddResponseHeadersTask {
+            mutations: vec![HeaderMutation::append("X-RateLimit-Limit", "10")],
+        });
+
+        let TaskOutcome::Pending(request_task) = request_task.apply(&mut ctx) else {
+            panic!("Should wait for the request headers");
+        };
+        host.set_phase(Phase::RequestHeaders);
+        assert!(matches!(request_task.apply(&mut ctx), TaskOutcome::Done));
+        assert_eq!(host.request_headers(), vec![("x-ratelimit", "ok")].into());
+
+        host.set_phase(Phase::RequestBody);
+        let late_task = Box::new(ModifyRequestHeadersTask {
+            mutations: vec![],
+            remove: vec!["x-ratelimit".to_string()],
+        });
+        assert!(matches!(late_task.apply(&mut ctx), TaskOutcome::Done));
+        assert!(
+            host.request_headers().contains("x-ratelimit"),
+            "Too late to modify the request"
+        );
+
+        let TaskOutcome::Pending(response_task) = response_task.apply(&mut ctx) else {
+            panic!("Should wait for the response headers");
+        };
+        host.set_phase(Phase::ResponseHeaders);
+        assert!(matches!(response_task.apply(&mut ctx), TaskOutcome::Done));
+        assert_eq!(
+            host.response_headers(),
+            vec![("X-RateLimit-Limit", "10")].into()
+        );
+    }
+
+    #[test]
+    fn it_gets_attributes() {
+        let host = MockHost::default()
+            .with_request_headers(vec![(":authority", "example.com")])
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host));
         assert_eq!(
             ctx.get_attribute("doesntexist"),
             PendingValue::Resolved(None)
         );
         assert_eq!(
-            ctx.get_attribute("ratelimit.domain"),
-            PendingValue::Resolved(Some("example".to_string()))
+            ctx.get_attribute("request.host"),
+            PendingValue::Resolved(Some(AttributeValue::String("example.com".to_string())))
         );
     }
 }
-
-fn main() {
-    // tests::it_rate_limits();
-    // tests::it_not_rate_limits();
-    // tests::it_token_rate_limits();
-    // tests::it_gets_attributes();
-    // println!("ok")
-}
@@ -0,0 +1,289 @@
+use super::{MatcherError, StringMatcher};
+use crate::envoy::{
+    self, HeaderMatcher_oneof_header_match_specifier, StringMatcher_oneof_match_pattern,
+};
+use crate::headers::HeaderMap;
+use std::ops::Range;
+
+/// A compiled `HeaderMatcher`, evaluated against all the values of its header joined with a
+/// `,`, as Envoy does.
+#[derive(Clone, Debug)]
+pub struct HeaderMatcher {
+    name: String,
+    kind: Kind,
+    invert_match: bool,
+    treat_missing_as_empty: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Present(bool),
+    Range(Range<i64>),
+    /// The deprecated `exact_match`, which matches any value when empty.
+    Exact(String),
+    String(StringMatcher),
+}
+
+impl TryFrom<&envoy::HeaderMatcher> for HeaderMatcher {
+    type Error = MatcherError;
+
+    fn try_from(matcher: &envoy::HeaderMatcher) -> Result<Self, Self::Error> {
+        use HeaderMatcher_oneof_header_match_specifier as Specifier;
+        use StringMatcher_oneof_match_pattern as MatchPattern;
+        let string = |pattern| {
+            StringMatcher::try_from(&envoy::StringMatcher {
+                match_pattern: Some(pattern),
+                ..Default::default()
+            })
+            .map(Kind::String)
+        };
+        let kind = match &matcher.header_match_specifier {
+            None => Kind::Present(true),
+            Some(Specifier::present_match(present)) => Kind::Present(*present),
+            Some(Specifier::range_match(range)) => {
+                if range.start > range.end {
+                    return Err(MatcherError::InvalidRange {
+                        start: range.start,
+                        end: range.end,
+                    });
+                }
+                Kind::Range(range.start..range.end)
+            }
+            Some(Specifier::exact_match(exact)) => Kind::Exact(exact.clone()),
+            Some(Specifier::prefix_match(prefix)) => string(MatchPattern::prefix(prefix.clone()))?,
+            Some(Specifier::suffix_match(suffix)) => string(MatchPattern::suffix(suffix.clone()))?,
+            Some(Specifier::contains_match(contains)) => {
+                string(MatchPattern::contains(contains.clone()))?
+            }
+            Some(Specifier::safe_regex_match(regex)) => {
+                string(MatchPattern::safe_regex(regex.clone()))?
+            }
+            Some(Specifier::string_match(matcher)) => {
+                Kind::String(StringMatcher::try_from(matcher)?)
+            }
+        };
+        Ok(Self {
+            name: matcher.name.to_ascii_lowercase(),
+            kind,
+            invert_match: matcher.invert_match,
+            treat_missing_as_empty: false,
+        })
+    }
+}
+
+impl HeaderMatcher {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Matches a missing header as if it were empty, rather than not matching it at all. Newer
+    /// Envoy versions have a `treat_missing_header_as_empty` field for this, which the protos
+    /// of this crate predate.
+    pub fn treat_missing_as_empty(mut self, treat_missing_as_empty: bool) -> Self {
+        self.treat_missing_as_empty = treat_missing_as_empty;
+        self
+    }
+
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let values: Vec<_> = headers.get_all(&self.name).collect();
+        if values.is_empty() && !self.treat_missing_as_empty {
+            // As Envoy's `HeaderUtility::matchHeaders`: only presence says anything about a
+            // missing header, and no value matches it, inverted or not
+            return matches!(self.kind, Kind::Present(present) if present == self.invert_match);
+        }
+        self.matches_value(&values.join(",")) != self.invert_match
+    }
+
+    fn matches_value(&self, value: &str) -> bool {
+        match &self.kind {
+            Kind::Present(present) => *present,
+            Kind::Range(range) => value
+                .trim()
+                .parse::<i64>()
+                .is_ok_and(|value| range.contains(&value)),
+            Kind::Exact(exact) => exact.is_empty() || value == exact,
+            Kind::String(matcher) => matcher.matches(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{Int64Range, RegexMatcher, StringMatcher};
+
+    type Specifier = HeaderMatcher_oneof_header_match_specifier;
+
+    fn matcher(specifier: Option<Specifier>, invert_match: bool) -> HeaderMatcher {
+        HeaderMatcher::try_from(&envoy::HeaderMatcher {
+            name: "X-Tier".to_string(),
+            invert_match,
+            header_match_specifier: specifier,
+            ..Default::default()
+        })
+        .expect("valid matcher")
+    }
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        values
+            .iter()
+            .map(|value| ("x-tier", *value))
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn regex(regex: &str) -> RegexMatcher {
+        RegexMatcher {
+            regex: regex.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_matches_header_values() {
+        let gold = headers(&["gold"]);
+        let cases = [
+            (Specifier::exact_match("gold".to_string()), true),
+            (Specifier::exact_match("GOLD".to_string()), false),
+            (Specifier::exact_match(String::new()), true),
+            (Specifier::prefix_match("go".to_string()), true),
+            (Specifier::suffix_match("ld".to_string()), true),
+            (Specifier::contains_match("ol".to_string()), true),
+            (Specifier::contains_match("silver".to_string()), false),
+            (Specifier::safe_regex_match(regex("g.l")), false),
+            (Specifier::safe_regex_match(regex("g.+")), true),
+            (Specifier::present_match(true), true),
+            (Specifier::present_match(false), false),
+        ];
+        for (specifier, expected) in cases {
+            let description = format!("{specifier:?}");
+            assert_eq!(
+                matcher(Some(specifier.clone()), false).matches(&gold),
+                expected,
+                "{description}"
+            );
+            assert_eq!(
+                matcher(Some(specifier), true).matches(&gold),
+                !expected,
+                "inverted {description}"
+            );
+        }
+        assert!(matcher(None, false).matches(&gold), "present by default");
+    }
+
+    #[test]
+    fn it_matches_ranges() {
+        let range = |start, end| {
+            matcher(
+                Some(Specifier::range_match(Int64Range {
+                    start,
+                    end,
+                    ..Default::default()
+                })),
+                false,
+            )
+        };
+        assert!(range(-10, 0).matches(&headers(&["-1"])));
+        assert!(range(-10, 0).matches(&headers(&["-10"])));
+        assert!(!range(-10, 0).matches(&headers(&["0"])), "end is exclusive");
+        assert!(!range(-10, 0).matches(&headers(&["-1.5"])));
+        assert!(!range(-10, 0).matches(&headers(&["-1", "-2"])));
+        assert_eq!(
+            HeaderMatcher::try_from(&envoy::HeaderMatcher {
+                header_match_specifier: Some(Specifier::range_match(Int64Range {
+                    start: 1,
+                    end: 0,
+                    ..Default::default()
+                })),
+                ..Default::default()
+            })
+            .err(),
+            Some(MatcherError::InvalidRange { start: 1, end: 0 })
+        );
+    }
+
+    #[test]
+    fn it_joins_multiple_values() {
+        let both = headers(&["gold", "silver"]);
+        assert!(
+            matcher(
+                Some(Specifier::exact_match("gold,silver".to_string())),
+                false
+            )
+            .matches(&both)
+        );
+        assert!(!matcher(Some(Specifier::exact_match("gold".to_string())), false).matches(&both));
+        assert!(matcher(Some(Specifier::safe_regex_match(regex("gold,.*"))), false).matches(&both));
+    }
+
+    #[test]
+    fn it_handles_missing_headers() {
+        let none = HeaderMap::default();
+        let exact = Some(Specifier::exact_match(String::new()));
+        assert!(!matcher(exact.clone(), false).matches(&none));
+        assert!(!matcher(exact.clone(), true).matches(&none));
+        assert!(matcher(Some(Specifier::present_match(false)), false).matches(&none));
+        assert!(!matcher(Some(Specifier::present_match(false)), true).matches(&none));
+        assert!(!matcher(Some(Specifier::present_match(true)), false).matches(&none));
+        assert!(matcher(Some(Specifier::present_match(true)), true).matches(&none));
+        assert!(matcher(None, true).matches(&none));
+
+        let empty = matcher(exact, false).treat_missing_as_empty(true);
+        assert!(empty.matches(&none));
+        let prefixed = matcher(Some(Specifier::prefix_match("g".to_string())), false)
+            .treat_missing_as_empty(true);
+        assert!(!prefixed.matches(&none));
+    }
+
+    #[test]
+    fn it_matches_string_matchers() {
+        let string = |pattern, ignore_case| {
+            matcher(
+                Some(Specifier::string_match(StringMatcher {
+                    match_pattern: Some(pattern),
+                    ignore_case,
+                    ..Default::default()
+                })),
+                false,
+            )
+        };
+        let gold = headers(&["Gold"]);
+        assert!(
+            !string(
+                StringMatcher_oneof_match_pattern::exact("gold".to_string()),
+                false
+            )
+            .matches(&gold)
+        );
+        assert!(
+            string(
+                StringMatcher_oneof_match_pattern::exact("gold".to_string()),
+                true
+            )
+            .matches(&gold)
+        );
+        assert!(
+            string(
+                StringMatcher_oneof_match_pattern::prefix("GO".to_string()),
+                true
+            )
+            .matches(&gold)
+        );
+        assert!(
+            !string(
+                StringMatcher_oneof_match_pattern::safe_regex(regex("gold")),
+                true
+            )
+            .matches(&gold)
+        );
+        assert_eq!(
+            HeaderMatcher::try_from(&envoy::HeaderMatcher {
+                header_match_specifier: Some(Specifier::safe_regex_match(regex("("))),
+                ..Default::default()
+            })
+            .err()
+            .map(|e| matches!(e, MatcherError::InvalidRegex { .. })),
+            Some(true)
+        );
+    }
+}
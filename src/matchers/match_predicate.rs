@@ -0,0 +1,383 @@
+use super::{HeaderMatcher, MatcherError, any_is};
+use crate::envoy::{
+    self, HttpGenericBodyMatch, HttpGenericBodyMatch_GenericTextMatch_oneof_rule, HttpHeadersMatch,
+    MatchPredicate_oneof_rule,
+};
+use crate::{PendingValue, Phase, ReqRespCtx};
+
+/// A compiled `MatchPredicate`, evaluated as the request and response make progress: it's
+/// pending until the headers or body it looks at are known, or, for bodies, until either all
+/// patterns were found or none can be anymore.
+#[derive(Clone, Debug)]
+pub struct MatchPredicate {
+    rule: Rule,
+}
+
+#[derive(Clone, Debug)]
+enum Rule {
+    Any,
+    Or(Vec<Rule>),
+    And(Vec<Rule>),
+    Not(Box<Rule>),
+    RequestHeaders(Vec<HeaderMatcher>),
+    ResponseHeaders(Vec<HeaderMatcher>),
+    RequestBody(BodyMatch),
+    ResponseBody(BodyMatch),
+}
+
+/// Matches when all of its patterns are found in the first `bytes_limit` bytes of the body.
+#[derive(Clone, Debug)]
+struct BodyMatch {
+    bytes_limit: Option<usize>,
+    patterns: Vec<Vec<u8>>,
+}
+
+impl TryFrom<&envoy::MatchPredicate> for MatchPredicate {
+    type Error = MatcherError;
+
+    fn try_from(predicate: &envoy::MatchPredicate) -> Result<Self, Self::Error> {
+        Rule::compile(predicate).map(|rule| Self { rule })
+    }
+}
+
+impl MatchPredicate {
+    pub fn eval(&self, ctx: &ReqRespCtx) -> PendingValue<bool> {
+        self.rule.eval(ctx)
+    }
+}
+
+impl Rule {
+    fn compile(predicate: &envoy::MatchPredicate) -> Result<Self, MatcherError> {
+        use MatchPredicate_oneof_rule as Specifier;
+        let all = |predicates: &[envoy::MatchPredicate]| {
+            predicates
+                .iter()
+                .map(Rule::compile)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let headers = |headers: &HttpHeadersMatch| {
+            headers
+                .headers
+                .iter()
+                .map(HeaderMatcher::try_from)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match &predicate.rule {
+            None => return Err(MatcherError::MissingField("rule")),
+            Some(Specifier::any_match(_)) => Rule::Any,
+            Some(Specifier::or_match(set)) => Rule::Or(all(&set.rules)?),
+            Some(Specifier::and_match(set)) => Rule::And(all(&set.rules)?),
+            Some(Specifier::not_match(predicate)) => Rule::Not(Box::new(Rule::compile(predicate)?)),
+            Some(Specifier::http_request_headers_match(matchers)) => {
+                Rule::RequestHeaders(headers(matchers)?)
+            }
+            Some(Specifier::http_response_headers_match(matchers)) => {
+                Rule::ResponseHeaders(headers(matchers)?)
+            }
+            Some(Specifier::http_request_generic_body_match(body)) => {
+                Rule::RequestBody(BodyMatch::compile(body)?)
+            }
+            Some(Specifier::http_response_generic_body_match(body)) => {
+                Rule::ResponseBody(BodyMatch::compile(body)?)
+            }
+            // Trailers aren't seen by the pipeline
+            Some(Specifier::http_request_trailers_match(_)) => {
+                return Err(MatcherError::UnknownExtension(
+                    "http_request_trailers_match".to_string(),
+                ));
+            }
+            Some(Specifier::http_response_trailers_match(_)) => {
+                return Err(MatcherError::UnknownExtension(
+                    "http_response_trailers_match".to_string(),
+                ));
+            }
+        })
+    }
+
+    fn eval(&self, ctx: &ReqRespCtx) -> PendingValue<bool> {
+        let phase = ctx.phase();
+        match self {
+            Rule::Any => PendingValue::Resolved(true),
+            Rule::Or(rules) => any_is(rules.iter().map(|rule| rule.eval(ctx)), true),
+            Rule::And(rules) => any_is(rules.iter().map(|rule| rule.eval(ctx)), false),
+            Rule::Not(rule) => match rule.eval(ctx) {
+                PendingValue::Resolved(holds) => PendingValue::Resolved(!holds),
+                PendingValue::Pending => PendingValue::Pending,
+            },
+            Rule::RequestHeaders(matchers) => {
+                if phase.is_none_or(|phase| phase < Phase::RequestHeaders) {
+                    return PendingValue::Pending;
+                }
+                let headers = ctx.host.request_headers();
+                PendingValue::Resolved(matchers.iter().all(|m| m.matches(&headers)))
+            }
+            Rule::ResponseHeaders(matchers) => {
+                if phase.is_none_or(|phase| phase < Phase::ResponseHeaders) {
+                    return PendingValue::Pending;
+                }
+                let headers = ctx.host.response_headers();
+                PendingValue::Resolved(matchers.iter().all(|m| m.matches(&headers)))
+            }
+            Rule::RequestBody(body) => match phase {
+                Some(phase) if phase >= Phase::RequestBody => body.eval(
+                    &ctx.host.request_body().unwrap_or_default(),
+                    // Once past its phase, the host still has all of the request body
+                    phase > Phase::RequestBody || ctx.host.end_of_stream(),
+                ),
+                Some(Phase::RequestHeaders) if ctx.host.end_of_stream() => body.eval(&[], true),
+                _ => PendingValue::Pending,
+            },
+            Rule::ResponseBody(body) => match phase {
+                Some(Phase::ResponseBody) => body.eval(
+                    &ctx.host.response_body().unwrap_or_default(),
+                    ctx.host.end_of_stream(),
+                ),
+                // No body phase follows headers ending the stream
+                Some(Phase::ResponseHeaders) if ctx.host.end_of_stream() => body.eval(&[], true),
+                _ => PendingValue::Pending,
+            },
+        }
+    }
+}
+
+impl BodyMatch {
+    fn compile(body: &HttpGenericBodyMatch) -> Result<Self, MatcherError> {
+        use HttpGenericBodyMatch_GenericTextMatch_oneof_rule as Pattern;
+        if body.patterns.is_empty() {
+            return Err(MatcherError::MissingPattern);
+        }
+        let patterns = body
+            .patterns
+            .iter()
+            .map(|pattern| match &pattern.rule {
+                None => Err(MatcherError::MissingPattern),
+                Some(Pattern::string_match(pattern)) if !pattern.is_empty() => {
+                    Ok(pattern.as_bytes().to_vec())
+                }
+                Some(Pattern::binary_match(pattern)) if !pattern.is_empty() => Ok(pattern.clone()),
+                Some(_) => Err(MatcherError::EmptyPattern),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            bytes_limit: (body.bytes_limit > 0).then_some(body.bytes_limit as usize),
+            patterns,
+        })
+    }
+
+    /// Pending while the patterns weren't all found and more of the body may still arrive
+    /// within the limit.
+    fn eval(&self, body: &[u8], complete: bool) -> PendingValue<bool> {
+        let searched = &body[..self
+            .bytes_limit
+            .map_or(body.len(), |limit| limit.min(body.len()))];
+        let found = self.patterns.iter().all(|pattern| {
+            searched
+                .windows(pattern.len())
+                .any(|window| window == pattern.as_slice())
+        });
+        if found || complete || self.bytes_limit.is_some_and(|limit| body.len() >= limit) {
+            PendingValue::Resolved(found)
+        } else {
+            PendingValue::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{
+        HeaderMatcher_oneof_header_match_specifier, HttpGenericBodyMatch_GenericTextMatch,
+        MatchPredicate_MatchSet,
+    };
+    use crate::host::{Host, MockHost};
+
+    type Specifier = MatchPredicate_oneof_rule;
+
+    fn predicate(rule: Specifier) -> envoy::MatchPredicate {
+        envoy::MatchPredicate {
+            rule: Some(rule),
+            ..Default::default()
+        }
+    }
+
+    fn headers(name: &str, exact: &str) -> HttpHeadersMatch {
+        HttpHeadersMatch {
+            headers: vec![envoy::HeaderMatcher {
+                name: name.to_string(),
+                header_match_specifier: Some(
+                    HeaderMatcher_oneof_header_match_specifier::exact_match(exact.to_string()),
+                ),
+                ..Default::default()
+            }]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    fn body(bytes_limit: u32, patterns: &[&str]) -> HttpGenericBodyMatch {
+        HttpGenericBodyMatch {
+            bytes_limit,
+            patterns: patterns
+                .iter()
+                .map(|pattern| HttpGenericBodyMatch_GenericTextMatch {
+                    rule: Some(
+                        HttpGenericBodyMatch_GenericTextMatch_oneof_rule::string_match(
+                            pattern.to_string(),
+                        ),
+                    ),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn set(rules: Vec<envoy::MatchPredicate>) -> MatchPredicate_MatchSet {
+        MatchPredicate_MatchSet {
+            rules: rules.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_matches_headers() {
+        let post = predicate(Specifier::http_request_headers_match(headers(
+            ":method", "POST",
+        )));
+        let ok = predicate(Specifier::http_response_headers_match(headers(
+            ":status", "200",
+        )));
+        let compile = |rule| MatchPredicate::try_from(&predicate(rule)).unwrap();
+        let either = compile(Specifier::or_match(set(vec![post.clone(), ok.clone()])));
+        let both = compile(Specifier::and_match(set(vec![post.clone(), ok])));
+        let not_post = compile(Specifier::not_match(Box::new(post)));
+        let any = compile(Specifier::any_match(true));
+
+        let host = MockHost::default()
+            .with_request_headers(vec![(":method", "POST")])
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        assert_eq!(either.eval(&ctx), PendingValue::Resolved(true));
+        assert_eq!(both.eval(&ctx), PendingValue::Pending);
+        assert_eq!(not_post.eval(&ctx), PendingValue::Resolved(false));
+        assert_eq!(any.eval(&ctx), PendingValue::Resolved(true));
+
+        host.set_response_headers(vec![(":status", "429")].into());
+        host.set_phase(Phase::ResponseHeaders);
+        assert_eq!(both.eval(&ctx), PendingValue::Resolved(false));
+    }
+
+    #[test]
+    fn it_streams_the_body() {
+        let gpt4 = MatchPredicate::try_from(&predicate(
+            Specifier::http_request_generic_body_match(body(32, &["\"model\":\"gpt-4\"", "{"])),
+        ))
+        .unwrap();
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        assert_eq!(gpt4.eval(&ctx), PendingValue::Pending);
+
+        host.set_phase(Phase::RequestBody);
+        host.append_request_body(br#"{"model":"gp"#);
+        assert_eq!(gpt4.eval(&ctx), PendingValue::Pending);
+        host.append_request_body(br#"t-4"}"#);
+        assert_eq!(gpt4.eval(&ctx), PendingValue::Resolved(true));
+
+        let host = MockHost::default().with_phase(Phase::RequestBody);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        host.append_request_body(br#"{"messages":[],"#);
+        assert_eq!(gpt4.eval(&ctx), PendingValue::Pending);
+        host.append_request_body(br#""stream":true,"model":"gpt-4"}"#);
+        assert_eq!(
+            gpt4.eval(&ctx),
+            PendingValue::Resolved(false),
+            "past the bytes limit"
+        );
+
+        let host = MockHost::default().with_phase(Phase::RequestBody);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        host.append_request_body(b"{}");
+        assert_eq!(gpt4.eval(&ctx), PendingValue::Pending);
+        host.set_end_of_stream(true);
+        assert_eq!(gpt4.eval(&ctx), PendingValue::Resolved(false));
+
+        let host = MockHost::default().with_phase(Phase::RequestBody);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        host.append_request_body(br#"{"model":"gpt-4"}"#);
+        host.set_end_of_stream(true);
+        host.set_phase(Phase::ResponseHeaders);
+        host.set_end_of_stream(false);
+        assert_eq!(
+            gpt4.eval(&ctx),
+            PendingValue::Resolved(true),
+            "the body is kept past its phase"
+        );
+
+        let host = MockHost::default().with_phase(Phase::ResponseHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(
+            gpt4.eval(&ctx),
+            PendingValue::Resolved(false),
+            "the request had no body"
+        );
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        host.set_end_of_stream(true);
+        let ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(
+            gpt4.eval(&ctx),
+            PendingValue::Resolved(false),
+            "the request has no body"
+        );
+    }
+
+    #[test]
+    fn it_matches_the_response_body() {
+        let usage = MatchPredicate::try_from(&predicate(
+            Specifier::http_response_generic_body_match(body(0, &["usage"])),
+        ))
+        .unwrap();
+        let host = MockHost::default().with_phase(Phase::ResponseHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host.clone()));
+        assert_eq!(usage.eval(&ctx), PendingValue::Pending);
+
+        host.set_phase(Phase::ResponseBody);
+        host.append_response_body(br#"{"usage":{}}"#);
+        host.set_end_of_stream(true);
+        assert_eq!(usage.eval(&ctx), PendingValue::Resolved(true));
+
+        let host = MockHost::default().with_phase(Phase::ResponseHeaders);
+        host.set_end_of_stream(true);
+        let ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(
+            usage.eval(&ctx),
+            PendingValue::Resolved(false),
+            "the response has no body"
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_predicates() {
+        let error = |rule| MatchPredicate::try_from(&predicate(rule)).err();
+        assert_eq!(
+            MatchPredicate::try_from(&envoy::MatchPredicate::default()).err(),
+            Some(MatcherError::MissingField("rule"))
+        );
+        assert_eq!(
+            error(Specifier::http_request_generic_body_match(body(0, &[]))),
+            Some(MatcherError::MissingPattern)
+        );
+        assert_eq!(
+            error(Specifier::http_response_generic_body_match(body(0, &[""]))),
+            Some(MatcherError::EmptyPattern)
+        );
+        assert_eq!(
+            error(Specifier::http_request_trailers_match(
+                HttpHeadersMatch::new()
+            )),
+            Some(MatcherError::UnknownExtension(
+                "http_request_trailers_match".to_string()
+            ))
+        );
+    }
+}
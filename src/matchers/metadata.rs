@@ -0,0 +1,320 @@
+use super::{MatcherError, StringMatcher};
+use crate::envoy::{
+    self, DoubleMatcher_oneof_match_pattern, ListMatcher_oneof_match_pattern, Metadata,
+    MetadataKey, MetadataKey_PathSegment, MetadataKey_PathSegment_oneof_segment,
+    MetadataMatcher_PathSegment_oneof_segment, ValueMatcher_oneof_match_pattern,
+};
+use crate::metadata::resolve;
+use protobuf::well_known_types::{Value, Value_oneof_kind};
+use std::ops::Range;
+
+/// A compiled `MetadataMatcher`, matching the value at its path in the metadata of its filter.
+#[derive(Clone, Debug)]
+pub struct MetadataMatcher {
+    key: MetadataKey,
+    value: ValueMatcher,
+    invert: bool,
+}
+
+impl TryFrom<&envoy::MetadataMatcher> for MetadataMatcher {
+    type Error = MatcherError;
+
+    fn try_from(matcher: &envoy::MetadataMatcher) -> Result<Self, Self::Error> {
+        let path = matcher
+            .path
+            .iter()
+            .map(|segment| match &segment.segment {
+                Some(MetadataMatcher_PathSegment_oneof_segment::key(key)) => {
+                    Ok(MetadataKey_PathSegment {
+                        segment: Some(MetadataKey_PathSegment_oneof_segment::key(key.clone())),
+                        ..Default::default()
+                    })
+                }
+                None => Err(MatcherError::MissingField("segment")),
+            })
+            .collect::<Result<_, _>>()?;
+        let value = matcher
+            .value
+            .as_ref()
+            .ok_or(MatcherError::MissingField("value"))?;
+        Ok(Self {
+            key: MetadataKey {
+                key: matcher.filter.clone(),
+                path,
+                ..Default::default()
+            },
+            value: ValueMatcher::try_from(value)?,
+            invert: matcher.invert,
+        })
+    }
+}
+
+impl MetadataMatcher {
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        self.value.matches(
+            resolve(metadata, &self.key)
+                .ok()
+                .as_ref()
+                .map(|v| v.value()),
+        ) != self.invert
+    }
+}
+
+/// A compiled `ValueMatcher`. As with Envoy, a missing value only ever matches an inverted
+/// metadata matcher, not even `present_match: false` matches it.
+#[derive(Clone, Debug)]
+pub struct ValueMatcher {
+    kind: Kind,
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Null,
+    /// `[start, end)`
+    Range(Range<f64>),
+    Double(f64),
+    String(StringMatcher),
+    Bool(bool),
+    Present(bool),
+    /// Matches lists with at least one matching item.
+    OneOf(Box<ValueMatcher>),
+}
+
+impl TryFrom<&envoy::ValueMatcher> for ValueMatcher {
+    type Error = MatcherError;
+
+    fn try_from(matcher: &envoy::ValueMatcher) -> Result<Self, Self::Error> {
+        use ValueMatcher_oneof_match_pattern as MatchPattern;
+        let kind = match &matcher.match_pattern {
+            None => return Err(MatcherError::MissingPattern),
+            Some(MatchPattern::null_match(_)) => Kind::Null,
+            Some(MatchPattern::double_match(double)) => match &double.match_pattern {
+                None => return Err(MatcherError::MissingPattern),
+                Some(DoubleMatcher_oneof_match_pattern::range(range)) => {
+                    Kind::Range(range.start..range.end)
+                }
+                Some(DoubleMatcher_oneof_match_pattern::exact(exact)) => Kind::Double(*exact),
+            },
+            Some(MatchPattern::string_match(string)) => {
+                Kind::String(StringMatcher::try_from(string)?)
+            }
+            Some(MatchPattern::bool_match(expected)) => Kind::Bool(*expected),
+            Some(MatchPattern::present_match(present)) => Kind::Present(*present),
+            Some(MatchPattern::list_match(list)) => match &list.match_pattern {
+                None => return Err(MatcherError::MissingPattern),
+                Some(ListMatcher_oneof_match_pattern::one_of(item)) => {
+                    Kind::OneOf(Box::new(ValueMatcher::try_from(item.as_ref())?))
+                }
+            },
+        };
+        Ok(Self { kind })
+    }
+}
+
+impl ValueMatcher {
+    pub fn matches(&self, value: Option<&Value>) -> bool {
+        let Some(kind) = value.and_then(|value| value.kind.as_ref()) else {
+            return false;
+        };
+        match (&self.kind, kind) {
+            (Kind::Null, Value_oneof_kind::null_value(_)) => true,
+            (Kind::Range(range), Value_oneof_kind::number_value(number)) => range.contains(number),
+            (Kind::Double(exact), Value_oneof_kind::number_value(number)) => number == exact,
+            (Kind::String(matcher), Value_oneof_kind::string_value(string)) => {
+                matcher.matches(string)
+            }
+            (Kind::Bool(expected), Value_oneof_kind::bool_value(actual)) => actual == expected,
+            (Kind::Present(present), _) => *present,
+            (Kind::OneOf(matcher), Value_oneof_kind::list_value(list)) => {
+                list.values.iter().any(|item| matcher.matches(Some(item)))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{
+        DoubleMatcher, DoubleRange, ListMatcher, MetadataMatcher_PathSegment,
+        StringMatcher_oneof_match_pattern, ValueMatcher_NullMatch,
+    };
+    use crate::host::MockHost;
+    use crate::predicate::Predicate;
+    use crate::{PendingValue, Phase, ReqRespCtx};
+    use protobuf::well_known_types::{ListValue, NullValue, Struct};
+
+    type MatchPattern = ValueMatcher_oneof_match_pattern;
+
+    fn value(kind: Value_oneof_kind) -> Value {
+        Value {
+            kind: Some(kind),
+            ..Default::default()
+        }
+    }
+
+    fn matcher(pattern: MatchPattern) -> ValueMatcher {
+        ValueMatcher::try_from(&envoy::ValueMatcher {
+            match_pattern: Some(pattern),
+            ..Default::default()
+        })
+        .expect("valid matcher")
+    }
+
+    fn metadata_matcher(path: &[&str], pattern: MatchPattern, invert: bool) -> MetadataMatcher {
+        MetadataMatcher::try_from(&envoy::MetadataMatcher {
+            filter: "envoy.filters.http.ext_authz".to_string(),
+            path: path
+                .iter()
+                .map(|key| MetadataMatcher_PathSegment {
+                    segment: Some(MetadataMatcher_PathSegment_oneof_segment::key(
+                        key.to_string(),
+                    )),
+                    ..Default::default()
+                })
+                .collect(),
+            value: Some(envoy::ValueMatcher {
+                match_pattern: Some(pattern),
+                ..Default::default()
+            })
+            .into(),
+            invert,
+            ..Default::default()
+        })
+        .expect("valid matcher")
+    }
+
+    fn string(exact: &str) -> MatchPattern {
+        MatchPattern::string_match(envoy::StringMatcher {
+            match_pattern: Some(StringMatcher_oneof_match_pattern::exact(exact.to_string())),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn it_matches_values() {
+        let null = value(Value_oneof_kind::null_value(NullValue::NULL_VALUE));
+        let seven = value(Value_oneof_kind::number_value(7.0));
+        let gold = value(Value_oneof_kind::string_value("gold".to_string()));
+        let yes = value(Value_oneof_kind::bool_value(true));
+        let range = |start, end| {
+            MatchPattern::double_match(DoubleMatcher {
+                match_pattern: Some(DoubleMatcher_oneof_match_pattern::range(DoubleRange {
+                    start,
+                    end,
+                    ..Default::default()
+                })),
+                ..Default::default()
+            })
+        };
+
+        let cases = [
+            (
+                MatchPattern::null_match(ValueMatcher_NullMatch::new()),
+                [true, false, false, false],
+            ),
+            (range(0.0, 7.5), [false, true, false, false]),
+            (range(0.0, 7.0), [false, false, false, false]),
+            (
+                MatchPattern::double_match(DoubleMatcher {
+                    match_pattern: Some(DoubleMatcher_oneof_match_pattern::exact(7.0)),
+                    ..Default::default()
+                }),
+                [false, true, false, false],
+            ),
+            (string("gold"), [false, false, true, false]),
+            (MatchPattern::bool_match(true), [false, false, false, true]),
+            (
+                MatchPattern::bool_match(false),
+                [false, false, false, false],
+            ),
+            (MatchPattern::present_match(true), [true, true, true, true]),
+            (MatchPattern::present_match(false), [false; 4]),
+        ];
+        for (pattern, expected) in cases {
+            let description = format!("{pattern:?}");
+            let matcher = matcher(pattern);
+            let actual = [&null, &seven, &gold, &yes].map(|value| matcher.matches(Some(value)));
+            assert_eq!(actual, expected, "{description}");
+            assert!(!matcher.matches(None), "{description} on a missing value");
+        }
+    }
+
+    #[test]
+    fn it_matches_one_of_a_list() {
+        let one_of_gold = matcher(MatchPattern::list_match(Box::new(ListMatcher {
+            match_pattern: Some(ListMatcher_oneof_match_pattern::one_of(Box::new(
+                envoy::ValueMatcher {
+                    match_pattern: Some(string("gold")),
+                    ..Default::default()
+                },
+            ))),
+            ..Default::default()
+        })));
+        let list = |items: &[&str]| {
+            value(Value_oneof_kind::list_value(ListValue {
+                values: items
+                    .iter()
+                    .map(|item| value(Value_oneof_kind::string_value(item.to_string())))
+                    .collect(),
+                ..Default::default()
+            }))
+        };
+        assert!(one_of_gold.matches(Some(&list(&["silver", "gold"]))));
+        assert!(!one_of_gold.matches(Some(&list(&["silver"]))));
+        assert!(!one_of_gold.matches(Some(&list(&[]))));
+        assert!(
+            !one_of_gold.matches(Some(&value(Value_oneof_kind::string_value(
+                "gold".to_string()
+            ))))
+        );
+    }
+
+    #[test]
+    fn it_matches_dynamic_metadata() {
+        let mut identity = Struct::new();
+        identity.fields.insert(
+            "tier".to_string(),
+            value(Value_oneof_kind::string_value("gold".to_string())),
+        );
+        let mut authz = Struct::new();
+        authz.fields.insert(
+            "identity".to_string(),
+            value(Value_oneof_kind::struct_value(identity)),
+        );
+        let mut metadata = Metadata::new();
+        metadata
+            .filter_metadata
+            .insert("envoy.filters.http.ext_authz".to_string(), authz);
+
+        let gold = metadata_matcher(&["identity", "tier"], string("gold"), false);
+        assert!(gold.matches(&metadata));
+        assert!(!gold.matches(&Metadata::new()));
+        let not_gold = metadata_matcher(&["identity", "tier"], string("gold"), true);
+        assert!(!not_gold.matches(&metadata));
+        assert!(not_gold.matches(&Metadata::new()));
+        let absent = metadata_matcher(
+            &["identity", "plan"],
+            MatchPattern::present_match(true),
+            true,
+        );
+        assert!(absent.matches(&metadata));
+
+        let predicate = Predicate::from(gold);
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(predicate.eval(&ctx), PendingValue::Resolved(false));
+        ctx.dynamic_metadata = metadata;
+        assert_eq!(predicate.eval(&ctx), PendingValue::Resolved(true));
+
+        assert_eq!(
+            MetadataMatcher::try_from(&envoy::MetadataMatcher::new()).err(),
+            Some(MatcherError::MissingField("value"))
+        );
+        assert_eq!(
+            ValueMatcher::try_from(&envoy::ValueMatcher::new()).err(),
+            Some(MatcherError::MissingPattern)
+        );
+    }
+}
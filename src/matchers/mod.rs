@@ -0,0 +1,157 @@
+use crate::PendingValue;
+use crate::envoy::{RegexMatcher, RegexMatcher_oneof_engine_type};
+use regex::Regex;
+use regex_automata::nfa::thompson::Compiler;
+use regex_automata::util::syntax;
+use std::fmt;
+
+mod header;
+mod match_predicate;
+mod metadata;
+mod query;
+mod regex_rewrite;
+mod string;
+mod tree;
+
+pub use header::HeaderMatcher;
+pub use match_predicate::MatchPredicate;
+pub use metadata::MetadataMatcher;
+pub use query::QueryParameterMatcher;
+pub use regex_rewrite::RegexRewrite;
+pub use string::{ListStringMatcher, StringMatcher};
+pub use tree::{DataInputRegistry, MatchTree};
+
+/// Envoy's default for `re2.max_program_size.error_level`, when the regex has no
+/// `max_program_size` of its own.
+const DEFAULT_MAX_PROGRAM_SIZE: usize = 100;
+
+#[derive(Debug, PartialEq)]
+pub enum MatcherError {
+    MissingPattern,
+    EmptyPattern,
+    InvalidRegex {
+        regex: String,
+        message: String,
+    },
+    InvalidRange {
+        start: i64,
+        end: i64,
+    },
+    ProgramTooLarge {
+        regex: String,
+        size: usize,
+        max: usize,
+    },
+    InvalidSubstitution {
+        substitution: String,
+        message: String,
+    },
+    /// A required field of a unified matcher isn't set.
+    MissingField(&'static str),
+    /// More than one of the fields of a `oneof` are set.
+    MoreThanOne(&'static str),
+    /// An extension, such as a data input, that isn't supported.
+    UnknownExtension(String),
+    InvalidExtension {
+        type_url: String,
+        message: String,
+    },
+    /// An action that doesn't select anything known.
+    UnknownAction(String),
+}
+
+impl fmt::Display for MatcherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatcherError::MissingPattern => write!(f, "no pattern to match"),
+            MatcherError::EmptyPattern => write!(f, "empty prefix, suffix or contains pattern"),
+            MatcherError::InvalidRegex { regex, message } => {
+                write!(f, "invalid regex `{regex}`: {message}")
+            }
+            MatcherError::InvalidRange { start, end } => {
+                write!(f, "invalid range [{start}, {end})")
+            }
+            MatcherError::ProgramTooLarge { regex, size, max } => write!(
+                f,
+                "regex `{regex}` is too complex, its program size {size} exceeds {max}"
+            ),
+            MatcherError::InvalidSubstitution {
+                substitution,
+                message,
+            } => write!(f, "invalid substitution `{substitution}`: {message}"),
+            MatcherError::MissingField(field) => write!(f, "missing `{field}`"),
+            MatcherError::MoreThanOne(field) => write!(f, "more than one `{field}`"),
+            MatcherError::UnknownExtension(name) => write!(f, "unsupported extension `{name}`"),
+            MatcherError::InvalidExtension { type_url, message } => {
+                write!(f, "invalid `{type_url}`: {message}")
+            }
+            MatcherError::UnknownAction(name) => write!(f, "unknown action `{name}`"),
+        }
+    }
+}
+
+impl std::error::Error for MatcherError {}
+
+/// `decisive` when any of `results` is, which are evaluated up to that one, and pending when
+/// none is but some are pending.
+fn any_is(
+    results: impl IntoIterator<Item = PendingValue<bool>>,
+    decisive: bool,
+) -> PendingValue<bool> {
+    let mut pending = false;
+    for result in results {
+        match result {
+            PendingValue::Resolved(holds) if holds == decisive => {
+                return PendingValue::Resolved(decisive);
+            }
+            PendingValue::Resolved(_) => {}
+            PendingValue::Pending => pending = true,
+        }
+    }
+    if pending {
+        PendingValue::Pending
+    } else {
+        PendingValue::Resolved(!decisive)
+    }
+}
+
+/// Envoy's regexes have to match the whole value, not just part of it.
+fn full_match_regex(matcher: &RegexMatcher) -> Result<Regex, MatcherError> {
+    compile_regex(matcher, &format!("^(?:{})$", matcher.regex))
+}
+
+/// Compiles `pattern`, the possibly decorated regex of `matcher`, once the complexity of the
+/// latter checked against its `max_program_size`. The size of its byte oriented NFA stands in
+/// for the one of the RE2 program, close enough to keep the same budgets.
+fn compile_regex(matcher: &RegexMatcher, pattern: &str) -> Result<Regex, MatcherError> {
+    let invalid = |message: String| MatcherError::InvalidRegex {
+        regex: matcher.regex.clone(),
+        message,
+    };
+    let max = match &matcher.engine_type {
+        Some(RegexMatcher_oneof_engine_type::google_re2(re2)) => re2
+            .max_program_size
+            .as_ref()
+            .map_or(DEFAULT_MAX_PROGRAM_SIZE, |max| max.value as usize),
+        None => DEFAULT_MAX_PROGRAM_SIZE,
+    };
+    let nfa = match Compiler::new()
+        .syntax(syntax::Config::new().unicode(false).utf8(false))
+        .build(&matcher.regex)
+    {
+        Ok(nfa) => nfa,
+        // e.g. Unicode classes, which can't be matched byte wise
+        Err(_) => Compiler::new()
+            .build(&matcher.regex)
+            .map_err(|e| invalid(e.to_string()))?,
+    };
+    let size = nfa.states().len();
+    if size > max {
+        return Err(MatcherError::ProgramTooLarge {
+            regex: matcher.regex.clone(),
+            size,
+            max,
+        });
+    }
+    Regex::new(pattern).map_err(|e| invalid(e.to_string()))
+}
@@ -0,0 +1,98 @@
+use super::{MatcherError, StringMatcher};
+use crate::envoy::{self, QueryParameterMatcher_oneof_query_parameter_match_specifier};
+use crate::query::QueryParams;
+
+/// A compiled `QueryParameterMatcher`, evaluated against the first value of its parameter, as
+/// Envoy does. A missing parameter never matches.
+#[derive(Clone, Debug)]
+pub struct QueryParameterMatcher {
+    name: String,
+    /// `None` when the parameter only has to be present.
+    matcher: Option<StringMatcher>,
+}
+
+impl TryFrom<&envoy::QueryParameterMatcher> for QueryParameterMatcher {
+    type Error = MatcherError;
+
+    fn try_from(matcher: &envoy::QueryParameterMatcher) -> Result<Self, Self::Error> {
+        use QueryParameterMatcher_oneof_query_parameter_match_specifier as Specifier;
+        let string_matcher = match &matcher.query_parameter_match_specifier {
+            Some(Specifier::string_match(matcher)) => Some(StringMatcher::try_from(matcher)?),
+            // Even a `false` one only checks the presence of the parameter in Envoy
+            Some(Specifier::present_match(_)) | None => None,
+        };
+        Ok(Self {
+            name: matcher.name.clone(),
+            matcher: string_matcher,
+        })
+    }
+}
+
+impl QueryParameterMatcher {
+    pub fn matches(&self, params: &QueryParams) -> bool {
+        params.get(&self.name).is_some_and(|value| {
+            self.matcher
+                .as_ref()
+                .is_none_or(|matcher| matcher.matches(value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{RegexMatcher, StringMatcher_oneof_match_pattern};
+
+    type Specifier = QueryParameterMatcher_oneof_query_parameter_match_specifier;
+
+    fn matcher(specifier: Option<Specifier>) -> QueryParameterMatcher {
+        QueryParameterMatcher::try_from(&envoy::QueryParameterMatcher {
+            name: "api_key".to_string(),
+            query_parameter_match_specifier: specifier,
+            ..Default::default()
+        })
+        .expect("valid matcher")
+    }
+
+    fn string(pattern: StringMatcher_oneof_match_pattern) -> Option<Specifier> {
+        Some(Specifier::string_match(envoy::StringMatcher {
+            match_pattern: Some(pattern),
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn it_matches_query_parameters() {
+        let params = QueryParams::parse("api_key=abc%2B1&api_key=other&flag");
+        let exact = |value: &str| string(StringMatcher_oneof_match_pattern::exact(value.into()));
+        assert!(matcher(exact("abc+1")).matches(&params));
+        assert!(
+            !matcher(exact("other")).matches(&params),
+            "only the first value counts"
+        );
+        assert!(matcher(Some(Specifier::present_match(true))).matches(&params));
+        assert!(matcher(Some(Specifier::present_match(false))).matches(&params));
+        assert!(matcher(None).matches(&params));
+
+        let none = QueryParams::parse("flag");
+        assert!(!matcher(Some(Specifier::present_match(true))).matches(&none));
+        assert!(!matcher(exact("")).matches(&none));
+        assert!(matcher(exact("")).matches(&QueryParams::parse("api_key")));
+
+        assert_eq!(
+            QueryParameterMatcher::try_from(&envoy::QueryParameterMatcher {
+                name: "api_key".to_string(),
+                query_parameter_match_specifier: string(
+                    StringMatcher_oneof_match_pattern::safe_regex(RegexMatcher {
+                        regex: "(".to_string(),
+                        ..Default::default()
+                    })
+                ),
+                ..Default::default()
+            })
+            .err()
+            .map(|e| matches!(e, MatcherError::InvalidRegex { .. })),
+            Some(true)
+        );
+    }
+}
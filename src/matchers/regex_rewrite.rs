@@ -0,0 +1,162 @@
+use super::{MatcherError, compile_regex};
+use crate::envoy::RegexMatchAndSubstitute;
+use regex::Regex;
+
+/// A compiled `RegexMatchAndSubstitute`, replacing every match of its pattern with the
+/// substitution, where `\1` to `\9` refer to the capture groups and `\0` to the whole match.
+#[derive(Clone, Debug)]
+pub struct RegexRewrite {
+    regex: Regex,
+    /// The substitution, in the syntax of `Regex::replace_all`.
+    replacement: String,
+}
+
+impl TryFrom<&RegexMatchAndSubstitute> for RegexRewrite {
+    type Error = MatcherError;
+
+    fn try_from(rewrite: &RegexMatchAndSubstitute) -> Result<Self, Self::Error> {
+        let pattern = rewrite
+            .pattern
+            .as_ref()
+            .ok_or(MatcherError::MissingPattern)?;
+        let regex = compile_regex(pattern, &pattern.regex)?;
+        let invalid = |message: String| MatcherError::InvalidSubstitution {
+            substitution: rewrite.substitution.clone(),
+            message,
+        };
+
+        let mut replacement = String::with_capacity(rewrite.substitution.len());
+        let mut chars = rewrite.substitution.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('\\') => replacement.push('\\'),
+                    Some(digit @ '0'..='9') => {
+                        let group = digit as usize - '0' as usize;
+                        if group >= regex.captures_len() {
+                            return Err(invalid(format!(
+                                "`\\{group}` but the regex only has {} group(s)",
+                                regex.captures_len() - 1
+                            )));
+                        }
+                        replacement.push_str(&format!("${{{group}}}"));
+                    }
+                    _ => return Err(invalid("`\\` must precede a digit or `\\`".to_string())),
+                },
+                '$' => replacement.push_str("$$"),
+                c => replacement.push(c),
+            }
+        }
+        Ok(Self { regex, replacement })
+    }
+}
+
+impl PartialEq for RegexRewrite {
+    fn eq(&self, other: &Self) -> bool {
+        self.regex.as_str() == other.regex.as_str() && self.replacement == other.replacement
+    }
+}
+
+impl RegexRewrite {
+    pub fn apply(&self, value: &str) -> String {
+        self.regex
+            .replace_all(value, self.replacement.as_str())
+            .into_owned()
+    }
+
+    /// Rewrites the path only, the query string is left untouched, as in Envoy's route
+    /// `regex_rewrite`.
+    pub fn apply_to_path(&self, path: &str) -> String {
+        match path.split_once('?') {
+            Some((path, query)) => format!("{}?{query}", self.apply(path)),
+            None => self.apply(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{RegexMatcher, RegexMatcher_GoogleRE2, RegexMatcher_oneof_engine_type};
+    use protobuf::SingularPtrField;
+    use protobuf::well_known_types::UInt32Value;
+
+    fn rewrite(regex: &str, substitution: &str) -> Result<RegexRewrite, MatcherError> {
+        RegexRewrite::try_from(&RegexMatchAndSubstitute {
+            pattern: SingularPtrField::some(RegexMatcher {
+                regex: regex.to_string(),
+                ..Default::default()
+            }),
+            substitution: substitution.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn it_substitutes_capture_groups() {
+        let ids = rewrite("/[0-9]+", "/{id}").unwrap();
+        assert_eq!(ids.apply("/users/123/posts/4"), "/users/{id}/posts/{id}");
+        assert_eq!(ids.apply("/users"), "/users");
+
+        let swap = rewrite("^/(\\w+)/(\\w+)$", "/\\2/\\1").unwrap();
+        assert_eq!(swap.apply("/users/alice"), "/alice/users");
+
+        let whole = rewrite("[a-z]+", "<\\0>$1\\\\").unwrap();
+        assert_eq!(whole.apply("ab1"), "<ab>$1\\1");
+    }
+
+    #[test]
+    fn it_leaves_the_query_string_alone() {
+        let ids = rewrite("/[0-9]+", "/{id}").unwrap();
+        assert_eq!(
+            ids.apply_to_path("/users/123?page=2&id=/42"),
+            "/users/{id}?page=2&id=/42"
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_substitutions() {
+        assert!(matches!(
+            rewrite("/([0-9]+)", "/\\2"),
+            Err(MatcherError::InvalidSubstitution { .. })
+        ));
+        assert!(matches!(
+            rewrite("/([0-9]+)", "/\\n"),
+            Err(MatcherError::InvalidSubstitution { .. })
+        ));
+        assert!(matches!(
+            RegexRewrite::try_from(&RegexMatchAndSubstitute::default()),
+            Err(MatcherError::MissingPattern)
+        ));
+    }
+
+    #[test]
+    fn it_enforces_the_program_size_budget() {
+        assert!(matches!(
+            rewrite("[a-z]{200}", ""),
+            Err(MatcherError::ProgramTooLarge { max: 100, .. })
+        ));
+
+        let budget = |max_program_size| RegexMatchAndSubstitute {
+            pattern: SingularPtrField::some(RegexMatcher {
+                regex: "[a-z]{200}".to_string(),
+                engine_type: Some(RegexMatcher_oneof_engine_type::google_re2(
+                    RegexMatcher_GoogleRE2 {
+                        max_program_size: SingularPtrField::some(UInt32Value {
+                            value: max_program_size,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                )),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(RegexRewrite::try_from(&budget(1000)).is_ok());
+        assert!(matches!(
+            RegexRewrite::try_from(&budget(10)),
+            Err(MatcherError::ProgramTooLarge { max: 10, .. })
+        ));
+    }
+}
@@ -0,0 +1,272 @@
+use super::{MatcherError, full_match_regex};
+use crate::envoy::{self, StringMatcher_oneof_match_pattern};
+use regex::Regex;
+
+/// A compiled `StringMatcher`. Like Envoy, `ignore_case` only folds ASCII letters and has no
+/// effect on regexes, which have to match the whole value.
+#[derive(Clone, Debug)]
+pub struct StringMatcher {
+    pattern: Pattern,
+    ignore_case: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    /// Lowercased already when ignoring the case.
+    Contains(String),
+    Regex(Regex),
+}
+
+impl TryFrom<&envoy::StringMatcher> for StringMatcher {
+    type Error = MatcherError;
+
+    fn try_from(matcher: &envoy::StringMatcher) -> Result<Self, Self::Error> {
+        use StringMatcher_oneof_match_pattern as MatchPattern;
+        let non_empty = |pattern: &String| {
+            if pattern.is_empty() {
+                Err(MatcherError::EmptyPattern)
+            } else if matcher.ignore_case {
+                Ok(pattern.to_ascii_lowercase())
+            } else {
+                Ok(pattern.clone())
+            }
+        };
+        let pattern = match &matcher.match_pattern {
+            None => return Err(MatcherError::MissingPattern),
+            Some(MatchPattern::exact(exact)) => Pattern::Exact(exact.clone()),
+            Some(MatchPattern::prefix(prefix)) => Pattern::Prefix(non_empty(prefix)?),
+            Some(MatchPattern::suffix(suffix)) => Pattern::Suffix(non_empty(suffix)?),
+            Some(MatchPattern::contains(contains)) => Pattern::Contains(non_empty(contains)?),
+            Some(MatchPattern::safe_regex(regex)) => Pattern::Regex(full_match_regex(regex)?),
+        };
+        Ok(Self {
+            pattern,
+            ignore_case: matcher.ignore_case,
+        })
+    }
+}
+
+impl StringMatcher {
+    pub fn matches(&self, value: &str) -> bool {
+        let eq = |lhs: &str, rhs: &str| {
+            if self.ignore_case {
+                lhs.eq_ignore_ascii_case(rhs)
+            } else {
+                lhs == rhs
+            }
+        };
+        match &self.pattern {
+            Pattern::Exact(exact) => eq(value, exact),
+            Pattern::Prefix(prefix) => value.get(..prefix.len()).is_some_and(|v| eq(v, prefix)),
+            Pattern::Suffix(suffix) => value
+                .len()
+                .checked_sub(suffix.len())
+                .and_then(|at| value.get(at..))
+                .is_some_and(|v| eq(v, suffix)),
+            Pattern::Contains(contains) if self.ignore_case => {
+                value.to_ascii_lowercase().contains(contains.as_str())
+            }
+            Pattern::Contains(contains) => value.contains(contains.as_str()),
+            Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// Matches when any of its patterns does.
+#[derive(Clone, Debug)]
+pub struct ListStringMatcher {
+    patterns: Vec<StringMatcher>,
+}
+
+impl TryFrom<&envoy::ListStringMatcher> for ListStringMatcher {
+    type Error = MatcherError;
+
+    fn try_from(matcher: &envoy::ListStringMatcher) -> Result<Self, Self::Error> {
+        if matcher.patterns.is_empty() {
+            return Err(MatcherError::MissingPattern);
+        }
+        let patterns = matcher
+            .patterns
+            .iter()
+            .map(StringMatcher::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+}
+
+impl ListStringMatcher {
+    pub fn matches(&self, value: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.matches(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::RegexMatcher;
+
+    type MatchPattern = StringMatcher_oneof_match_pattern;
+
+    fn matcher(pattern: MatchPattern, ignore_case: bool) -> StringMatcher {
+        StringMatcher::try_from(&envoy::StringMatcher {
+            match_pattern: Some(pattern),
+            ignore_case,
+            ..Default::default()
+        })
+        .expect("valid matcher")
+    }
+
+    fn regex(regex: &str) -> MatchPattern {
+        MatchPattern::safe_regex(RegexMatcher {
+            regex: regex.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn it_matches_as_envoy_does() {
+        let cases = [
+            (MatchPattern::exact("foo".to_string()), "foo", true, true),
+            (MatchPattern::exact("foo".to_string()), "FOO", false, true),
+            (
+                MatchPattern::exact("foo".to_string()),
+                "foobar",
+                false,
+                false,
+            ),
+            (MatchPattern::exact(String::new()), "", true, true),
+            (MatchPattern::exact(String::new()), "foo", false, false),
+            (
+                MatchPattern::prefix("foo".to_string()),
+                "foobar",
+                true,
+                true,
+            ),
+            (
+                MatchPattern::prefix("foo".to_string()),
+                "FOObar",
+                false,
+                true,
+            ),
+            (MatchPattern::prefix("foo".to_string()), "fo", false, false),
+            (
+                MatchPattern::prefix("foo".to_string()),
+                "barfoo",
+                false,
+                false,
+            ),
+            (
+                MatchPattern::suffix("bar".to_string()),
+                "foobar",
+                true,
+                true,
+            ),
+            (
+                MatchPattern::suffix("bar".to_string()),
+                "fooBAR",
+                false,
+                true,
+            ),
+            (MatchPattern::suffix("bar".to_string()), "ar", false, false),
+            (
+                MatchPattern::contains("oba".to_string()),
+                "foobar",
+                true,
+                true,
+            ),
+            (
+                MatchPattern::contains("oba".to_string()),
+                "FOOBAR",
+                false,
+                true,
+            ),
+            (
+                MatchPattern::contains("oba".to_string()),
+                "foo",
+                false,
+                false,
+            ),
+            (regex("fo+"), "fooo", true, true),
+            (regex("fo+"), "foobar", false, false),
+            (regex("fo+"), "FOO", false, false),
+            (regex("(?i)fo+"), "FOO", true, true),
+        ];
+        for (pattern, value, expected, ignoring_case) in cases {
+            let description = format!("{pattern:?} on {value:?}");
+            assert_eq!(
+                matcher(pattern.clone(), false).matches(value),
+                expected,
+                "{description}"
+            );
+            assert_eq!(
+                matcher(pattern, true).matches(value),
+                ignoring_case,
+                "{description}, ignoring case"
+            );
+        }
+    }
+
+    #[test]
+    fn it_only_folds_ascii() {
+        assert!(matcher(MatchPattern::exact("été".to_string()), true).matches("éTé"));
+        assert!(!matcher(MatchPattern::exact("été".to_string()), true).matches("ÉTÉ"));
+        assert!(matcher(MatchPattern::prefix("é".to_string()), false).matches("été"));
+        assert!(!matcher(MatchPattern::prefix("e".to_string()), false).matches("été"));
+        assert!(!matcher(MatchPattern::suffix("e".to_string()), true).matches("é"));
+        assert!(matcher(MatchPattern::contains("T".to_string()), true).matches("été"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_matchers() {
+        let error = |pattern: Option<MatchPattern>| {
+            StringMatcher::try_from(&envoy::StringMatcher {
+                match_pattern: pattern,
+                ..Default::default()
+            })
+            .err()
+        };
+        assert_eq!(error(None), Some(MatcherError::MissingPattern));
+        assert_eq!(
+            error(Some(MatchPattern::prefix(String::new()))),
+            Some(MatcherError::EmptyPattern)
+        );
+        assert_eq!(
+            error(Some(MatchPattern::contains(String::new()))),
+            Some(MatcherError::EmptyPattern)
+        );
+        assert!(matches!(
+            error(Some(regex("fo("))),
+            Some(MatcherError::InvalidRegex { .. })
+        ));
+    }
+
+    #[test]
+    fn it_matches_any_of_a_list() {
+        let list = ListStringMatcher::try_from(&envoy::ListStringMatcher {
+            patterns: vec![
+                envoy::StringMatcher {
+                    match_pattern: Some(MatchPattern::prefix("/api".to_string())),
+                    ..Default::default()
+                },
+                envoy::StringMatcher {
+                    match_pattern: Some(MatchPattern::suffix(".json".to_string())),
+                    ignore_case: true,
+                    ..Default::default()
+                },
+            ]
+            .into(),
+            ..Default::default()
+        })
+        .expect("valid matcher");
+        assert!(list.matches("/api/users"));
+        assert!(list.matches("/users.JSON"));
+        assert!(!list.matches("/users"));
+        assert_eq!(
+            ListStringMatcher::try_from(&envoy::ListStringMatcher::default()).err(),
+            Some(MatcherError::MissingPattern)
+        );
+    }
+}
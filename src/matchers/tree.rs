@@ -0,0 +1,755 @@
+use super::{MatcherError, StringMatcher, any_is};
+use crate::envoy::{
+    Matcher, Matcher_MatcherList_Predicate,
+    Matcher_MatcherList_Predicate_SinglePredicate_oneof_matcher,
+    Matcher_MatcherList_Predicate_oneof_match_type, Matcher_MatcherTree_MatchMap,
+    Matcher_MatcherTree_oneof_tree_type, Matcher_OnMatch, Matcher_OnMatch_oneof_on_match,
+    Matcher_oneof_matcher_type, MetadataKey, TypedExtensionConfig,
+};
+use crate::metadata::MetadataSource;
+use crate::{PendingValue, Phase, ReqRespCtx};
+use protobuf::{CodedInputStream, Message};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+macro_rules! resolved {
+    ($value:expr) => {
+        match $value {
+            PendingValue::Resolved(value) => value,
+            PendingValue::Pending => return PendingValue::Pending,
+        }
+    };
+}
+
+const REQUEST_HEADER_INPUT: &str = "envoy.type.matcher.v3.HttpRequestHeaderMatchInput";
+const QUERY_PARAM_INPUT: &str = "envoy.type.matcher.v3.HttpRequestQueryParamMatchInput";
+const DYNAMIC_METADATA_INPUT: &str =
+    "envoy.extensions.matching.common_inputs.network.v3.DynamicMetadataInput";
+
+/// The data a matcher looks at, e.g. a request header. `None` when the request has no such data,
+/// `Pending` while it may still get it.
+pub trait DataInput {
+    fn get(&self, ctx: &ReqRespCtx) -> PendingValue<Option<String>>;
+}
+
+type InputFactory = Box<dyn Fn(&[u8]) -> Result<Rc<dyn DataInput>, String>>;
+
+/// The data inputs matchers can use, by the type of their `typed_config`. It knows about
+/// `HttpRequestHeaderMatchInput`, which also covers the path and method through the `:path` and
+/// `:method` pseudo headers, `HttpRequestQueryParamMatchInput` and `DynamicMetadataInput`.
+pub struct DataInputRegistry {
+    factories: HashMap<String, InputFactory>,
+}
+
+impl Default for DataInputRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        registry.register(REQUEST_HEADER_INPUT, |config| {
+            let name = string_field(config, 1)?.unwrap_or_default();
+            Ok(Rc::new(RequestHeaderInput(name.to_ascii_lowercase())))
+        });
+        registry.register(QUERY_PARAM_INPUT, |config| {
+            let name = string_field(config, 1)?.unwrap_or_default();
+            Ok(Rc::new(QueryParamInput(name)))
+        });
+        // Encoded as a `MetadataKey`: its `filter` and `path` have the same numbers and types
+        registry.register(DYNAMIC_METADATA_INPUT, |config| {
+            MetadataKey::parse_from_bytes(config)
+                .map(|key| Rc::new(DynamicMetadataInput(key)) as Rc<dyn DataInput>)
+                .map_err(|e| e.to_string())
+        });
+        registry
+    }
+}
+
+impl DataInputRegistry {
+    /// Registers the factory of the inputs whose `typed_config` is of `type_name`, e.g.
+    /// `envoy.type.matcher.v3.HttpRequestHeaderMatchInput`. It's given the encoded config.
+    pub fn register<F>(&mut self, type_name: &str, factory: F)
+    where
+        F: Fn(&[u8]) -> Result<Rc<dyn DataInput>, String> + 'static,
+    {
+        self.factories
+            .insert(type_name.to_string(), Box::new(factory));
+    }
+
+    fn input(&self, config: &TypedExtensionConfig) -> Result<Rc<dyn DataInput>, MatcherError> {
+        let any = config.typed_config.get_ref();
+        // Type URLs are `type.googleapis.com/<type name>`, only the type name matters
+        let type_name = any.type_url.rsplit('/').next().unwrap_or_default();
+        let factory = self
+            .factories
+            .get(type_name)
+            .ok_or_else(|| MatcherError::UnknownExtension(any.type_url.clone()))?;
+        factory(&any.value).map_err(|message| MatcherError::InvalidExtension {
+            type_url: any.type_url.clone(),
+            message,
+        })
+    }
+}
+
+/// The value of the string field `number` in an encoded message, the last one if repeated.
+fn string_field(message: &[u8], number: u32) -> Result<Option<String>, String> {
+    let mut input = CodedInputStream::from_bytes(message);
+    let mut value = None;
+    while !input.eof().map_err(|e| e.to_string())? {
+        let (field, wire_type) = input.read_tag_unpack().map_err(|e| e.to_string())?;
+        if field == number {
+            value = Some(input.read_string().map_err(|e| e.to_string())?);
+        } else {
+            input.skip_field(wire_type).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(value)
+}
+
+/// All the values of the header, joined with a `,`.
+struct RequestHeaderInput(String);
+
+impl DataInput for RequestHeaderInput {
+    fn get(&self, ctx: &ReqRespCtx) -> PendingValue<Option<String>> {
+        if ctx
+            .phase()
+            .is_none_or(|phase| phase < Phase::RequestHeaders)
+        {
+            return PendingValue::Pending;
+        }
+        let headers = ctx.host.request_headers();
+        let values: Vec<_> = headers.get_all(&self.0).collect();
+        PendingValue::Resolved((!values.is_empty()).then(|| values.join(",")))
+    }
+}
+
+/// The first value of the query parameter, decoded.
+struct QueryParamInput(String);
+
+impl DataInput for QueryParamInput {
+    fn get(&self, ctx: &ReqRespCtx) -> PendingValue<Option<String>> {
+        if ctx
+            .phase()
+            .is_none_or(|phase| phase < Phase::RequestHeaders)
+        {
+            return PendingValue::Pending;
+        }
+        PendingValue::Resolved(ctx.query_params().get(&self.0).map(str::to_string))
+    }
+}
+
+/// The value at the key in the dynamic metadata, rendered as by Envoy.
+struct DynamicMetadataInput(MetadataKey);
+
+impl DataInput for DynamicMetadataInput {
+    fn get(&self, ctx: &ReqRespCtx) -> PendingValue<Option<String>> {
+        PendingValue::Resolved(
+            MetadataSource::Request
+                .resolve(ctx, &self.0)
+                .ok()
+                .map(|value| value.render()),
+        )
+    }
+}
+
+/// A compiled unified `Matcher`, selecting an action of type `A` for a request.
+///
+/// A matcher list picks the first field matcher whose predicate holds, and a matcher tree the
+/// entry for its input's value, the longest matching prefix first for a prefix map. When that
+/// leads to a nested matcher that doesn't match, the next candidate is tried, and then
+/// `on_no_match`. A predicate evaluated on data that's still pending makes the whole matcher
+/// pending, as a later candidate can't be picked before knowing this one doesn't match.
+pub struct MatchTree<A> {
+    kind: Kind<A>,
+    on_no_match: Option<OnMatch<A>>,
+}
+
+enum Kind<A> {
+    List(Vec<(Predicate, OnMatch<A>)>),
+    Exact(Rc<dyn DataInput>, HashMap<String, OnMatch<A>>),
+    /// Sorted from the longest prefix to the shortest.
+    Prefix(Rc<dyn DataInput>, Vec<(String, OnMatch<A>)>),
+}
+
+enum OnMatch<A> {
+    Action(A),
+    Matcher(Box<MatchTree<A>>),
+}
+
+enum Predicate {
+    Single(Rc<dyn DataInput>, StringMatcher),
+    Or(Vec<Predicate>),
+    And(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+/// Turns the `action` of an `on_match` into the one selected.
+pub type ActionFactory<'a, A> = dyn FnMut(&TypedExtensionConfig) -> Result<A, MatcherError> + 'a;
+
+impl<A> MatchTree<A> {
+    pub fn compile(
+        matcher: &Matcher,
+        registry: &DataInputRegistry,
+        action: &mut ActionFactory<A>,
+    ) -> Result<Self, MatcherError> {
+        use Matcher_MatcherTree_oneof_tree_type as TreeType;
+        let kind = match &matcher.matcher_type {
+            None => return Err(MatcherError::MissingField("matcher_type")),
+            Some(Matcher_oneof_matcher_type::matcher_list(list)) => Kind::List(
+                list.matchers
+                    .iter()
+                    .map(|field| {
+                        let predicate = field
+                            .predicate
+                            .as_ref()
+                            .ok_or(MatcherError::MissingField("predicate"))?;
+                        let on_match = field
+                            .on_match
+                            .as_ref()
+                            .ok_or(MatcherError::MissingField("on_match"))?;
+                        Ok((
+                            Predicate::compile(predicate, registry)?,
+                            OnMatch::compile(on_match, registry, action)?,
+                        ))
+                    })
+                    .collect::<Result<_, MatcherError>>()?,
+            ),
+            Some(Matcher_oneof_matcher_type::matcher_tree(tree)) => {
+                let input = registry.input(
+                    tree.input
+                        .as_ref()
+                        .ok_or(MatcherError::MissingField("input"))?,
+                )?;
+                let mut map = |map: &Matcher_MatcherTree_MatchMap| {
+                    map.map
+                        .iter()
+                        .map(|(key, on_match)| {
+                            Ok((key.clone(), OnMatch::compile(on_match, registry, action)?))
+                        })
+                        .collect::<Result<Vec<_>, MatcherError>>()
+                };
+                match &tree.tree_type {
+                    None => return Err(MatcherError::MissingField("tree_type")),
+                    Some(TreeType::exact_match_map(exact)) => {
+                        Kind::Exact(input, map(exact)?.into_iter().collect())
+                    }
+                    Some(TreeType::prefix_match_map(prefix)) => {
+                        let mut entries = map(prefix)?;
+                        entries.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+                        Kind::Prefix(input, entries)
+                    }
+                    Some(TreeType::custom_match(custom)) => {
+                        return Err(MatcherError::UnknownExtension(custom.name.clone()));
+                    }
+                }
+            }
+        };
+        let on_no_match = matcher
+            .on_no_match
+            .as_ref()
+            .map(|on_match| OnMatch::compile(on_match, registry, action))
+            .transpose()?;
+        Ok(Self { kind, on_no_match })
+    }
+
+    /// The action selected for the request, `None` when nothing matches.
+    pub fn evaluate(&self, ctx: &ReqRespCtx) -> PendingValue<Option<&A>> {
+        let matched = match &self.kind {
+            Kind::List(fields) => {
+                let mut matched = None;
+                for (predicate, on_match) in fields {
+                    match predicate.eval(ctx) {
+                        PendingValue::Pending => return PendingValue::Pending,
+                        PendingValue::Resolved(false) => continue,
+                        PendingValue::Resolved(true) => {}
+                    }
+                    if let Some(action) = resolved!(on_match.evaluate(ctx)) {
+                        matched = Some(action);
+                        break;
+                    }
+                }
+                matched
+            }
+            Kind::Exact(input, map) => match resolved!(input.get(ctx)) {
+                Some(value) => match map.get(&value) {
+                    Some(on_match) => resolved!(on_match.evaluate(ctx)),
+                    None => None,
+                },
+                None => None,
+            },
+            Kind::Prefix(input, entries) => match resolved!(input.get(ctx)) {
+                Some(value) => {
+                    let mut matched = None;
+                    for (_, on_match) in entries
+                        .iter()
+                        .filter(|(prefix, _)| value.starts_with(prefix.as_str()))
+                    {
+                        if let Some(action) = resolved!(on_match.evaluate(ctx)) {
+                            matched = Some(action);
+                            break;
+                        }
+                    }
+                    matched
+                }
+                None => None,
+            },
+        };
+        match (matched, &self.on_no_match) {
+            (Some(action), _) => PendingValue::Resolved(Some(action)),
+            (None, Some(on_no_match)) => on_no_match.evaluate(ctx),
+            (None, None) => PendingValue::Resolved(None),
+        }
+    }
+}
+
+impl<A> OnMatch<A> {
+    fn compile(
+        on_match: &Matcher_OnMatch,
+        registry: &DataInputRegistry,
+        action: &mut ActionFactory<A>,
+    ) -> Result<Self, MatcherError> {
+        match &on_match.on_match {
+            None => Err(MatcherError::MissingField("on_match")),
+            Some(Matcher_OnMatch_oneof_on_match::action(config)) => {
+                action(config).map(OnMatch::Action)
+            }
+            Some(Matcher_OnMatch_oneof_on_match::matcher(matcher)) => {
+                MatchTree::compile(matcher, registry, action)
+                    .map(|matcher| OnMatch::Matcher(Box::new(matcher)))
+            }
+        }
+    }
+
+    fn evaluate(&self, ctx: &ReqRespCtx) -> PendingValue<Option<&A>> {
+        match self {
+            OnMatch::Action(action) => PendingValue::Resolved(Some(action)),
+            OnMatch::Matcher(matcher) => matcher.evaluate(ctx),
+        }
+    }
+}
+
+impl Predicate {
+    fn compile(
+        predicate: &Matcher_MatcherList_Predicate,
+        registry: &DataInputRegistry,
+    ) -> Result<Self, MatcherError> {
+        use Matcher_MatcherList_Predicate_SinglePredicate_oneof_matcher as SingleMatcher;
+        use Matcher_MatcherList_Predicate_oneof_match_type as MatchType;
+        let all = |predicates: &[Matcher_MatcherList_Predicate]| {
+            predicates
+                .iter()
+                .map(|predicate| Predicate::compile(predicate, registry))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        match &predicate.match_type {
+            None => Err(MatcherError::MissingField("match_type")),
+            Some(MatchType::single_predicate(single)) => {
+                let input = registry.input(
+                    single
+                        .input
+                        .as_ref()
+                        .ok_or(MatcherError::MissingField("input"))?,
+                )?;
+                match &single.matcher {
+                    None => Err(MatcherError::MissingField("matcher")),
+                    Some(SingleMatcher::value_match(matcher)) => {
+                        Ok(Predicate::Single(input, StringMatcher::try_from(matcher)?))
+                    }
+                    Some(SingleMatcher::custom_match(custom)) => {
+                        Err(MatcherError::UnknownExtension(custom.name.clone()))
+                    }
+                }
+            }
+            Some(MatchType::or_matcher(list)) => all(&list.predicate).map(Predicate::Or),
+            Some(MatchType::and_matcher(list)) => all(&list.predicate).map(Predicate::And),
+            Some(MatchType::not_matcher(predicate)) => {
+                Predicate::compile(predicate, registry).map(|p| Predicate::Not(Box::new(p)))
+            }
+        }
+    }
+
+    /// Whether the predicate holds, pending only when data still to come could change that.
+    fn eval(&self, ctx: &ReqRespCtx) -> PendingValue<bool> {
+        match self {
+            Predicate::Single(input, matcher) => match input.get(ctx) {
+                PendingValue::Resolved(value) => {
+                    PendingValue::Resolved(value.is_some_and(|value| matcher.matches(&value)))
+                }
+                PendingValue::Pending => PendingValue::Pending,
+            },
+            Predicate::Or(predicates) => any_is(predicates.iter().map(|p| p.eval(ctx)), true),
+            Predicate::And(predicates) => any_is(predicates.iter().map(|p| p.eval(ctx)), false),
+            Predicate::Not(predicate) => match predicate.eval(ctx) {
+                PendingValue::Resolved(holds) => PendingValue::Resolved(!holds),
+                PendingValue::Pending => PendingValue::Pending,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{
+        Matcher_MatcherList, Matcher_MatcherList_FieldMatcher,
+        Matcher_MatcherList_Predicate_PredicateList, Matcher_MatcherList_Predicate_SinglePredicate,
+        Matcher_MatcherTree, MetadataKey_PathSegment, MetadataKey_PathSegment_oneof_segment,
+        StringMatcher_oneof_match_pattern,
+    };
+    use crate::host::MockHost;
+    use protobuf::well_known_types::{Any, Struct, Value};
+    use protobuf::{CodedOutputStream, SingularPtrField};
+
+    type MatchType = Matcher_MatcherList_Predicate_oneof_match_type;
+
+    fn input(type_name: &str, config: Vec<u8>) -> SingularPtrField<TypedExtensionConfig> {
+        SingularPtrField::some(TypedExtensionConfig {
+            name: "input".to_string(),
+            typed_config: SingularPtrField::some(Any {
+                type_url: format!("type.googleapis.com/{type_name}"),
+                value: config,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn named_input(type_name: &str, name: &str) -> SingularPtrField<TypedExtensionConfig> {
+        let mut config = Vec::new();
+        let mut output = CodedOutputStream::vec(&mut config);
+        output.write_string(1, name).unwrap();
+        output.flush().unwrap();
+        drop(output);
+        input(type_name, config)
+    }
+
+    fn header(name: &str) -> SingularPtrField<TypedExtensionConfig> {
+        named_input(REQUEST_HEADER_INPUT, name)
+    }
+
+    fn single(
+        input: SingularPtrField<TypedExtensionConfig>,
+        pattern: StringMatcher_oneof_match_pattern,
+    ) -> Matcher_MatcherList_Predicate {
+        Matcher_MatcherList_Predicate {
+            match_type: Some(MatchType::single_predicate(
+                Matcher_MatcherList_Predicate_SinglePredicate {
+                    input,
+                    matcher: Some(
+                        Matcher_MatcherList_Predicate_SinglePredicate_oneof_matcher::value_match(
+                            crate::envoy::StringMatcher {
+                                match_pattern: Some(pattern),
+                                ..Default::default()
+                            },
+                        ),
+                    ),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn exact(value: &str) -> StringMatcher_oneof_match_pattern {
+        StringMatcher_oneof_match_pattern::exact(value.to_string())
+    }
+
+    fn action(name: &str) -> Matcher_OnMatch {
+        Matcher_OnMatch {
+            on_match: Some(Matcher_OnMatch_oneof_on_match::action(
+                TypedExtensionConfig {
+                    name: name.to_string(),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn nested(matcher: Matcher) -> Matcher_OnMatch {
+        Matcher_OnMatch {
+            on_match: Some(Matcher_OnMatch_oneof_on_match::matcher(matcher)),
+            ..Default::default()
+        }
+    }
+
+    fn list(fields: Vec<(Matcher_MatcherList_Predicate, Matcher_OnMatch)>) -> Matcher {
+        Matcher {
+            matcher_type: Some(Matcher_oneof_matcher_type::matcher_list(
+                Matcher_MatcherList {
+                    matchers: fields
+                        .into_iter()
+                        .map(|(predicate, on_match)| Matcher_MatcherList_FieldMatcher {
+                            predicate: SingularPtrField::some(predicate),
+                            on_match: SingularPtrField::some(on_match),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn tree(
+        input: SingularPtrField<TypedExtensionConfig>,
+        prefix: bool,
+        entries: Vec<(&str, Matcher_OnMatch)>,
+    ) -> Matcher {
+        let map = Matcher_MatcherTree_MatchMap {
+            map: entries
+                .into_iter()
+                .map(|(key, on_match)| (key.to_string(), on_match))
+                .collect(),
+            ..Default::default()
+        };
+        Matcher {
+            matcher_type: Some(Matcher_oneof_matcher_type::matcher_tree(
+                Matcher_MatcherTree {
+                    input,
+                    tree_type: Some(if prefix {
+                        Matcher_MatcherTree_oneof_tree_type::prefix_match_map(map)
+                    } else {
+                        Matcher_MatcherTree_oneof_tree_type::exact_match_map(map)
+                    }),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn compile(matcher: &Matcher) -> Result<MatchTree<String>, MatcherError> {
+        MatchTree::compile(matcher, &DataInputRegistry::default(), &mut |action| {
+            Ok(action.name.clone())
+        })
+    }
+
+    fn evaluate(matcher: &MatchTree<String>, headers: Vec<(&str, &str)>) -> Option<String> {
+        let host = MockHost::default()
+            .with_request_headers(headers)
+            .with_phase(Phase::RequestHeaders);
+        match matcher.evaluate(&ReqRespCtx::new(Box::new(host))) {
+            PendingValue::Resolved(action) => action.cloned(),
+            PendingValue::Pending => panic!("the request headers are known"),
+        }
+    }
+
+    #[test]
+    fn it_selects_the_first_matching_field() {
+        let mut matcher = list(vec![
+            (single(header("X-Tier"), exact("gold")), action("gold")),
+            (
+                Matcher_MatcherList_Predicate {
+                    match_type: Some(MatchType::and_matcher(
+                        Matcher_MatcherList_Predicate_PredicateList {
+                            predicate: vec![
+                                single(header(":method"), exact("POST")),
+                                single(
+                                    header(":path"),
+                                    StringMatcher_oneof_match_pattern::prefix("/api".to_string()),
+                                ),
+                            ]
+                            .into(),
+                            ..Default::default()
+                        },
+                    )),
+                    ..Default::default()
+                },
+                action("api"),
+            ),
+            (
+                Matcher_MatcherList_Predicate {
+                    match_type: Some(MatchType::not_matcher(Box::new(single(
+                        header(":method"),
+                        exact("GET"),
+                    )))),
+                    ..Default::default()
+                },
+                action("writes"),
+            ),
+        ]);
+        let compiled = compile(&matcher).unwrap();
+        let get = (":method", "GET");
+        let post = (":method", "POST");
+
+        assert_eq!(
+            evaluate(&compiled, vec![post, ("x-tier", "gold")]),
+            Some("gold".to_string())
+        );
+        assert_eq!(
+            evaluate(&compiled, vec![post, (":path", "/api/users")]),
+            Some("api".to_string())
+        );
+        assert_eq!(
+            evaluate(&compiled, vec![post, (":path", "/")]),
+            Some("writes".to_string())
+        );
+        assert_eq!(evaluate(&compiled, vec![get, (":path", "/api")]), None);
+
+        matcher.on_no_match = SingularPtrField::some(action("default"));
+        let compiled = compile(&matcher).unwrap();
+        assert_eq!(evaluate(&compiled, vec![get]), Some("default".to_string()));
+        let ctx = ReqRespCtx::new(Box::new(MockHost::default()));
+        assert_eq!(compiled.evaluate(&ctx), PendingValue::Pending);
+    }
+
+    #[test]
+    fn it_looks_up_trees() {
+        let by_method = compile(&tree(
+            header(":method"),
+            false,
+            vec![("GET", action("reads")), ("POST", action("writes"))],
+        ))
+        .unwrap();
+        assert_eq!(
+            evaluate(&by_method, vec![(":method", "POST")]),
+            Some("writes".to_string())
+        );
+        assert_eq!(evaluate(&by_method, vec![(":method", "PUT")]), None);
+        assert_eq!(evaluate(&by_method, vec![]), None);
+
+        let v2_writes = tree(header(":method"), false, vec![("POST", action("v2"))]);
+        let by_path = compile(&tree(
+            header(":path"),
+            true,
+            vec![
+                ("/api", action("api")),
+                ("/api/v2", nested(v2_writes)),
+                ("/", action("root")),
+            ],
+        ))
+        .unwrap();
+        let path = |method, path| evaluate(&by_path, vec![(":method", method), (":path", path)]);
+        assert_eq!(path("POST", "/api/v2/users"), Some("v2".to_string()));
+        assert_eq!(
+            path("GET", "/api/v2/users"),
+            Some("api".to_string()),
+            "falls back to a shorter prefix"
+        );
+        assert_eq!(path("GET", "/other"), Some("root".to_string()));
+    }
+
+    #[test]
+    fn it_reads_dynamic_metadata() {
+        let key = MetadataKey {
+            key: "envoy.filters.http.ext_authz".to_string(),
+            path: vec![MetadataKey_PathSegment {
+                segment: Some(MetadataKey_PathSegment_oneof_segment::key(
+                    "tier".to_string(),
+                )),
+                ..Default::default()
+            }]
+            .into(),
+            ..Default::default()
+        };
+        let matcher = compile(&tree(
+            input(DYNAMIC_METADATA_INPUT, key.write_to_bytes().unwrap()),
+            false,
+            vec![("gold", action("gold"))],
+        ))
+        .unwrap();
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(matcher.evaluate(&ctx), PendingValue::Resolved(None));
+
+        let mut tier = Value::new();
+        tier.set_string_value("gold".to_string());
+        let mut metadata = Struct::new();
+        metadata.fields.insert("tier".to_string(), tier);
+        ctx.dynamic_metadata
+            .filter_metadata
+            .insert("envoy.filters.http.ext_authz".to_string(), metadata);
+        assert_eq!(
+            matcher.evaluate(&ctx),
+            PendingValue::Resolved(Some(&"gold".to_string()))
+        );
+    }
+
+    #[test]
+    fn it_reads_query_params() {
+        let matcher = compile(&tree(
+            named_input(QUERY_PARAM_INPUT, "feature"),
+            false,
+            vec![("new ui", action("beta"))],
+        ))
+        .unwrap();
+        assert_eq!(
+            evaluate(&matcher, vec![(":path", "/?feature=new%20ui&feature=old")]),
+            Some("beta".to_string())
+        );
+        assert_eq!(
+            evaluate(&matcher, vec![(":path", "/?feature=old&feature=new+ui")]),
+            None
+        );
+        assert_eq!(evaluate(&matcher, vec![(":path", "/")]), None);
+        let ctx = ReqRespCtx::new(Box::new(MockHost::default()));
+        assert_eq!(matcher.evaluate(&ctx), PendingValue::Pending);
+    }
+
+    #[test]
+    fn it_rejects_invalid_matchers() {
+        let error = |matcher: &Matcher| compile(matcher).err().map(|e| e.to_string());
+        assert_eq!(
+            error(&Matcher::default()),
+            Some("missing `matcher_type`".to_string())
+        );
+        assert_eq!(
+            error(&tree(
+                input("envoy.type.matcher.v3.HttpResponseHeaderMatchInput", vec![]),
+                false,
+                vec![]
+            )),
+            Some(
+                "unsupported extension \
+                 `type.googleapis.com/envoy.type.matcher.v3.HttpResponseHeaderMatchInput`"
+                    .to_string()
+            )
+        );
+        assert!(
+            error(&tree(
+                input(REQUEST_HEADER_INPUT, vec![0xff]),
+                false,
+                vec![]
+            ))
+            .is_some_and(|e| e.starts_with("invalid `type.googleapis.com/"))
+        );
+        assert_eq!(
+            error(&list(vec![(
+                single(header("x"), exact("")),
+                Matcher_OnMatch::new()
+            )])),
+            Some("missing `on_match`".to_string())
+        );
+    }
+
+    #[test]
+    fn it_uses_registered_inputs() {
+        let mut registry = DataInputRegistry::default();
+        registry.register("example.AlwaysPending", |_| {
+            struct AlwaysPending;
+            impl DataInput for AlwaysPending {
+                fn get(&self, _: &ReqRespCtx) -> PendingValue<Option<String>> {
+                    PendingValue::Pending
+                }
+            }
+            Ok(Rc::new(AlwaysPending))
+        });
+        let matcher = tree(input("example.AlwaysPending", vec![]), false, vec![]);
+        let compiled: MatchTree<()> = MatchTree::compile(&matcher, &registry, &mut |action| {
+            Err(MatcherError::UnknownAction(action.name.clone()))
+        })
+        .unwrap();
+        let ctx = ReqRespCtx::new(Box::new(MockHost::default()));
+        assert_eq!(compiled.evaluate(&ctx), PendingValue::Pending);
+        assert_eq!(
+            MatchTree::<()>::compile(
+                &tree(header("x"), false, vec![("a", action("a"))]),
+                &registry,
+                &mut |action| Err(MatcherError::UnknownAction(action.name.clone()))
+            )
+            .err(),
+            Some(MatcherError::UnknownAction("a".to_string()))
+        );
+    }
+}
@@ -0,0 +1,438 @@
+use crate::ReqRespCtx;
+use crate::envoy::{
+    Metadata, MetadataKey, MetadataKey_PathSegment_oneof_segment, MetadataKind,
+    MetadataKind_oneof_kind, RateLimit_Action_MetaData_Source,
+};
+use protobuf::Message;
+use protobuf::well_known_types::{Value, Value_oneof_kind};
+use std::fmt;
+
+/// The metadata a [`MetadataKey`] is looked up in, as with Envoy's `MetadataKind`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataSource {
+    /// The dynamic metadata of the request.
+    Request,
+    Route,
+    Cluster,
+    /// The upstream host's.
+    Host,
+}
+
+impl TryFrom<&MetadataKind> for MetadataSource {
+    type Error = MetadataError;
+
+    fn try_from(kind: &MetadataKind) -> Result<Self, Self::Error> {
+        match kind.kind {
+            None => Err(MetadataError::MissingKind),
+            Some(MetadataKind_oneof_kind::request(_)) => Ok(MetadataSource::Request),
+            Some(MetadataKind_oneof_kind::route(_)) => Ok(MetadataSource::Route),
+            Some(MetadataKind_oneof_kind::cluster(_)) => Ok(MetadataSource::Cluster),
+            Some(MetadataKind_oneof_kind::host(_)) => Ok(MetadataSource::Host),
+        }
+    }
+}
+
+impl From<RateLimit_Action_MetaData_Source> for MetadataSource {
+    fn from(source: RateLimit_Action_MetaData_Source) -> Self {
+        match source {
+            RateLimit_Action_MetaData_Source::DYNAMIC => MetadataSource::Request,
+            RateLimit_Action_MetaData_Source::ROUTE_ENTRY => MetadataSource::Route,
+        }
+    }
+}
+
+impl MetadataSource {
+    /// The metadata of the source, empty when the host has none. For the request, what the
+    /// pipeline wrote itself takes precedence over what the host knows of.
+    pub fn metadata(self, ctx: &ReqRespCtx) -> Metadata {
+        let property = |path: &[&str]| {
+            ctx.host
+                .get_property(path)
+                .and_then(|bytes| Metadata::parse_from_bytes(&bytes).ok())
+                .unwrap_or_default()
+        };
+        match self {
+            MetadataSource::Request => {
+                let mut metadata = ctx.dynamic_metadata.clone();
+                for (namespace, value) in property(&["metadata"]).filter_metadata {
+                    metadata.filter_metadata.entry(namespace).or_insert(value);
+                }
+                metadata
+            }
+            MetadataSource::Route => property(&["xds", "route_metadata"]),
+            MetadataSource::Cluster => property(&["xds", "cluster_metadata"]),
+            MetadataSource::Host => property(&["xds", "upstream_host_metadata"]),
+        }
+    }
+
+    pub fn resolve(
+        self,
+        ctx: &ReqRespCtx,
+        key: &MetadataKey,
+    ) -> Result<MetadataValue, MetadataError> {
+        resolve(&self.metadata(ctx), key)
+    }
+}
+
+/// The value at `key`, walking its path through the nested structs of the filter's metadata.
+/// Without a path, that's the filter's metadata as a whole.
+pub fn resolve(metadata: &Metadata, key: &MetadataKey) -> Result<MetadataValue, MetadataError> {
+    let filter = metadata
+        .filter_metadata
+        .get(&key.key)
+        .ok_or_else(|| MetadataError::MissingFilter(key.key.clone()))?;
+    let mut path = Vec::with_capacity(key.path.len());
+    let mut value: Option<&Value> = None;
+    for segment in &key.path {
+        let Some(MetadataKey_PathSegment_oneof_segment::key(name)) = &segment.segment else {
+            return Err(MetadataError::MissingSegment);
+        };
+        let fields = match value.map(|value| &value.kind) {
+            None => &filter.fields,
+            Some(Some(Value_oneof_kind::struct_value(fields))) => &fields.fields,
+            Some(kind) => {
+                return Err(MetadataError::TypeMismatch {
+                    filter: key.key.clone(),
+                    path: path.join("."),
+                    expected: "struct",
+                    actual: kind_name(kind),
+                });
+            }
+        };
+        path.push(name.as_str());
+        value = Some(fields.get(name).ok_or_else(|| MetadataError::Missing {
+            filter: key.key.clone(),
+            path: path.join("."),
+        })?);
+    }
+    Ok(MetadataValue {
+        filter: key.key.clone(),
+        path: path.join("."),
+        value: match value {
+            Some(value) => value.clone(),
+            None => Value {
+                kind: Some(Value_oneof_kind::struct_value(filter.clone())),
+                ..Default::default()
+            },
+        },
+    })
+}
+
+fn kind_name(kind: &Option<Value_oneof_kind>) -> &'static str {
+    match kind {
+        None => "unset",
+        Some(Value_oneof_kind::null_value(_)) => "null",
+        Some(Value_oneof_kind::number_value(_)) => "number",
+        Some(Value_oneof_kind::string_value(_)) => "string",
+        Some(Value_oneof_kind::bool_value(_)) => "bool",
+        Some(Value_oneof_kind::struct_value(_)) => "struct",
+        Some(Value_oneof_kind::list_value(_)) => "list",
+    }
+}
+
+/// A value found in metadata, accessed as the type it's expected to be.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetadataValue {
+    filter: String,
+    path: String,
+    value: Value,
+}
+
+impl MetadataValue {
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn into_value(self) -> Value {
+        self.value
+    }
+
+    pub fn as_str(&self) -> Result<&str, MetadataError> {
+        match &self.value.kind {
+            Some(Value_oneof_kind::string_value(value)) => Ok(value),
+            kind => Err(self.mismatch("string", kind)),
+        }
+    }
+
+    /// The value as Envoy formats metadata, e.g. for `%DYNAMIC_METADATA(..)%`: strings as they
+    /// are, anything else as JSON, integral numbers without a fractional part.
+    pub fn render(&self) -> String {
+        match &self.value.kind {
+            Some(Value_oneof_kind::string_value(value)) => value.clone(),
+            _ => json(&self.value).to_string(),
+        }
+    }
+
+    fn mismatch(&self, expected: &'static str, kind: &Option<Value_oneof_kind>) -> MetadataError {
+        MetadataError::TypeMismatch {
+            filter: self.filter.clone(),
+            path: self.path.clone(),
+            expected,
+            actual: kind_name(kind),
+        }
+    }
+}
+
+/// Protobuf's JSON mapping of a `Value`, which spells out non-finite numbers.
+fn json(value: &Value) -> serde_json::Value {
+    match &value.kind {
+        None | Some(Value_oneof_kind::null_value(_)) => serde_json::Value::Null,
+        Some(Value_oneof_kind::number_value(number)) => {
+            // Exactly representable integers, as Envoy prints them
+            if number.fract() == 0.0 && number.abs() < (1u64 << 53) as f64 {
+                serde_json::Value::from(*number as i64)
+            } else if number.is_nan() {
+                serde_json::Value::from("NaN")
+            } else if number.is_infinite() {
+                serde_json::Value::from(if *number > 0.0 {
+                    "Infinity"
+                } else {
+                    "-Infinity"
+                })
+            } else {
+                serde_json::Value::from(*number)
+            }
+        }
+        Some(Value_oneof_kind::string_value(string)) => serde_json::Value::from(string.as_str()),
+        Some(Value_oneof_kind::bool_value(value)) => serde_json::Value::Bool(*value),
+        Some(Value_oneof_kind::struct_value(fields)) => serde_json::Value::Object(
+            fields
+                .fields
+                .iter()
+                .map(|(name, value)| (name.clone(), json(value)))
+                .collect(),
+        ),
+        Some(Value_oneof_kind::list_value(list)) => {
+            serde_json::Value::Array(list.values.iter().map(json).collect())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MetadataError {
+    MissingKind,
+    /// A path segment without its key.
+    MissingSegment,
+    MissingFilter(String),
+    Missing {
+        filter: String,
+        path: String,
+    },
+    TypeMismatch {
+        filter: String,
+        /// Empty for the filter's metadata as a whole.
+        path: String,
+        expected: &'static str,
+        actual: &'static str,
+    },
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::MissingKind => write!(f, "no metadata kind"),
+            MetadataError::MissingSegment => write!(f, "path segment without a key"),
+            MetadataError::MissingFilter(filter) => write!(f, "no metadata for `{filter}`"),
+            MetadataError::Missing { filter, path } => {
+                write!(f, "no `{path}` in the metadata of `{filter}`")
+            }
+            MetadataError::TypeMismatch {
+                filter,
+                path,
+                expected,
+                actual,
+            } if path.is_empty() => write!(
+                f,
+                "expected the metadata of `{filter}` to be a {expected}, got a {actual}"
+            ),
+            MetadataError::TypeMismatch {
+                filter,
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "expected `{path}` in the metadata of `{filter}` to be a {expected}, got a {actual}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{MetadataKey_PathSegment, MetadataKind_Request, MetadataKind_Route};
+    use crate::host::MockHost;
+    use protobuf::well_known_types::{ListValue, NullValue, Struct};
+
+    fn value(kind: Value_oneof_kind) -> Value {
+        Value {
+            kind: Some(kind),
+            ..Default::default()
+        }
+    }
+
+    fn fields(fields: Vec<(&str, Value)>) -> Struct {
+        Struct {
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn key(filter: &str, path: &[&str]) -> MetadataKey {
+        MetadataKey {
+            key: filter.to_string(),
+            path: path
+                .iter()
+                .map(|key| MetadataKey_PathSegment {
+                    segment: Some(MetadataKey_PathSegment_oneof_segment::key(key.to_string())),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn metadata() -> Metadata {
+        let mut metadata = Metadata::default();
+        metadata.filter_metadata.insert(
+            "envoy.filters.http.jwt_authn".to_string(),
+            fields(vec![(
+                "claims",
+                value(Value_oneof_kind::struct_value(fields(vec![
+                    ("sub", value(Value_oneof_kind::string_value("alice".into()))),
+                    ("tier", value(Value_oneof_kind::number_value(7.0))),
+                    ("ratio", value(Value_oneof_kind::number_value(1.5))),
+                    ("admin", value(Value_oneof_kind::bool_value(true))),
+                    (
+                        "groups",
+                        value(Value_oneof_kind::list_value(ListValue {
+                            values: vec![
+                                value(Value_oneof_kind::string_value("a".into())),
+                                value(Value_oneof_kind::number_value(1.0)),
+                            ]
+                            .into(),
+                            ..Default::default()
+                        })),
+                    ),
+                    (
+                        "none",
+                        value(Value_oneof_kind::null_value(NullValue::NULL_VALUE)),
+                    ),
+                ]))),
+            )]),
+        );
+        metadata
+    }
+
+    const JWT: &str = "envoy.filters.http.jwt_authn";
+
+    #[test]
+    fn it_resolves_nested_paths() {
+        let metadata = metadata();
+        let sub = resolve(&metadata, &key(JWT, &["claims", "sub"])).expect("resolves");
+        assert_eq!(sub.as_str(), Ok("alice"));
+        let kind = |path: &[&str]| {
+            resolve(&metadata, &key(JWT, path))
+                .expect("resolves")
+                .into_value()
+                .kind
+        };
+        assert_eq!(
+            kind(&["claims", "tier"]),
+            Some(Value_oneof_kind::number_value(7.0))
+        );
+        assert_eq!(
+            kind(&["claims", "admin"]),
+            Some(Value_oneof_kind::bool_value(true))
+        );
+        assert!(matches!(
+            kind(&[]),
+            Some(Value_oneof_kind::struct_value(filter)) if filter.fields.contains_key("claims")
+        ));
+
+        assert_eq!(
+            resolve(&metadata, &key("other", &["claims"])),
+            Err(MetadataError::MissingFilter("other".to_string()))
+        );
+        let missing = resolve(&metadata, &key(JWT, &["claims", "aud"])).unwrap_err();
+        assert_eq!(
+            missing.to_string(),
+            "no `claims.aud` in the metadata of `envoy.filters.http.jwt_authn`"
+        );
+        let mismatch = resolve(&metadata, &key(JWT, &["claims", "sub", "name"])).unwrap_err();
+        assert_eq!(
+            mismatch.to_string(),
+            "expected `claims.sub` in the metadata of `envoy.filters.http.jwt_authn` to be a \
+             struct, got a string"
+        );
+        assert_eq!(
+            resolve(&metadata, &key(JWT, &["claims", "tier"])).and_then(|v| v.as_str().map(drop)),
+            Err(MetadataError::TypeMismatch {
+                filter: JWT.to_string(),
+                path: "claims.tier".to_string(),
+                expected: "string",
+                actual: "number",
+            })
+        );
+    }
+
+    #[test]
+    fn it_renders_values_as_envoy_does() {
+        let metadata = metadata();
+        let render = |name| {
+            resolve(&metadata, &key(JWT, &["claims", name]))
+                .expect("resolves")
+                .render()
+        };
+        assert_eq!(render("sub"), "alice");
+        assert_eq!(render("tier"), "7");
+        assert_eq!(render("ratio"), "1.5");
+        assert_eq!(render("admin"), "true");
+        assert_eq!(render("groups"), r#"["a",1]"#);
+        assert_eq!(render("none"), "null");
+    }
+
+    #[test]
+    fn it_reads_metadata_from_its_source() {
+        let route = MetadataSource::try_from(&MetadataKind {
+            kind: Some(MetadataKind_oneof_kind::route(MetadataKind_Route::default())),
+            ..Default::default()
+        })
+        .expect("known kind");
+        assert_eq!(route, MetadataSource::Route);
+        assert_eq!(
+            MetadataSource::try_from(&MetadataKind::default()),
+            Err(MetadataError::MissingKind)
+        );
+
+        let host = MockHost::default().with_property(
+            &["xds", "route_metadata"],
+            metadata().write_to_bytes().expect("serializable"),
+        );
+        let ctx = ReqRespCtx::new(Box::new(host));
+        assert_eq!(
+            route
+                .resolve(&ctx, &key(JWT, &["claims", "sub"]))
+                .map(|value| value.render()),
+            Ok("alice".to_string())
+        );
+
+        let request = MetadataSource::try_from(&MetadataKind {
+            kind: Some(MetadataKind_oneof_kind::request(
+                MetadataKind_Request::default(),
+            )),
+            ..Default::default()
+        })
+        .expect("known kind");
+        assert_eq!(
+            request.resolve(&ctx, &key(JWT, &["claims", "sub"])),
+            Err(MetadataError::MissingFilter(JWT.to_string()))
+        );
+    }
+}
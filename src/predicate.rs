@@ -0,0 +1,862 @@
+use crate::attributes::AttributeValue;
+use crate::matchers::{MatchPredicate, MetadataMatcher};
+use crate::metadata::MetadataSource;
+use crate::{PendingValue, ReqRespCtx};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// A boolean expression over request attributes, e.g.
+/// `request.method == "GET" && request.path.startsWith("/api")`.
+///
+/// Expressions are compiled once, when the configuration is loaded, and evaluated against
+/// [`ReqRespCtx::get_attribute`] for every request. They support `true`/`false`/`null`, integer,
+/// double, string and list literals, attribute paths (`request.headers["x-api-key"]`), `!`, `&&`, `||`,
+/// the comparison operators, `in`, and the `startsWith`, `endsWith`, `contains` and `size`
+/// methods. Attributes keep their type, so `response.code == 200` compares integers, while ints
+/// and doubles compare numerically.
+#[derive(Clone, Debug)]
+pub struct Predicate {
+    expr: Expr,
+}
+
+impl Predicate {
+    pub fn new(source: &str) -> Result<Self, PredicateError> {
+        let tokens = Lexer::new(source).tokenize()?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.len(),
+        };
+        let expr = parser.expression()?;
+        match parser.peek() {
+            None => Ok(Self { expr }),
+            Some((position, token)) => Err(PredicateError::new(
+                *position,
+                format!("unexpected {token}"),
+            )),
+        }
+    }
+
+    /// Holds when all of `predicates` do, or when there are none.
+    pub fn all(predicates: Vec<Predicate>) -> Self {
+        let expr = predicates
+            .into_iter()
+            .map(|predicate| predicate.expr)
+            .reduce(|lhs, rhs| Expr::And(Box::new(lhs), Box::new(rhs)))
+            .unwrap_or(Expr::Literal(Value::Bool(true)));
+        Self { expr }
+    }
+
+    /// Resolves to `false` when the expression doesn't evaluate to a boolean, e.g. when
+    /// comparing values of different types with `<`, and to `Pending` when the attributes
+    /// needed to decide aren't available in the current phase yet.
+    pub fn eval(&self, ctx: &ReqRespCtx) -> PendingValue<bool> {
+        match self.expr.eval(ctx) {
+            Ok(PendingValue::Resolved(Value::Bool(result))) => PendingValue::Resolved(result),
+            Ok(PendingValue::Pending) => PendingValue::Pending,
+            Ok(PendingValue::Resolved(_)) | Err(_) => PendingValue::Resolved(false),
+        }
+    }
+
+    /// The attributes the expression reads, as dotted paths, e.g. `request.headers.x-api-key`.
+    pub fn attributes(&self) -> Vec<String> {
+        let mut attributes = Vec::new();
+        self.expr.attributes(&mut attributes);
+        attributes
+    }
+}
+
+/// Holds when the `MatchPredicate` matches, e.g. to combine body matches with expressions
+/// through [`Predicate::all`].
+impl From<MatchPredicate> for Predicate {
+    fn from(predicate: MatchPredicate) -> Self {
+        Self {
+            expr: Expr::Match(predicate),
+        }
+    }
+}
+
+/// Holds when the `MetadataMatcher` matches the dynamic metadata, e.g. what an authorization
+/// service responded with.
+impl From<MetadataMatcher> for Predicate {
+    fn from(matcher: MetadataMatcher) -> Self {
+        Self {
+            expr: Expr::Metadata(matcher),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PredicateError {
+    position: usize,
+    message: String,
+}
+
+impl PredicateError {
+    fn new(position: usize, message: String) -> Self {
+        Self { position, message }
+    }
+}
+
+impl fmt::Display for PredicateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for PredicateError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Double(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Timestamp(SystemTime),
+    Duration(Duration),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl From<AttributeValue> for Value {
+    fn from(value: AttributeValue) -> Self {
+        match value {
+            AttributeValue::String(value) => Value::String(value),
+            AttributeValue::Int(value) => Value::Int(value),
+            AttributeValue::Double(value) => Value::Double(value),
+            AttributeValue::Bool(value) => Value::Bool(value),
+            AttributeValue::Bytes(value) => Value::Bytes(value),
+            AttributeValue::Timestamp(value) => Value::Timestamp(value),
+            AttributeValue::Duration(value) => Value::Duration(value),
+            AttributeValue::Map(entries) => Value::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            ),
+            AttributeValue::List(items) => {
+                Value::List(items.into_iter().map(Value::from).collect())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(_) => write!(f, "bool"),
+            Value::Int(_) => write!(f, "int"),
+            Value::Double(_) => write!(f, "double"),
+            Value::String(_) => write!(f, "string"),
+            Value::Bytes(_) => write!(f, "bytes"),
+            Value::Timestamp(_) => write!(f, "timestamp"),
+            Value::Duration(_) => write!(f, "duration"),
+            Value::List(_) => write!(f, "list"),
+            Value::Map(_) => write!(f, "map"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Method {
+    StartsWith,
+    EndsWith,
+    Contains,
+    Size,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    List(Vec<Expr>),
+    Attribute(Vec<String>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Box<Expr>),
+    Call(Method, Box<Expr>, Vec<Expr>),
+    Match(MatchPredicate),
+    Metadata(MetadataMatcher),
+}
+
+type EvalResult = Result<PendingValue<Value>, String>;
+
+macro_rules! resolved {
+    ($value:expr) => {
+        match $value? {
+            PendingValue::Resolved(value) => value,
+            PendingValue::Pending => return Ok(PendingValue::Pending),
+        }
+    };
+}
+
+impl Expr {
+    fn eval(&self, ctx: &ReqRespCtx) -> EvalResult {
+        let value = match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::List(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(resolved!(item.eval(ctx)));
+                }
+                Value::List(values)
+            }
+            Expr::Attribute(path) => {
+                match ctx.get_attribute_path(&path.iter().map(String::as_str).collect::<Vec<_>>()) {
+                    PendingValue::Resolved(Some(value)) => value.into(),
+                    PendingValue::Resolved(None) => Value::Null,
+                    PendingValue::Pending => return Ok(PendingValue::Pending),
+                }
+            }
+            Expr::Match(predicate) => match predicate.eval(ctx) {
+                PendingValue::Resolved(matches) => Value::Bool(matches),
+                PendingValue::Pending => return Ok(PendingValue::Pending),
+            },
+            Expr::Metadata(matcher) => {
+                Value::Bool(matcher.matches(&MetadataSource::Request.metadata(ctx)))
+            }
+            Expr::Not(expr) => match resolved!(expr.eval(ctx)) {
+                Value::Bool(value) => Value::Bool(!value),
+                other => return Err(format!("can't negate {other}")),
+            },
+            Expr::Negate(expr) => match resolved!(expr.eval(ctx)) {
+                Value::Int(value) => Value::Int(
+                    value
+                        .checked_neg()
+                        .ok_or_else(|| format!("can't negate {value} without overflowing"))?,
+                ),
+                Value::Double(value) => Value::Double(-value),
+                other => return Err(format!("can't negate {other}")),
+            },
+            Expr::And(lhs, rhs) => return Self::logical(ctx, lhs, rhs, false),
+            Expr::Or(lhs, rhs) => return Self::logical(ctx, lhs, rhs, true),
+            Expr::Compare(op, lhs, rhs) => match (lhs.eval(ctx)?, rhs.eval(ctx)?) {
+                (PendingValue::Resolved(lhs), PendingValue::Resolved(rhs)) => {
+                    Value::Bool(Self::compare(*op, lhs, rhs)?)
+                }
+                _ => return Ok(PendingValue::Pending),
+            },
+            Expr::In(needle, haystack) => match (needle.eval(ctx)?, haystack.eval(ctx)?) {
+                (PendingValue::Resolved(needle), PendingValue::Resolved(Value::List(items))) => {
+                    Value::Bool(items.contains(&needle))
+                }
+                (
+                    PendingValue::Resolved(Value::String(key)),
+                    PendingValue::Resolved(Value::Map(entries)),
+                ) => Value::Bool(entries.contains_key(&key)),
+                (PendingValue::Resolved(needle), PendingValue::Resolved(Value::Map(_))) => {
+                    return Err(format!("can't look up {needle} in a map"));
+                }
+                (_, PendingValue::Resolved(other))
+                    if !matches!(other, Value::List(_) | Value::Map(_)) =>
+                {
+                    return Err(format!("can't look into {other}"));
+                }
+                _ => return Ok(PendingValue::Pending),
+            },
+            Expr::Call(method, target, args) => {
+                let target = resolved!(target.eval(ctx));
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(resolved!(arg.eval(ctx)));
+                }
+                Self::call(*method, target, values)?
+            }
+        };
+        Ok(PendingValue::Resolved(value))
+    }
+
+    fn attributes(&self, attributes: &mut Vec<String>) {
+        match self {
+            Expr::Literal(_) => {}
+            Expr::Attribute(path) => attributes.push(path.join(".")),
+            // Whatever part of the stream it may match
+            Expr::Match(_) => attributes.extend(["request".to_string(), "response".to_string()]),
+            Expr::Metadata(_) => attributes.push("metadata".to_string()),
+            Expr::Not(expr) | Expr::Negate(expr) => expr.attributes(attributes),
+            Expr::And(lhs, rhs)
+            | Expr::Or(lhs, rhs)
+            | Expr::Compare(_, lhs, rhs)
+            | Expr::In(lhs, rhs) => {
+                lhs.attributes(attributes);
+                rhs.attributes(attributes);
+            }
+            Expr::List(items) => items.iter().for_each(|item| item.attributes(attributes)),
+            Expr::Call(_, target, args) => {
+                target.attributes(attributes);
+                args.iter().for_each(|arg| arg.attributes(attributes));
+            }
+        }
+    }
+
+    /// `&&` and `||` are commutative: a side that decides the result does so even when the
+    /// other one is pending, or fails.
+    fn logical(ctx: &ReqRespCtx, lhs: &Expr, rhs: &Expr, short_circuit: bool) -> EvalResult {
+        let lhs = lhs.eval(ctx);
+        if matches!(lhs, Ok(PendingValue::Resolved(Value::Bool(b))) if b == short_circuit) {
+            return Ok(PendingValue::Resolved(Value::Bool(short_circuit)));
+        }
+        let rhs = rhs.eval(ctx);
+        if matches!(rhs, Ok(PendingValue::Resolved(Value::Bool(b))) if b == short_circuit) {
+            return Ok(PendingValue::Resolved(Value::Bool(short_circuit)));
+        }
+        match (lhs?, rhs?) {
+            (PendingValue::Pending, _) | (_, PendingValue::Pending) => Ok(PendingValue::Pending),
+            (PendingValue::Resolved(Value::Bool(_)), PendingValue::Resolved(Value::Bool(_))) => {
+                Ok(PendingValue::Resolved(Value::Bool(!short_circuit)))
+            }
+            (PendingValue::Resolved(lhs), PendingValue::Resolved(rhs)) => {
+                Err(format!("expected bool operands, got {lhs} and {rhs}"))
+            }
+        }
+    }
+
+    fn compare(op: CompareOp, lhs: Value, rhs: Value) -> Result<bool, String> {
+        let ordering = match (&lhs, &rhs) {
+            (Value::Int(lhs), Value::Int(rhs)) => Some(lhs.cmp(rhs)),
+            (Value::Int(lhs), Value::Double(rhs)) => (*lhs as f64).partial_cmp(rhs),
+            (Value::Double(lhs), Value::Int(rhs)) => lhs.partial_cmp(&(*rhs as f64)),
+            (Value::Double(lhs), Value::Double(rhs)) => lhs.partial_cmp(rhs),
+            (Value::String(lhs), Value::String(rhs)) => Some(lhs.cmp(rhs)),
+            (Value::Bytes(lhs), Value::Bytes(rhs)) => Some(lhs.cmp(rhs)),
+            (Value::Timestamp(lhs), Value::Timestamp(rhs)) => Some(lhs.cmp(rhs)),
+            (Value::Duration(lhs), Value::Duration(rhs)) => Some(lhs.cmp(rhs)),
+            _ => None,
+        };
+        match (op, ordering) {
+            (CompareOp::Eq, Some(ordering)) => Ok(ordering.is_eq()),
+            (CompareOp::Ne, Some(ordering)) => Ok(ordering.is_ne()),
+            (CompareOp::Eq, None) => Ok(lhs == rhs),
+            (CompareOp::Ne, None) => Ok(lhs != rhs),
+            (CompareOp::Lt, Some(ordering)) => Ok(ordering.is_lt()),
+            (CompareOp::Le, Some(ordering)) => Ok(ordering.is_le()),
+            (CompareOp::Gt, Some(ordering)) => Ok(ordering.is_gt()),
+            (CompareOp::Ge, Some(ordering)) => Ok(ordering.is_ge()),
+            (_, None) => Err(format!("can't order {lhs} and {rhs}")),
+        }
+    }
+
+    fn call(method: Method, target: Value, args: Vec<Value>) -> Result<Value, String> {
+        match (method, target, args.as_slice()) {
+            (Method::StartsWith, Value::String(s), [Value::String(arg)]) => {
+                Ok(Value::Bool(s.starts_with(arg.as_str())))
+            }
+            (Method::EndsWith, Value::String(s), [Value::String(arg)]) => {
+                Ok(Value::Bool(s.ends_with(arg.as_str())))
+            }
+            (Method::Contains, Value::String(s), [Value::String(arg)]) => {
+                Ok(Value::Bool(s.contains(arg.as_str())))
+            }
+            (Method::Contains, Value::Bytes(bytes), [Value::String(arg)]) => {
+                let needle = arg.as_bytes();
+                Ok(Value::Bool(
+                    needle.is_empty() || bytes.windows(needle.len()).any(|w| w == needle),
+                ))
+            }
+            (Method::Size, Value::String(s), []) => Ok(Value::Int(s.chars().count() as i64)),
+            (Method::Size, Value::Bytes(bytes), []) => Ok(Value::Int(bytes.len() as i64)),
+            (Method::Size, Value::List(items), []) => Ok(Value::Int(items.len() as i64)),
+            (Method::Size, Value::Map(entries), []) => Ok(Value::Int(entries.len() as i64)),
+            (method, target, _) => Err(format!("can't call {method:?} on {target}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Int(i64),
+    Double(f64),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "`{ident}`"),
+            Token::String(s) => write!(f, "{s:?}"),
+            Token::Int(i) => write!(f, "`{i}`"),
+            Token::Double(d) => write!(f, "`{d:?}`"),
+            Token::Symbol(symbol) => write!(f, "`{symbol}`"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 16] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "[", "]", ".", ",", "-",
+];
+
+struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { source, pos: 0 }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(usize, Token)>, PredicateError> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.rest().chars().next() {
+            let start = self.pos;
+            if c.is_whitespace() {
+                self.pos += c.len_utf8();
+            } else if c == '"' || c == '\'' {
+                tokens.push((start, Token::String(self.string(c)?)));
+            } else if c.is_ascii_digit() {
+                let digits = self.take_while(|c| c.is_ascii_digit());
+                let mut fraction = self.rest().chars();
+                if fraction.next() == Some('.')
+                    && fraction.next().is_some_and(|c| c.is_ascii_digit())
+                {
+                    self.pos += 1;
+                    self.take_while(|c| c.is_ascii_digit());
+                    let number = &self.source[start..self.pos];
+                    let value = number.parse().map_err(|_| {
+                        PredicateError::new(start, format!("invalid double `{number}`"))
+                    })?;
+                    tokens.push((start, Token::Double(value)));
+                    continue;
+                }
+                let value = digits.parse().map_err(|_| {
+                    PredicateError::new(start, format!("integer `{digits}` out of range"))
+                })?;
+                tokens.push((start, Token::Int(value)));
+            } else if c.is_alphabetic() || c == '_' {
+                let ident = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                tokens.push((start, Token::Ident(ident.to_string())));
+            } else if let Some(symbol) = SYMBOLS.iter().find(|s| self.rest().starts_with(*s)) {
+                self.pos += symbol.len();
+                tokens.push((start, Token::Symbol(symbol)));
+            } else {
+                return Err(PredicateError::new(start, format!("unexpected `{c}`")));
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn string(&mut self, quote: char) -> Result<String, PredicateError> {
+        let start = self.pos;
+        let mut chars = self.rest().char_indices().skip(1);
+        let mut value = String::new();
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                c if c == quote => {
+                    self.pos += offset + c.len_utf8();
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c @ ('\\' | '"' | '\''))) => value.push(c),
+                    Some((offset, c)) => {
+                        return Err(PredicateError::new(
+                            start + offset,
+                            format!("unknown escape `\\{c}`"),
+                        ));
+                    }
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        Err(PredicateError::new(start, "unterminated string".into()))
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// The position of the end of input, right after the last character.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&(usize, Token)> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some((_, Token::Symbol(s))) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some((_, Token::Ident(ident))) if ident == keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), PredicateError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{symbol}`")))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> PredicateError {
+        match self.peek() {
+            Some((position, token)) => {
+                PredicateError::new(*position, format!("expected {expected}, got {token}"))
+            }
+            None => PredicateError::new(self.end, format!("expected {expected}, got end of input")),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, PredicateError> {
+        let mut expr = self.conjunction()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.conjunction()?));
+        }
+        Ok(expr)
+    }
+
+    fn conjunction(&mut self) -> Result<Expr, PredicateError> {
+        let mut expr = self.comparison()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, PredicateError> {
+        let lhs = self.unary()?;
+        let op = [
+            ("==", CompareOp::Eq),
+            ("!=", CompareOp::Ne),
+            ("<=", CompareOp::Le),
+            (">=", CompareOp::Ge),
+            ("<", CompareOp::Lt),
+            (">", CompareOp::Gt),
+        ]
+        .into_iter()
+        .find(|(symbol, _)| self.eat(symbol));
+        if let Some((_, op)) = op {
+            Ok(Expr::Compare(op, Box::new(lhs), Box::new(self.unary()?)))
+        } else if self.eat_keyword("in") {
+            Ok(Expr::In(Box::new(lhs), Box::new(self.unary()?)))
+        } else {
+            Ok(lhs)
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, PredicateError> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("-") {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else {
+            self.postfix()
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, PredicateError> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat(".") {
+                let (position, name) = match self.tokens.get(self.pos).cloned() {
+                    Some((position, Token::Ident(name))) => (position, name),
+                    _ => return Err(self.unexpected("a field or method name")),
+                };
+                self.pos += 1;
+                if self.eat("(") {
+                    let method = match name.as_str() {
+                        "startsWith" => Method::StartsWith,
+                        "endsWith" => Method::EndsWith,
+                        "contains" => Method::Contains,
+                        "size" => Method::Size,
+                        _ => {
+                            return Err(PredicateError::new(
+                                position,
+                                format!("unknown method `{name}`"),
+                            ));
+                        }
+                    };
+                    let args = self.list("(", ")")?;
+                    expr = Expr::Call(method, Box::new(expr), args);
+                } else {
+                    expr = Self::select(expr, &name, position)?;
+                }
+            } else if matches!(self.peek(), Some((_, Token::Symbol("[")))) {
+                let position = self.peek().map(|(position, _)| *position).unwrap_or(0);
+                self.pos += 1;
+                let key = match self.tokens.get(self.pos).cloned() {
+                    Some((_, Token::String(key))) => key,
+                    _ => return Err(self.unexpected("a string key")),
+                };
+                self.pos += 1;
+                self.expect("]")?;
+                expr = Self::select(expr, &key, position)?;
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn select(expr: Expr, field: &str, position: usize) -> Result<Expr, PredicateError> {
+        match expr {
+            Expr::Attribute(mut path) => {
+                path.push(field.to_string());
+                Ok(Expr::Attribute(path))
+            }
+            _ => Err(PredicateError::new(
+                position,
+                format!("can't select `{field}` out of a value that isn't an attribute"),
+            )),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, PredicateError> {
+        let Some((_, token)) = self.tokens.get(self.pos).cloned() else {
+            return Err(self.unexpected("an expression"));
+        };
+        let expr = match token {
+            Token::Int(value) => Expr::Literal(Value::Int(value)),
+            Token::Double(value) => Expr::Literal(Value::Double(value)),
+            Token::String(value) => Expr::Literal(Value::String(value)),
+            Token::Ident(ident) => match ident.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                "null" => Expr::Literal(Value::Null),
+                "in" => return Err(self.unexpected("an expression")),
+                _ => Expr::Attribute(vec![ident]),
+            },
+            Token::Symbol("(") => {
+                self.pos += 1;
+                let expr = self.expression()?;
+                self.expect(")")?;
+                return Ok(expr);
+            }
+            Token::Symbol("[") => return Ok(Expr::List(self.list("[", "]")?)),
+            Token::Symbol(_) => return Err(self.unexpected("an expression")),
+        };
+        self.pos += 1;
+        Ok(expr)
+    }
+
+    /// Parses a comma separated list of expressions, the opening delimiter included unless
+    /// already consumed.
+    fn list(&mut self, open: &str, close: &str) -> Result<Vec<Expr>, PredicateError> {
+        self.eat(open);
+        let mut items = Vec::new();
+        if self.eat(close) {
+            return Ok(items);
+        }
+        loop {
+            items.push(self.expression()?);
+            if self.eat(close) {
+                return Ok(items);
+            }
+            self.expect(",")?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Phase;
+    use crate::host::{Host, MockHost};
+
+    fn ctx(phase: Phase) -> ReqRespCtx {
+        let host = MockHost::default()
+            .with_request_headers(vec![
+                (":method", "GET"),
+                (":path", "/api/v1/users"),
+                ("x-api-key", "secret"),
+            ])
+            .with_property(&["request", "time"], 1_000_000_000i64.to_le_bytes())
+            .with_phase(phase);
+        host.set_response_headers(vec![(":status", "200")].into());
+        host.append_response_body(b"{\"usage\": 42}");
+        // The bodies are complete, and the headers didn't end the stream before them
+        host.set_end_of_stream(matches!(phase, Phase::RequestBody | Phase::ResponseBody));
+        ReqRespCtx::new(Box::new(host))
+    }
+
+    fn eval(source: &str, ctx: &ReqRespCtx) -> PendingValue<bool> {
+        Predicate::new(source)
+            .unwrap_or_else(|e| panic!("`{source}` should compile: {e}"))
+            .eval(ctx)
+    }
+
+    #[test]
+    fn it_evaluates_request_attributes() {
+        let ctx = ctx(Phase::RequestHeaders);
+        for source in [
+            "true",
+            "!false",
+            r#"request.method == "GET" && request.path.startsWith("/api")"#,
+            r#"request.method != 'POST'"#,
+            r#"request.headers["x-api-key"] == "secret""#,
+            r#"request.headers.missing == null"#,
+            r#"request.method in ["GET", "HEAD"]"#,
+            r#"request.path.endsWith("users") || request.path.contains("nope")"#,
+            r#"request.path.size() > 5 && 1 < 2 && -1 <= 0 && "b" >= "a""#,
+            r#"!(request.method == "POST" || request.method == "PUT")"#,
+            r#""x-api-key" in request.headers && request.headers.size() == 3"#,
+            r#"request.time > request.time && false || request.time == request.time"#,
+        ] {
+            assert_eq!(eval(source, &ctx), PendingValue::Resolved(true), "{source}");
+        }
+        for source in [
+            "false",
+            r#"request.method == "POST""#,
+            r#"request.path.startsWith("/admin")"#,
+            r#"request.method in []"#,
+            // type errors resolve to false
+            r#"request.method > 1"#,
+            r#"request.method"#,
+        ] {
+            assert_eq!(
+                eval(source, &ctx),
+                PendingValue::Resolved(false),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn it_reports_evaluation_errors() {
+        let host = MockHost::default()
+            .with_request_headers(vec![(":method", "GET")])
+            .with_property(&["request", "total_size"], i64::MIN.to_le_bytes())
+            .with_phase(Phase::RequestHeaders);
+        let ctx = ReqRespCtx::new(Box::new(host));
+        for (source, error) in [
+            ("1 in request.headers", "can't look up int in a map"),
+            ("1 in 2", "can't look into int"),
+            (
+                "-request.total_size < 0",
+                "can't negate -9223372036854775808 without overflowing",
+            ),
+        ] {
+            let predicate = Predicate::new(source).unwrap();
+            assert_eq!(
+                predicate.expr.eval(&ctx).map(|_| ()),
+                Err(error.to_string()),
+                "{source}"
+            );
+            assert_eq!(predicate.eval(&ctx), PendingValue::Resolved(false));
+        }
+    }
+
+    #[test]
+    fn it_is_pending_until_attributes_are_available() {
+        let source = r#"request.method == "GET" && response.code == 200"#;
+        assert_eq!(
+            eval(source, &ctx(Phase::RequestHeaders)),
+            PendingValue::Pending
+        );
+        assert_eq!(
+            eval(source, &ctx(Phase::ResponseHeaders)),
+            PendingValue::Resolved(true)
+        );
+        // a resolved side decides the outcome, regardless of the pending one
+        assert_eq!(
+            eval(
+                r#"response.code == 200 && request.method == "POST""#,
+                &ctx(Phase::RequestHeaders)
+            ),
+            PendingValue::Resolved(false)
+        );
+        assert_eq!(
+            eval(
+                r#"response.code == 200 || request.method == "GET""#,
+                &ctx(Phase::RequestHeaders)
+            ),
+            PendingValue::Resolved(true)
+        );
+        let source = r#"response.code >= 200.0 && response.body.contains("usage")"#;
+        assert_eq!(
+            eval(source, &ctx(Phase::ResponseHeaders)),
+            PendingValue::Pending
+        );
+        assert_eq!(
+            eval(source, &ctx(Phase::ResponseBody)),
+            PendingValue::Resolved(true)
+        );
+    }
+
+    #[test]
+    fn it_lists_the_attributes_it_reads() {
+        let predicate = Predicate::new(
+            r#"request.headers["x-api-key"] in ["a", source.address] && !metadata.auth.admin"#,
+        )
+        .unwrap();
+        assert_eq!(
+            predicate.attributes(),
+            vec![
+                "request.headers.x-api-key",
+                "source.address",
+                "metadata.auth.admin"
+            ]
+        );
+        assert!(Predicate::new("1 < 2").unwrap().attributes().is_empty());
+    }
+
+    #[test]
+    fn it_reports_syntax_errors() {
+        for (source, error) in [
+            ("", "expected an expression, got end of input at position 0"),
+            (
+                "request.method ==",
+                "expected an expression, got end of input at position 17",
+            ),
+            ("(true", "expected `)`, got end of input at position 5"),
+            ("true false", "unexpected `false` at position 5"),
+            ("\"open", "unterminated string at position 0"),
+            ("a.b.foo()", "unknown method `foo` at position 4"),
+            ("a # b", "unexpected `#` at position 2"),
+            (
+                "'a'.b",
+                "can't select `b` out of a value that isn't an attribute at position 4",
+            ),
+        ] {
+            assert_eq!(
+                Predicate::new(source)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+                Err(error.to_string()),
+                "{source}"
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+/// The parameters of a query string, in order, with their names and values percent-decoded.
+/// A name can be repeated, and a parameter without a `=` has an empty value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryParams {
+    params: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    /// Parses the query of `path`, e.g. `/search?q=a%20b&page=2`, ignoring any fragment.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.split_once('#').map_or(path, |(path, _)| path);
+        path.split_once('?')
+            .map(|(_, query)| Self::parse(query))
+            .unwrap_or_default()
+    }
+
+    /// Parses a query string, without its leading `?`. Empty parameters, as in `a=1&&b=2`, are
+    /// skipped.
+    pub fn parse(query: &str) -> Self {
+        let params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                (decode(name), decode(value))
+            })
+            .collect();
+        Self { params }
+    }
+
+    /// The first value of `name`, as Envoy's query parameter matchers use.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.params
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+/// Percent-decodes `value`, a `+` standing for a space as in forms. Malformed escapes are kept
+/// as they are, and invalid UTF-8 replaced.
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let escaped = bytes
+                    .get(i + 1..i + 3)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+                if let Some(byte) = escaped {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+                decoded.push(b'%');
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_query_strings() {
+        let params =
+            QueryParams::from_path("/search?q=a%20b+c&flag&page=2&q=%E2%9C%93&&empty=#top");
+        assert_eq!(
+            params.iter().collect::<Vec<_>>(),
+            vec![
+                ("q", "a b c"),
+                ("flag", ""),
+                ("page", "2"),
+                ("q", "✓"),
+                ("empty", ""),
+            ]
+        );
+        assert_eq!(params.get("q"), Some("a b c"));
+        assert_eq!(params.get_all("q").collect::<Vec<_>>(), vec!["a b c", "✓"]);
+        assert_eq!(params.get("flag"), Some(""));
+        assert_eq!(params.get("top"), None);
+        assert_eq!(params.get("Page"), None, "names are case-sensitive");
+
+        assert_eq!(QueryParams::from_path("/search"), QueryParams::default());
+        assert_eq!(QueryParams::from_path("/search?"), QueryParams::default());
+    }
+
+    #[test]
+    fn it_keeps_malformed_escapes() {
+        let params = QueryParams::parse("a=100%&b=%zz&c=%4&d=%ff&e%3Db=1&f=%+1");
+        assert_eq!(params.get("a"), Some("100%"));
+        assert_eq!(params.get("b"), Some("%zz"));
+        assert_eq!(params.get("c"), Some("%4"));
+        assert_eq!(params.get("d"), Some("\u{fffd}"));
+        assert_eq!(params.get("e=b"), Some("1"), "decoded after splitting");
+        assert_eq!(params.get("f"), Some("% 1"));
+    }
+}
@@ -1 +1,394 @@
-pub struct AuthService;
+use crate::envoy::{
+    Address, Address_oneof_address, AttributeContext, AttributeContext_HttpRequest,
+    AttributeContext_Peer, AttributeContext_Request, CheckRequest, CheckResponse,
+    CheckResponse_oneof_http_response, DeniedHttpResponse, HeaderValueOption, OkHttpResponse,
+    SocketAddress, SocketAddress_oneof_port_specifier, StatusCode,
+};
+use crate::headers::HeaderMutation;
+use crate::host::HostError;
+use crate::matchers::ListStringMatcher;
+use crate::services::GrpcService;
+use crate::{Effects, Outcome, PendingValue, ReqRespCtx, Service};
+use protobuf::well_known_types::Struct;
+use protobuf::{Message, SingularPtrField};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const SERVICE_NAME: &str = "envoy.service.auth.v3.Authorization";
+const METHOD_NAME: &str = "Check";
+
+const METADATA_NAMESPACE: &str = "envoy.filters.http.ext_authz";
+
+pub struct AuthService {
+    grpc_service: GrpcService,
+    context_extensions: HashMap<String, String>,
+    /// The request headers sent to the service, as with ext_authz's `allowed_headers`, all of
+    /// them when not set.
+    allowed_headers: Option<ListStringMatcher>,
+}
+
+impl AuthService {
+    pub fn new(
+        grpc_service: impl Into<GrpcService>,
+        context_extensions: HashMap<String, String>,
+    ) -> Self {
+        Self {
+            grpc_service: grpc_service.into(),
+            context_extensions,
+            allowed_headers: None,
+        }
+    }
+
+    pub fn with_allowed_headers(mut self, allowed_headers: ListStringMatcher) -> Self {
+        self.allowed_headers = Some(allowed_headers);
+        self
+    }
+
+    fn check_request(&self, ctx: &ReqRespCtx) -> CheckRequest {
+        let attribute = |key: &str| match ctx.get_attribute(key) {
+            PendingValue::Resolved(Some(value)) => value.to_string(),
+            _ => String::default(),
+        };
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for (key, value) in ctx.host.request_headers().iter() {
+            let key = key.to_ascii_lowercase();
+            if let Some(allowed_headers) = &self.allowed_headers
+                && !allowed_headers.matches(&key)
+            {
+                continue;
+            }
+            headers
+                .entry(key)
+                .and_modify(|existing| {
+                    existing.push(',');
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+        let size = headers
+            .get("content-length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(-1);
+
+        let http = AttributeContext_HttpRequest {
+            id: headers.get("x-request-id").cloned().unwrap_or_default(),
+            method: attribute("request.method"),
+            path: attribute("request.path"),
+            host: attribute("request.host"),
+            scheme: attribute("request.scheme"),
+            protocol: attribute("request.protocol"),
+            size,
+            headers,
+            ..Default::default()
+        };
+
+        CheckRequest {
+            attributes: SingularPtrField::some(AttributeContext {
+                source: SingularPtrField::some(Self::peer(&attribute("source.address"))),
+                destination: SingularPtrField::some(Self::peer(&attribute("destination.address"))),
+                request: SingularPtrField::some(AttributeContext_Request {
+                    http: SingularPtrField::some(http),
+                    ..Default::default()
+                }),
+                context_extensions: self.context_extensions.clone(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Builds a peer out of an `ip:port` (or `[ipv6]:port`) address attribute.
+    fn peer(address: &str) -> AttributeContext_Peer {
+        if address.is_empty() {
+            return AttributeContext_Peer::default();
+        }
+        let (ip, port) = match address.rsplit_once(':') {
+            Some((ip, port)) if !ip.ends_with(':') => match port.parse::<u32>() {
+                Ok(port) => (ip, Some(port)),
+                Err(_) => (address, None),
+            },
+            _ => (address, None),
+        };
+        let ip = ip.trim_start_matches('[').trim_end_matches(']');
+        AttributeContext_Peer {
+            address: SingularPtrField::some(Address {
+                address: Some(Address_oneof_address::socket_address(SocketAddress {
+                    address: ip.to_string(),
+                    port_specifier: port.map(SocketAddress_oneof_port_specifier::port_value),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Like Envoy's `ext_authz` filter, a denial without a `DeniedHttpResponse` results in a `403`.
+    fn denied(response: DeniedHttpResponse, dynamic_metadata: Option<Struct>) -> Outcome {
+        let status_code = match response.get_status().code {
+            StatusCode::Empty => StatusCode::Forbidden,
+            code => code,
+        };
+        Outcome::Deny(Effects {
+            response_headers_to_add: Self::headers(response.headers.into_vec()),
+            dynamic_metadata: dynamic_metadata.map(|m| (METADATA_NAMESPACE.to_string(), m)),
+            status_code: Some(status_code as u32),
+            body: Some(response.body).filter(|body| !body.is_empty()),
+            ..Default::default()
+        })
+    }
+
+    fn allowed(mut response: OkHttpResponse, dynamic_metadata: Option<Struct>) -> Outcome {
+        // `OkHttpResponse.dynamic_metadata` is deprecated in favor of the one on `CheckResponse`
+        let dynamic_metadata = dynamic_metadata.or_else(|| response.dynamic_metadata.take());
+        Outcome::Allow(Effects {
+            request_headers_to_add: Self::headers(response.headers.into_vec()),
+            request_headers_to_remove: response.headers_to_remove.into_vec(),
+            response_headers_to_add: Self::headers(response.response_headers_to_add.into_vec()),
+            dynamic_metadata: dynamic_metadata.map(|m| (METADATA_NAMESPACE.to_string(), m)),
+            ..Default::default()
+        })
+    }
+
+    fn headers(options: Vec<HeaderValueOption>) -> Vec<HeaderMutation> {
+        options.into_iter().map(HeaderMutation::from).collect()
+    }
+}
+
+impl Service for AuthService {
+    type Response = Outcome;
+
+    fn dispatch(&self, ctx: &mut ReqRespCtx) -> Result<PendingValue<Option<usize>>, HostError> {
+        let message = self
+            .check_request(ctx)
+            .write_to_bytes()
+            .expect("CheckRequest is always serializable");
+        self.grpc_service
+            .dispatch(ctx, SERVICE_NAME, METHOD_NAME, &message)
+            .map(|token_id| PendingValue::Resolved(Some(token_id)))
+    }
+
+    fn parse_message(&self, message: Vec<u8>) -> Outcome {
+        let mut response = match CheckResponse::parse_from_bytes(&message) {
+            Ok(response) => response,
+            Err(e) => return Outcome::Error(format!("invalid CheckResponse: {e}")),
+        };
+        let dynamic_metadata = response.dynamic_metadata.take();
+        let allowed = response.status.as_ref().map(|s| s.code).unwrap_or(0) == 0;
+        match (allowed, response.http_response) {
+            (true, Some(CheckResponse_oneof_http_response::ok_response(response))) => {
+                Self::allowed(response, dynamic_metadata)
+            }
+            (true, _) => Self::allowed(OkHttpResponse::default(), dynamic_metadata),
+            (false, Some(CheckResponse_oneof_http_response::denied_response(response))) => {
+                Self::denied(response, dynamic_metadata)
+            }
+            (false, _) => Self::denied(DeniedHttpResponse::default(), dynamic_metadata),
+        }
+    }
+
+    fn reads(&self) -> Vec<String> {
+        vec!["request".to_string()]
+    }
+
+    fn writes(&self) -> Vec<String> {
+        vec![
+            "request.headers".to_string(),
+            "response.headers".to_string(),
+            format!("metadata.{METADATA_NAMESPACE}"),
+        ]
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.grpc_service.timeout()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Phase;
+    use crate::envoy::{HeaderValue, HttpStatus, Status};
+    use crate::host::MockHost;
+
+    fn service() -> AuthService {
+        let extensions = HashMap::from([("tenant".to_string(), "acme".to_string())]);
+        AuthService::new("authorino", extensions)
+    }
+
+    #[test]
+    fn it_dispatches_a_check_request() {
+        let service = service();
+        let host = MockHost::default()
+            .with_request_headers(vec![
+                (":method", "POST"),
+                (":path", "/api/v1?q=1"),
+                (":authority", "example.com"),
+                (":scheme", "https"),
+                ("X-Request-Id", "abc"),
+                ("accept", "text/html"),
+                ("accept", "application/json"),
+            ])
+            .with_property(&["source", "address"], "10.0.0.1:5000")
+            .with_property(&["destination", "address"], "[::1]:8080")
+            .with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+
+        assert_eq!(
+            service.dispatch(&mut ctx),
+            Ok(PendingValue::Resolved(Some(1)))
+        );
+
+        let call = &host.grpc_calls()[0];
+        assert_eq!(call.upstream, "authorino");
+        assert_eq!(call.service, SERVICE_NAME);
+        assert_eq!(call.method, METHOD_NAME);
+        let request = CheckRequest::parse_from_bytes(&call.message).unwrap();
+        let attributes = request.get_attributes();
+        let http = attributes.get_request().get_http();
+        assert_eq!(http.method, "POST");
+        assert_eq!(http.path, "/api/v1?q=1");
+        assert_eq!(http.host, "example.com");
+        assert_eq!(http.scheme, "https");
+        assert_eq!(http.id, "abc");
+        assert_eq!(http.size, -1);
+        assert_eq!(http.headers["accept"], "text/html,application/json");
+        assert_eq!(attributes.context_extensions["tenant"], "acme");
+
+        let source = attributes.get_source().get_address().get_socket_address();
+        assert_eq!(source.address, "10.0.0.1");
+        assert_eq!(source.get_port_value(), 5000);
+        let destination = attributes
+            .get_destination()
+            .get_address()
+            .get_socket_address();
+        assert_eq!(destination.address, "::1");
+        assert_eq!(destination.get_port_value(), 8080);
+    }
+
+    #[test]
+    fn it_only_sends_the_allowed_headers() {
+        use crate::envoy::{self, StringMatcher, StringMatcher_oneof_match_pattern};
+
+        let allowed_headers = ListStringMatcher::try_from(&envoy::ListStringMatcher {
+            patterns: vec![
+                StringMatcher {
+                    match_pattern: Some(StringMatcher_oneof_match_pattern::exact(
+                        "authorization".to_string(),
+                    )),
+                    ..Default::default()
+                },
+                StringMatcher {
+                    match_pattern: Some(StringMatcher_oneof_match_pattern::prefix(
+                        "x-".to_string(),
+                    )),
+                    ..Default::default()
+                },
+            ]
+            .into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let service = service().with_allowed_headers(allowed_headers);
+        let host = MockHost::default()
+            .with_request_headers(vec![
+                (":path", "/"),
+                ("Authorization", "Bearer t"),
+                ("x-tenant", "acme"),
+                ("cookie", "secret"),
+            ])
+            .with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+        service.dispatch(&mut ctx).unwrap();
+
+        let request = CheckRequest::parse_from_bytes(&host.grpc_calls()[0].message).unwrap();
+        let mut headers: Vec<_> = request
+            .get_attributes()
+            .get_request()
+            .get_http()
+            .headers
+            .keys()
+            .cloned()
+            .collect();
+        headers.sort();
+        assert_eq!(headers, vec!["authorization", "x-tenant"]);
+        // What isn't a header is still sent
+        assert_eq!(request.get_attributes().get_request().get_http().path, "/");
+    }
+
+    fn header(key: &str, value: &str) -> HeaderValueOption {
+        HeaderValueOption {
+            header: SingularPtrField::some(HeaderValue {
+                key: key.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_parses_allowed_responses() {
+        let service = service();
+        let ok = OkHttpResponse {
+            headers: vec![header("x-user", "alice")].into(),
+            headers_to_remove: vec!["authorization".to_string()].into(),
+            response_headers_to_add: vec![header("x-auth", "ok")].into(),
+            ..Default::default()
+        };
+        let response = CheckResponse {
+            status: SingularPtrField::some(Status::default()),
+            dynamic_metadata: SingularPtrField::some(Struct::default()),
+            http_response: Some(CheckResponse_oneof_http_response::ok_response(ok)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            service.parse_message(response.write_to_bytes().unwrap()),
+            Outcome::Allow(Effects {
+                request_headers_to_add: vec![HeaderMutation::append("x-user", "alice")],
+                request_headers_to_remove: vec!["authorization".to_string()],
+                response_headers_to_add: vec![HeaderMutation::append("x-auth", "ok")],
+                dynamic_metadata: Some((METADATA_NAMESPACE.to_string(), Struct::default())),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_denied_responses() {
+        let service = service();
+        let denied = DeniedHttpResponse {
+            status: SingularPtrField::some(HttpStatus {
+                code: StatusCode::Unauthorized,
+                ..Default::default()
+            }),
+            headers: vec![header("www-authenticate", "Bearer")].into(),
+            body: "go away".to_string(),
+            ..Default::default()
+        };
+        let response = CheckResponse {
+            status: SingularPtrField::some(Status {
+                code: 7,
+                ..Default::default()
+            }),
+            http_response: Some(CheckResponse_oneof_http_response::denied_response(denied)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            service.parse_message(response.write_to_bytes().unwrap()),
+            Outcome::Deny(Effects {
+                response_headers_to_add: vec![HeaderMutation::append("www-authenticate", "Bearer")],
+                status_code: Some(401),
+                body: Some("go away".to_string()),
+                ..Default::default()
+            })
+        );
+        assert!(matches!(
+            service.parse_message(vec![0xff, 0xff]),
+            Outcome::Error(_)
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::ReqRespCtx;
+use crate::envoy::{self, GrpcService_oneof_target_specifier};
+use crate::host::HostError;
+use std::fmt;
+use std::time::Duration;
+
+/// How calls to a service are made, as an Envoy `GrpcService` tells: to which cluster, with
+/// what initial metadata, and how long to wait for the response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrpcService {
+    cluster: String,
+    initial_metadata: Vec<(String, String)>,
+    timeout: Option<Duration>,
+}
+
+impl GrpcService {
+    pub fn new(cluster: &str) -> Self {
+        Self {
+            cluster: cluster.to_string(),
+            initial_metadata: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    pub fn with_initial_metadata(mut self, initial_metadata: Vec<(String, String)>) -> Self {
+        self.initial_metadata = initial_metadata;
+        self
+    }
+
+    /// A zero `timeout` means none, as in Envoy.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// How long to wait for the response, if not forever.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Sends `message` to `service`/`method`, along with the initial metadata.
+    pub fn dispatch(
+        &self,
+        ctx: &ReqRespCtx,
+        service: &str,
+        method: &str,
+        message: &[u8],
+    ) -> Result<usize, HostError> {
+        ctx.host.dispatch_grpc_call(
+            &self.cluster,
+            service,
+            method,
+            &self.initial_metadata,
+            message,
+            self.timeout,
+        )
+    }
+}
+
+impl From<&str> for GrpcService {
+    fn from(cluster: &str) -> Self {
+        Self::new(cluster)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GrpcServiceError {
+    MissingCluster,
+    /// Only Envoy's own gRPC client is available to proxy-wasm modules.
+    UnsupportedGoogleGrpc,
+    InvalidTimeout,
+}
+
+impl fmt::Display for GrpcServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrpcServiceError::MissingCluster => write!(f, "no cluster to call"),
+            GrpcServiceError::UnsupportedGoogleGrpc => write!(f, "google_grpc isn't supported"),
+            GrpcServiceError::InvalidTimeout => write!(f, "invalid timeout"),
+        }
+    }
+}
+
+impl std::error::Error for GrpcServiceError {}
+
+impl TryFrom<&envoy::GrpcService> for GrpcService {
+    type Error = GrpcServiceError;
+
+    fn try_from(service: &envoy::GrpcService) -> Result<Self, Self::Error> {
+        let cluster = match &service.target_specifier {
+            Some(GrpcService_oneof_target_specifier::envoy_grpc(grpc))
+                if !grpc.cluster_name.is_empty() =>
+            {
+                &grpc.cluster_name
+            }
+            Some(GrpcService_oneof_target_specifier::google_grpc(_)) => {
+                return Err(GrpcServiceError::UnsupportedGoogleGrpc);
+            }
+            _ => return Err(GrpcServiceError::MissingCluster),
+        };
+        let initial_metadata = service
+            .initial_metadata
+            .iter()
+            .map(|header| (header.key.clone(), header.value.clone()))
+            .collect();
+        let mut grpc_service = Self::new(cluster).with_initial_metadata(initial_metadata);
+        if let Some(timeout) = service.timeout.as_ref() {
+            let (Ok(seconds), Ok(nanos)) =
+                (u64::try_from(timeout.seconds), u32::try_from(timeout.nanos))
+            else {
+                return Err(GrpcServiceError::InvalidTimeout);
+            };
+            grpc_service = grpc_service.with_timeout(Duration::new(seconds, nanos));
+        }
+        Ok(grpc_service)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::{GrpcService_EnvoyGrpc, GrpcService_GoogleGrpc, HeaderValue};
+    use protobuf::well_known_types;
+
+    #[test]
+    fn it_reads_envoy_grpc_services() {
+        let envoy_grpc = |cluster_name: &str| {
+            Some(GrpcService_oneof_target_specifier::envoy_grpc(
+                GrpcService_EnvoyGrpc {
+                    cluster_name: cluster_name.to_string(),
+                    ..Default::default()
+                },
+            ))
+        };
+        let timeout = |seconds, nanos| {
+            Some(well_known_types::Duration {
+                seconds,
+                nanos,
+                ..Default::default()
+            })
+            .into()
+        };
+
+        let service = envoy::GrpcService {
+            target_specifier: envoy_grpc("limitador"),
+            timeout: timeout(0, 250_000_000),
+            initial_metadata: vec![HeaderValue {
+                key: "x-tenant".to_string(),
+                value: "acme".to_string(),
+                ..Default::default()
+            }]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            GrpcService::try_from(&service),
+            Ok(GrpcService::new("limitador")
+                .with_initial_metadata(vec![("x-tenant".to_string(), "acme".to_string())])
+                .with_timeout(Duration::from_millis(250)))
+        );
+        assert_eq!(
+            GrpcService::try_from(&envoy::GrpcService {
+                target_specifier: envoy_grpc("limitador"),
+                ..Default::default()
+            })
+            .map(|service| service.timeout()),
+            Ok(None)
+        );
+        assert_eq!(
+            GrpcService::try_from(&envoy::GrpcService {
+                target_specifier: envoy_grpc("limitador"),
+                timeout: timeout(0, 0),
+                ..Default::default()
+            })
+            .map(|service| service.timeout()),
+            Ok(None)
+        );
+
+        for (service, error) in [
+            (
+                envoy::GrpcService {
+                    target_specifier: envoy_grpc(""),
+                    ..Default::default()
+                },
+                GrpcServiceError::MissingCluster,
+            ),
+            (
+                envoy::GrpcService {
+                    target_specifier: Some(GrpcService_oneof_target_specifier::google_grpc(
+                        GrpcService_GoogleGrpc::default(),
+                    )),
+                    ..Default::default()
+                },
+                GrpcServiceError::UnsupportedGoogleGrpc,
+            ),
+            (
+                envoy::GrpcService {
+                    target_specifier: envoy_grpc("limitador"),
+                    timeout: timeout(-1, 0),
+                    ..Default::default()
+                },
+                GrpcServiceError::InvalidTimeout,
+            ),
+        ] {
+            assert_eq!(GrpcService::try_from(&service), Err(error));
+        }
+    }
+}
@@ -1,5 +1,8 @@
 mod auth;
 pub use auth::AuthService;
 
+mod grpc;
+pub use grpc::GrpcService;
+
 mod ratelimit;
-pub use ratelimit::RateLimitService;
+pub use ratelimit::{DescriptorValue, HitsAddend, RateLimitService};
@@ -1,21 +1,241 @@
-use crate::Service;
-use crate::envoy::{RateLimitDescriptor, RateLimitRequest};
+use crate::descriptors::DescriptorBuilder;
+use crate::envoy::{
+    HeaderValue, RateLimitDescriptor, RateLimitDescriptor_Entry, RateLimitRequest,
+    RateLimitResponse, RateLimitResponse_Code,
+};
+use crate::headers::HeaderMutation;
+use crate::host::HostError;
+use crate::matchers::RegexRewrite;
+use crate::services::GrpcService;
+use crate::{Effects, Outcome, PendingValue, Phase, ReqRespCtx, Service};
 use protobuf::{Message, RepeatedField};
-pub struct RateLimitService;
+use std::time::Duration;
+
+const SERVICE_NAME: &str = "envoy.service.ratelimit.v3.RateLimitService";
+const METHOD_NAME: &str = "ShouldRateLimit";
+const METADATA_NAMESPACE: &str = "envoy.filters.http.ratelimit";
+
+/// Where the value of a descriptor entry comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DescriptorValue {
+    Static(String),
+    /// The entry is left out when the attribute has no value.
+    Attribute(String),
+    /// The value of the attribute, normalised by the rewrite, e.g. `/users/123` to `/users/{id}`.
+    Rewritten(String, RegexRewrite),
+}
+
+impl DescriptorValue {
+    /// The attribute the value is read from, if any.
+    pub fn attribute(&self) -> Option<&str> {
+        match self {
+            DescriptorValue::Static(_) => None,
+            DescriptorValue::Attribute(attribute) | DescriptorValue::Rewritten(attribute, _) => {
+                Some(attribute)
+            }
+        }
+    }
+
+    /// The value of the entry, `None` when it should be left out, as the attribute has no
+    /// value, and `Pending` until the attribute is available.
+    pub fn resolve(&self, ctx: &ReqRespCtx) -> PendingValue<Option<String>> {
+        match self {
+            DescriptorValue::Static(value) => PendingValue::Resolved(Some(value.clone())),
+            DescriptorValue::Attribute(attribute) => match ctx.get_attribute(attribute) {
+                PendingValue::Resolved(value) => {
+                    PendingValue::Resolved(value.map(|value| value.to_string()))
+                }
+                PendingValue::Pending => PendingValue::Pending,
+            },
+            DescriptorValue::Rewritten(attribute, rewrite) => match ctx.get_attribute(attribute) {
+                PendingValue::Resolved(value) => {
+                    PendingValue::Resolved(value.map(|value| rewrite.apply(&value.to_string())))
+                }
+                PendingValue::Pending => PendingValue::Pending,
+            },
+        }
+    }
+}
+
+/// How many hits are reported to the rate limit service, once known.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HitsAddend {
+    /// The number at the path in the JSON response body, e.g. `usage.total_tokens` as in an
+    /// OpenAI completion. A segment of the path indexes arrays when numeric.
+    ResponseBody(Vec<String>),
+}
+
+impl HitsAddend {
+    /// `None` when there's no such number, which then isn't reported.
+    pub fn resolve(&self, ctx: &ReqRespCtx) -> PendingValue<Option<u32>> {
+        match self {
+            HitsAddend::ResponseBody(path) => {
+                if ctx.phase() < Some(Phase::ResponseBody) || !ctx.host.end_of_stream() {
+                    return PendingValue::Pending;
+                }
+                let body = ctx.host.response_body().unwrap_or_default();
+                let Ok(json) = serde_json::from_slice::<serde_json::Value>(&body) else {
+                    return PendingValue::Resolved(None);
+                };
+                let value = path.iter().try_fold(&json, |value, segment| match value {
+                    serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+                    value => value.get(segment),
+                });
+                PendingValue::Resolved(
+                    value
+                        .and_then(serde_json::Value::as_u64)
+                        .map(|hits| u32::try_from(hits).unwrap_or(u32::MAX)),
+                )
+            }
+        }
+    }
+}
+
+pub struct RateLimitService {
+    grpc_service: GrpcService,
+    domain: String,
+    descriptor_entries: Vec<(String, DescriptorValue)>,
+    rate_limits: Vec<DescriptorBuilder>,
+}
+
+impl RateLimitService {
+    /// `grpc_service` is how the rate limit service is called, `domain` the one limits are
+    /// looked up in, and `descriptor_entries` maps each descriptor key to its value.
+    pub fn new(
+        grpc_service: impl Into<GrpcService>,
+        domain: &str,
+        descriptor_entries: Vec<(String, DescriptorValue)>,
+    ) -> Self {
+        Self {
+            grpc_service: grpc_service.into(),
+            domain: domain.to_string(),
+            descriptor_entries,
+            rate_limits: Vec::new(),
+        }
+    }
+
+    /// Adds the descriptors of Envoy `RateLimit`s to the request, the one made of
+    /// `descriptor_entries` is then only sent when it has any. Without any descriptor, the rate
+    /// limit service isn't asked at all.
+    pub fn with_rate_limits(mut self, rate_limits: Vec<DescriptorBuilder>) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+}
 
 impl Service for RateLimitService {
-    type Response = bool;
-    fn dispatch(&self, ctx: &mut crate::ReqRespCtx) -> usize {
-        // let msg = self.request_message(ctx.get_attribute::<String>("ratelimit.domain".into()));
-        todo!()
+    type Response = Outcome;
+    fn dispatch(&self, ctx: &mut ReqRespCtx) -> Result<PendingValue<Option<usize>>, HostError> {
+        self.send(ctx, 1)
     }
 
-    fn parse_message(&self, message: Vec<u8>) -> bool {
-        todo!()
+    /// Denies the request when it is `OVER_LIMIT`, a response that can't be decoded is left to
+    /// the service's failure mode.
+    fn parse_message(&self, message: Vec<u8>) -> Outcome {
+        let mut response = match RateLimitResponse::parse_from_bytes(&message) {
+            Ok(response) => response,
+            Err(e) => return Outcome::Error(format!("invalid RateLimitResponse: {e}")),
+        };
+        // Envoy only forwards `request_headers_to_add` upstream when the request is let through,
+        // while `response_headers_to_add` also make it onto the `429` when it isn't.
+        let effects = Effects {
+            request_headers_to_add: Self::headers(response.request_headers_to_add.into_vec()),
+            response_headers_to_add: Self::headers(response.response_headers_to_add.into_vec()),
+            dynamic_metadata: response
+                .dynamic_metadata
+                .take()
+                .map(|m| (METADATA_NAMESPACE.to_string(), m)),
+            ..Default::default()
+        };
+        if response.overall_code == RateLimitResponse_Code::OVER_LIMIT {
+            Outcome::Deny(effects)
+        } else {
+            Outcome::Allow(effects)
+        }
+    }
+
+    fn reads(&self) -> Vec<String> {
+        let mut reads: Vec<String> = self
+            .descriptor_entries
+            .iter()
+            .filter_map(|(_, value)| value.attribute().map(str::to_string))
+            .collect();
+        reads.extend(self.rate_limits.iter().flat_map(DescriptorBuilder::reads));
+        reads
+    }
+
+    /// Those of `request_headers_to_add`, `response_headers_to_add` and `dynamic_metadata`,
+    /// all a response can carry.
+    fn writes(&self) -> Vec<String> {
+        vec![
+            "request.headers".to_string(),
+            "response.headers".to_string(),
+            format!("metadata.{METADATA_NAMESPACE}"),
+        ]
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.grpc_service.timeout()
     }
 }
 
 impl RateLimitService {
+    /// Sends the descriptors of the request, which counts for `hits_addend` hits, once all of
+    /// their entries are known.
+    pub fn send(
+        &self,
+        ctx: &mut ReqRespCtx,
+        hits_addend: u32,
+    ) -> Result<PendingValue<Option<usize>>, HostError> {
+        let mut entries = RepeatedField::new();
+        for (key, value) in &self.descriptor_entries {
+            match value.resolve(ctx) {
+                PendingValue::Resolved(Some(value)) => entries.push(RateLimitDescriptor_Entry {
+                    key: key.clone(),
+                    value,
+                    ..Default::default()
+                }),
+                PendingValue::Resolved(None) => {}
+                PendingValue::Pending => return Ok(PendingValue::Pending),
+            }
+        }
+        let mut descriptors = Vec::with_capacity(self.rate_limits.len() + 1);
+        if !entries.is_empty() || self.rate_limits.is_empty() {
+            descriptors.push(RateLimitDescriptor {
+                entries,
+                ..Default::default()
+            });
+        }
+        for rate_limit in &self.rate_limits {
+            match rate_limit.build(ctx) {
+                PendingValue::Resolved(descriptor) => descriptors.extend(descriptor),
+                PendingValue::Pending => return Ok(PendingValue::Pending),
+            }
+        }
+        if descriptors.is_empty() {
+            return Ok(PendingValue::Resolved(None));
+        }
+        let msg = Self::request_message(
+            self.domain.clone(),
+            RepeatedField::from_vec(descriptors),
+            hits_addend,
+        );
+        let message = msg
+            .write_to_bytes()
+            .expect("RateLimitRequest is always serializable");
+        self.grpc_service
+            .dispatch(ctx, SERVICE_NAME, METHOD_NAME, &message)
+            .map(|token_id| PendingValue::Resolved(Some(token_id)))
+    }
+
+    /// Envoy appends these as they come, empty or not.
+    fn headers(headers: Vec<HeaderValue>) -> Vec<HeaderMutation> {
+        headers
+            .into_iter()
+            .map(|header| HeaderMutation::append(&header.key, &header.value).keep_empty_value(true))
+            .collect()
+    }
+
     fn request_message(
         domain: String,
         descriptors: RepeatedField<RateLimitDescriptor>,
@@ -30,3 +250,247 @@ impl RateLimitService {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Phase;
+    use crate::host::MockHost;
+
+    #[test]
+    fn it_dispatches_a_rate_limit_request() {
+        let service = RateLimitService::new(
+            "limitador",
+            "example",
+            vec![
+                (
+                    "tier".to_string(),
+                    DescriptorValue::Static("gold".to_string()),
+                ),
+                (
+                    "method".to_string(),
+                    DescriptorValue::Attribute("request.method".to_string()),
+                ),
+                (
+                    "missing".to_string(),
+                    DescriptorValue::Attribute("doesntexist".to_string()),
+                ),
+            ],
+        );
+        let host = MockHost::default()
+            .with_request_headers(vec![(":method", "GET")])
+            .with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+
+        assert_eq!(
+            service.dispatch(&mut ctx),
+            Ok(PendingValue::Resolved(Some(1)))
+        );
+
+        let call = &host.grpc_calls()[0];
+        assert_eq!(call.upstream, "limitador");
+        assert_eq!(call.service, SERVICE_NAME);
+        assert_eq!(call.method, METHOD_NAME);
+        let request = RateLimitRequest::parse_from_bytes(&call.message).expect("valid request");
+        assert_eq!(request.domain, "example");
+        assert_eq!(request.hits_addend, 1);
+        assert_eq!(request.descriptors.len(), 1);
+        let entries = &request.descriptors[0].entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (entries[0].key.as_str(), entries[0].value.as_str()),
+            ("tier", "gold")
+        );
+        assert_eq!(
+            (entries[1].key.as_str(), entries[1].value.as_str()),
+            ("method", "GET")
+        );
+    }
+
+    #[test]
+    fn it_waits_for_every_descriptor_entry() {
+        let service = RateLimitService::new(
+            "limitador",
+            "example",
+            vec![
+                (
+                    "tier".to_string(),
+                    DescriptorValue::Static("gold".to_string()),
+                ),
+                (
+                    "body".to_string(),
+                    DescriptorValue::Attribute("request.body".to_string()),
+                ),
+            ],
+        );
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+
+        assert_eq!(service.dispatch(&mut ctx), Ok(PendingValue::Pending));
+        assert!(host.grpc_calls().is_empty());
+
+        host.set_phase(Phase::RequestBody);
+        host.append_request_body(b"hello");
+        host.set_end_of_stream(true);
+        assert_eq!(
+            service.dispatch(&mut ctx),
+            Ok(PendingValue::Resolved(Some(1)))
+        );
+        let request =
+            RateLimitRequest::parse_from_bytes(&host.grpc_calls()[0].message).expect("valid");
+        assert_eq!(request.descriptors[0].entries.len(), 2);
+    }
+
+    #[test]
+    fn it_sends_the_descriptors_of_rate_limits() {
+        use crate::envoy::{
+            RateLimit, RateLimit_Action, RateLimit_Action_RequestHeaders,
+            RateLimit_Action_oneof_action_specifier,
+        };
+
+        let by_header = |name: &str| {
+            let rate_limit = RateLimit {
+                actions: vec![RateLimit_Action {
+                    action_specifier: Some(
+                        RateLimit_Action_oneof_action_specifier::request_headers(
+                            RateLimit_Action_RequestHeaders {
+                                header_name: name.to_string(),
+                                descriptor_key: name.to_string(),
+                                ..Default::default()
+                            },
+                        ),
+                    ),
+                    ..Default::default()
+                }]
+                .into(),
+                ..Default::default()
+            };
+            DescriptorBuilder::try_from(&rate_limit).unwrap()
+        };
+        let service = RateLimitService::new("limitador", "example", vec![])
+            .with_rate_limits(vec![by_header("x-user"), by_header("x-tenant")]);
+        assert_eq!(
+            service.reads(),
+            vec!["request.headers.x-user", "request.headers.x-tenant"]
+        );
+        let host = MockHost::default()
+            .with_request_headers(vec![("x-user", "alice")])
+            .with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+
+        assert_eq!(
+            service.dispatch(&mut ctx),
+            Ok(PendingValue::Resolved(Some(1)))
+        );
+        let request = RateLimitRequest::parse_from_bytes(&host.grpc_calls()[0].message).unwrap();
+        assert_eq!(request.descriptors.len(), 1, "no x-tenant, no descriptor");
+        let entry = &request.descriptors[0].entries[0];
+        assert_eq!(
+            (entry.key.as_str(), entry.value.as_str()),
+            ("x-user", "alice")
+        );
+
+        let host = MockHost::default().with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+        assert_eq!(service.dispatch(&mut ctx), Ok(PendingValue::Resolved(None)));
+        assert!(host.grpc_calls().is_empty());
+
+        let host = MockHost::default();
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+        assert_eq!(service.dispatch(&mut ctx), Ok(PendingValue::Pending));
+        assert!(host.grpc_calls().is_empty());
+    }
+
+    #[test]
+    fn it_normalises_rewritten_attributes() {
+        use crate::envoy::{RegexMatchAndSubstitute, RegexMatcher};
+
+        let rewrite = RegexRewrite::try_from(&RegexMatchAndSubstitute {
+            pattern: Some(RegexMatcher {
+                regex: "/[0-9]+".to_string(),
+                ..Default::default()
+            })
+            .into(),
+            substitution: "/{id}".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let service = RateLimitService::new(
+            "limitador",
+            "example",
+            vec![(
+                "path".to_string(),
+                DescriptorValue::Rewritten("request.url_path".to_string(), rewrite),
+            )],
+        );
+        let host = MockHost::default()
+            .with_request_headers(vec![(":path", "/users/123/posts/4?page=2")])
+            .with_phase(Phase::RequestHeaders);
+        let mut ctx = ReqRespCtx::new(Box::new(host.clone()));
+
+        assert_eq!(
+            service.dispatch(&mut ctx),
+            Ok(PendingValue::Resolved(Some(1)))
+        );
+        let request = RateLimitRequest::parse_from_bytes(&host.grpc_calls()[0].message).unwrap();
+        assert_eq!(
+            request.descriptors[0].entries[0].value,
+            "/users/{id}/posts/{id}"
+        );
+    }
+
+    #[test]
+    fn it_parses_rate_limit_responses() {
+        let service = RateLimitService::new("limitador", "example", vec![]);
+        let response = |code| {
+            RateLimitResponse {
+                overall_code: code,
+                ..Default::default()
+            }
+            .write_to_bytes()
+            .expect("valid response")
+        };
+
+        assert_eq!(
+            service.parse_message(response(RateLimitResponse_Code::OVER_LIMIT)),
+            Outcome::Deny(Effects::default())
+        );
+        assert_eq!(
+            service.parse_message(response(RateLimitResponse_Code::OK)),
+            Outcome::Allow(Effects::default())
+        );
+        assert!(matches!(
+            service.parse_message(vec![0xff, 0xff]),
+            Outcome::Error(_)
+        ));
+    }
+
+    #[test]
+    fn it_forwards_rate_limit_headers() {
+        let service = RateLimitService::new("limitador", "example", vec![]);
+        let header = |key: &str, value: &str| HeaderValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        };
+        let response = RateLimitResponse {
+            overall_code: RateLimitResponse_Code::OVER_LIMIT,
+            request_headers_to_add: vec![header("x-ratelimit-checked", "true")].into(),
+            response_headers_to_add: vec![header("X-RateLimit-Limit", "10")].into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            service.parse_message(response.write_to_bytes().unwrap()),
+            Outcome::Deny(Effects {
+                request_headers_to_add: vec![
+                    HeaderMutation::append("x-ratelimit-checked", "true").keep_empty_value(true)
+                ],
+                response_headers_to_add: vec![
+                    HeaderMutation::append("X-RateLimit-Limit", "10").keep_empty_value(true)
+                ],
+                ..Default::default()
+            })
+        );
+    }
+}